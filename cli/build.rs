@@ -0,0 +1,28 @@
+use std::io::Write;
+use std::path::Path;
+
+///
+/// The JS and CSS that get baked into the binary never change after it's built:
+///  so they're compressed once, here, instead of on every request
+///
+fn main() {
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    for file_name in ["feed.js", "style.css"] {
+        let source = Path::new("src").join("js").join(file_name);
+        println!("cargo:rerun-if-changed={}", source.display());
+        let contents = std::fs::read(&source).expect("Failed to read bundled JS/CSS.");
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gzip.write_all(&contents).expect("Failed to gzip bundled JS/CSS.");
+        let gzip = gzip.finish().expect("Failed to gzip bundled JS/CSS.");
+        std::fs::write(Path::new(&out_dir).join(format!("{}.gz", file_name)), gzip).expect("Failed to write bundled JS/CSS.");
+
+        let mut brotli = Vec::new();
+        let params = brotli::enc::BrotliEncoderParams {
+            quality: 11,
+            ..Default::default()
+        };
+        brotli::BrotliCompress(&mut &contents[..], &mut brotli, &params).expect("Failed to brotli bundled JS/CSS.");
+        std::fs::write(Path::new(&out_dir).join(format!("{}.br", file_name)), brotli).expect("Failed to write bundled JS/CSS.");
+    }
+}
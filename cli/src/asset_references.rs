@@ -0,0 +1,182 @@
+use std::path::{Component, PathBuf};
+
+use crate::file_modifiers::FileDirectives;
+use crate::ministry_directory::{Card, DeckMetadata};
+
+///
+/// A link to something in a deck's assets/ directory, as it appears in content.yml:
+///  `assets/bee.jpg?width=100` has an asset_path of `bee.jpg` and a query of `width=100`
+///
+/// the asset_path is only ever plain names: `assets/../../secret.txt` and `assets//etc/passwd` aren't asset links at all
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AssetReference{
+    pub reference: String,
+    pub asset_path: PathBuf,
+    pub query: String,
+}
+
+impl AssetReference{
+    pub fn parse(link: &str) -> Option<AssetReference>{
+        let link = link.trim();
+        let relative = link.trim_start_matches("./").trim_start_matches('/');
+        let relative = relative.strip_prefix("assets/")?;
+        let (path, query) = match relative.split_once('?'){
+            Some((path, query)) => (path, query),
+            None => (relative, ""),
+        };
+        let asset_path = PathBuf::from(path);
+        if path.is_empty() || !asset_path.components().all(|component| matches!(component, Component::Normal(_))) {
+            return None;
+        }
+        Some(AssetReference{
+            reference: link.to_string(),
+            asset_path,
+            query: query.to_string(),
+        })
+    }
+
+    pub fn directives(&self) -> FileDirectives{
+        FileDirectives::from_query(&self.query)
+    }
+}
+
+///
+/// Walk every `assets/...` link in a blob of text (markdown, mostly: `![A bee](assets/bee.jpg?width=100)`),
+///  replacing each link with whatever `on_link` returns (or leaving it alone if it returns None)
+///
+pub fn rewrite_text(text: &str, mut on_link: impl FnMut(&AssetReference) -> Option<String>) -> String{
+    let mut rewritten = String::new();
+    let mut position = 0;
+    while let Some(found) = text[position..].find("assets/"){
+        let index = position + found;
+        // include the leading `/` or `./` in `/assets/...` links
+        let before = &text[..index];
+        let start = if before.ends_with("./") { index - 2 } else if before.ends_with('/') { index - 1 } else { index };
+        // only count it if it's the start of a link, not the middle of some other word (or of a link to somebody else's site)
+        let is_link_start = match text[..start].chars().last(){
+            None => true,
+            Some(c) => c == '(' || c == '"' || c == '\'' || c == '=' || c.is_whitespace(),
+        };
+        let rest = &text[index..];
+        let length = rest.find(|c: char| c.is_whitespace() || c == ')' || c == '"' || c == '\'' || c == '<' || c == '>').unwrap_or(rest.len());
+        let end = index + length;
+
+        let replacement = match is_link_start{
+            true => AssetReference::parse(&text[start..end]).and_then(|reference| on_link(&reference)),
+            false => None,
+        };
+        match replacement{
+            Some(replacement) => {
+                rewritten.push_str(&text[position..start]);
+                rewritten.push_str(&replacement);
+            },
+            None => rewritten.push_str(&text[position..end]),
+        }
+        position = end;
+    }
+    rewritten.push_str(&text[position..]);
+    rewritten
+}
+
+///
+/// Find every `assets/...` link in a blob of text
+///
+pub fn find_in_text(text: &str) -> Vec<AssetReference>{
+    let mut references = Vec::new();
+    rewrite_text(text, |reference| {
+        references.push(reference.clone());
+        None
+    });
+    references
+}
+
+fn find_in_card(card: &Card, references: &mut Vec<AssetReference>){
+    for link in card.image_url.iter().chain(card.video_url.iter()).chain(card.pngs.iter()){
+        if let Some(reference) = AssetReference::parse(link){
+            references.push(reference);
+        }
+    }
+    for text in card.content.iter().chain(card.footnote.iter()){
+        references.extend(find_in_text(text));
+    }
+    for child in &card.stack{
+        find_in_card(child, references);
+    }
+}
+
+///
+/// Every asset a deck links to: the deck image, favicon and mp3, plus everything referenced by its cards
+///
+pub fn find_in_deck(metadata: &DeckMetadata, deck: &[Card]) -> Vec<AssetReference>{
+    let mut references = find_in_metadata(metadata);
+    references.extend(find_in_cards(deck));
+    references.sort();
+    references.dedup();
+    references
+}
+
+pub fn find_in_metadata(metadata: &DeckMetadata) -> Vec<AssetReference>{
+    metadata.image_url.iter().chain(metadata.favicon.iter()).chain(metadata.mp3.iter())
+        .filter_map(|link| AssetReference::parse(link))
+        .collect()
+}
+
+pub fn find_in_cards(cards: &[Card]) -> Vec<AssetReference>{
+    let mut references = Vec::new();
+    for card in cards{
+        find_in_card(card, &mut references);
+    }
+    references
+}
+
+///
+/// Run every link in a deck's metadata through `rewrite`
+///
+pub fn rewrite_metadata(mut metadata: DeckMetadata, rewrite: &impl Fn(&str) -> String) -> DeckMetadata{
+    metadata.image_url = metadata.image_url.map(|link| rewrite(&link));
+    metadata.favicon = metadata.favicon.map(|link| rewrite(&link));
+    metadata.mp3 = metadata.mp3.map(|link| rewrite(&link));
+    metadata
+}
+
+///
+/// Run every link (and every bit of text that might have links in it) in a card and its stack through `rewrite`
+///
+pub fn rewrite_card(mut card: Card, rewrite: &impl Fn(&str) -> String) -> Card{
+    card.image_url = card.image_url.map(|link| rewrite(&link));
+    card.video_url = card.video_url.map(|link| rewrite(&link));
+    card.pngs = card.pngs.iter().map(|link| rewrite(link)).collect();
+    card.content = card.content.map(|text| rewrite(&text));
+    card.footnote = card.footnote.map(|text| rewrite(&text));
+    card.stack = card.stack.into_iter().map(|child| rewrite_card(child, rewrite)).collect();
+    card
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_asset_links() {
+        let reference = AssetReference::parse("/assets/bees/bee.jpg?width=100").unwrap();
+        assert_eq!(reference.asset_path, PathBuf::from("bees/bee.jpg"));
+        assert_eq!(reference.query, "width=100");
+        assert!(AssetReference::parse("https://example.com/assets/bee.jpg").is_none());
+    }
+
+    #[test]
+    fn refuses_links_out_of_assets() {
+        for link in ["assets/../../../../secret.txt", "assets/bees/../../content.yml", "assets//etc/passwd", "assets/./bee.jpg", "assets/"] {
+            assert!(AssetReference::parse(link).is_none(), "{}", link);
+        }
+    }
+
+    #[test]
+    fn leaves_links_to_other_sites_alone() {
+        let text = "![x](https://cdn.example.com/assets/bee.jpg) ![y](/assets/bee.jpg) ![z](./assets/bee.jpg?width=100) <img src=\"assets/bee.jpg\">";
+        let rewritten = rewrite_text(text, |reference| Some(format!("assets/{}.webp", reference.asset_path.to_str().unwrap())));
+        assert_eq!(rewritten, "![x](https://cdn.example.com/assets/bee.jpg) ![y](assets/bee.jpg.webp) ![z](assets/bee.jpg.webp) <img src=\"assets/bee.jpg.webp\">");
+        assert_eq!(find_in_text("see https://cdn.example.com/assets/bee.jpg and ../assets/bee.jpg").len(), 0);
+    }
+}
@@ -0,0 +1,268 @@
+use std::io::{Seek, SeekFrom};
+use std::ops::RangeInclusive;
+use rocket::fs::NamedFile;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::tokio::io::AsyncReadExt;
+
+use crate::http_cache::etag_matches;
+use crate::security;
+
+///
+/// A file from a deck's assets/, served the way video and audio players (and caches) expect:
+///
+/// - `Range: bytes=...` gets a 206 with just those bytes (mobile Safari won't seek, or sometimes even play, a video without this)
+/// - `If-Range` only gets the range if the file hasn't changed since the client last saw it: otherwise, the whole thing
+/// - `If-None-Match` / `If-Modified-Since` get a 304 if the client's copy is still good
+/// - HEAD works too, because rocket answers it with whatever GET would have said, minus the body
+///
+/// anybody who can publish a deck can put anything in its assets/: so nothing in there is ever run as a page, or sniffed into being one
+///
+/// `immutable` is for links that carry the file's version: whatever's at that URL will never change, so it can be kept for a year
+///
+pub struct AssetResponse{
+    pub file: NamedFile,
+    // quoted, like it goes in the header
+    pub etag: String,
+    pub immutable: bool,
+}
+
+impl<'r> Responder<'r, 'static> for AssetResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let metadata = std::fs::metadata(self.file.path()).map_err(|_| Status::NotFound)?;
+        let length = metadata.len();
+        let last_modified = metadata.modified().ok().map(httpdate::fmt_http_date);
+        let headers = request.headers();
+
+        let not_modified = match headers.get_one("If-None-Match"){
+            Some(if_none_match) => etag_matches(Some(if_none_match), &self.etag),
+            // (only if there's no If-None-Match: the ETag is the better validator)
+            None => unchanged_since(headers.get_one("If-Modified-Since"), last_modified.as_deref()),
+        };
+        let range = match headers.get_one("Range"){
+            Some(range) if if_range_matches(headers.get_one("If-Range"), &self.etag, last_modified.as_deref()) => parse_range(range, length),
+            _ => None,
+        };
+
+        let mut response = match (not_modified, range){
+            (true, _) => Response::build().status(Status::NotModified).finalize(),
+            (false, None) => self.file.respond_to(request)?,
+            (false, Some(None)) => Response::build()
+                .status(Status::RangeNotSatisfiable)
+                .raw_header("Content-Range", format!("bytes */{}", length))
+                .finalize(),
+            (false, Some(Some(range))) => {
+                let content_type = self.file.path().extension()
+                    .and_then(|extension| extension.to_str())
+                    .and_then(ContentType::from_extension)
+                    .unwrap_or(ContentType::Binary);
+                let (start, end) = (*range.start(), *range.end());
+                let mut file = self.file.take_file().try_into_std().map_err(|_| Status::InternalServerError)?;
+                file.seek(SeekFrom::Start(start)).map_err(|_| Status::InternalServerError)?;
+                let body = rocket::tokio::fs::File::from_std(file).take(end - start + 1);
+                // a streamed body doesn't get a Content-Length of its own
+                Response::build()
+                    .status(Status::PartialContent)
+                    .header(content_type)
+                    .raw_header("Content-Range", format!("bytes {}-{}/{}", start, end, length))
+                    .raw_header("Content-Length", (end - start + 1).to_string())
+                    .streamed_body(body)
+                    .finalize()
+            },
+        };
+        response.set_raw_header("Content-Security-Policy", security::ASSET_POLICY);
+        response.set_raw_header("X-Content-Type-Options", "nosniff");
+        response.set_raw_header("Accept-Ranges", "bytes");
+        response.set_raw_header("ETag", self.etag);
+        if let Some(last_modified) = last_modified{
+            response.set_raw_header("Last-Modified", last_modified);
+        }
+        response.set_raw_header("Cache-Control", match self.immutable{
+            true => "public, max-age=31536000, immutable",
+            false => "no-cache",
+        });
+        Ok(response)
+    }
+}
+
+///
+/// `bytes=0-99`, `bytes=100-` or `bytes=-100`: None if we should ignore the header and send the whole file,
+///  Some(None) if the range is past the end of the file
+///
+/// several ranges at once (`bytes=0-99,200-299`) would need a multipart response: we're allowed to just send everything instead
+///
+fn parse_range(header: &str, length: u64) -> Option<Option<RangeInclusive<u64>>> {
+    let range = header.trim().strip_prefix("bytes=")?;
+    if range.contains(','){
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty(){
+        // the last n bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || length == 0{
+            return Some(None);
+        }
+        return Some(Some(length.saturating_sub(suffix)..=length - 1));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = match end.is_empty(){
+        true => u64::MAX,
+        false => end.parse().ok()?,
+    };
+    if end < start{
+        return None;
+    }
+    if start >= length{
+        return Some(None);
+    }
+    Some(Some(start..=end.min(length - 1)))
+}
+
+///
+/// Whether the client's copy (if it told us which copy it has) is still the one on disk
+///
+fn if_range_matches(if_range: Option<&str>, etag: &str, last_modified: Option<&str>) -> bool {
+    let Some(if_range) = if_range.map(|if_range| if_range.trim()) else {
+        return true;
+    };
+    // If-Range uses the strong comparison: a weak tag never matches
+    if if_range.starts_with('"') || if_range.starts_with("W/"){
+        return if_range == etag;
+    }
+    match (httpdate::parse_http_date(if_range), last_modified.map(httpdate::parse_http_date)){
+        (Ok(if_range), Some(Ok(last_modified))) => last_modified == if_range,
+        _ => false,
+    }
+}
+
+fn unchanged_since(if_modified_since: Option<&str>, last_modified: Option<&str>) -> bool {
+    match (if_modified_since.map(httpdate::parse_http_date), last_modified.map(httpdate::parse_http_date)){
+        (Some(Ok(if_modified_since)), Some(Ok(last_modified))) => last_modified <= if_modified_since,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::asynchronous::{Client, LocalResponse};
+
+    const ETAG: &str = "\"v1\"";
+
+    fn example_path(file_name: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("example/example/assets").join(file_name)
+    }
+
+    #[rocket::get("/<file_name>")]
+    async fn example_asset(file_name: &str) -> Option<AssetResponse> {
+        Some(AssetResponse{
+            file: NamedFile::open(example_path(file_name)).await.ok()?,
+            etag: ETAG.to_string(),
+            immutable: false,
+        })
+    }
+
+    async fn client() -> Client {
+        Client::tracked(rocket::build().mount("/", rocket::routes![example_asset])).await.unwrap()
+    }
+
+    fn header<'a>(response: &'a LocalResponse<'_>, name: &str) -> Option<&'a str> {
+        response.headers().get_one(name)
+    }
+
+    #[rocket::async_test]
+    async fn serves_ranges() {
+        let client = client().await;
+        for file_name in ["test_mp4_video.mp4", "test_webm_video.webm"] {
+            let bytes = std::fs::read(example_path(file_name)).unwrap();
+            let length = bytes.len();
+            let path = format!("/{}", file_name);
+
+            let response = client.get(path.as_str()).header(Header::new("Range", "bytes=0-99")).dispatch().await;
+            assert_eq!(response.status(), Status::PartialContent);
+            assert_eq!(header(&response, "Content-Range"), Some(format!("bytes 0-99/{}", length).as_str()));
+            assert_eq!(header(&response, "Content-Length"), Some("100"));
+            assert_eq!(response.into_bytes().await.unwrap(), bytes[..100]);
+
+            let response = client.get(path.as_str()).header(Header::new("Range", "bytes=-10")).dispatch().await;
+            assert_eq!(response.status(), Status::PartialContent);
+            assert_eq!(response.into_bytes().await.unwrap(), bytes[length - 10..]);
+
+            let response = client.get(path.as_str()).header(Header::new("Range", format!("bytes={}-", length - 5))).dispatch().await;
+            assert_eq!(header(&response, "Content-Range"), Some(format!("bytes {}-{}/{}", length - 5, length - 1, length).as_str()));
+            assert_eq!(response.into_bytes().await.unwrap(), bytes[length - 5..]);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn sends_everything_for_several_ranges() {
+        let client = client().await;
+        let bytes = std::fs::read(example_path("test_mp4_video.mp4")).unwrap();
+        let response = client.get("/test_mp4_video.mp4").header(Header::new("Range", "bytes=0-9,20-29")).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(header(&response, "Content-Range"), None);
+        assert_eq!(response.into_bytes().await.unwrap(), bytes);
+    }
+
+    #[rocket::async_test]
+    async fn refuses_ranges_past_the_end() {
+        let client = client().await;
+        let length = std::fs::metadata(example_path("test_webm_video.webm")).unwrap().len();
+        let response = client.get("/test_webm_video.webm").header(Header::new("Range", format!("bytes={}-", length))).dispatch().await;
+        assert_eq!(response.status(), Status::RangeNotSatisfiable);
+        assert_eq!(header(&response, "Content-Range"), Some(format!("bytes */{}", length).as_str()));
+    }
+
+    #[rocket::async_test]
+    async fn only_sends_a_range_of_the_same_file() {
+        let client = client().await;
+        let response = client.get("/test_mp4_video.mp4")
+            .header(Header::new("Range", "bytes=0-99"))
+            .header(Header::new("If-Range", ETAG))
+            .dispatch().await;
+        assert_eq!(response.status(), Status::PartialContent);
+
+        // the file's changed since the client got the first part of it: it needs all of the new one
+        let bytes = std::fs::read(example_path("test_mp4_video.mp4")).unwrap();
+        let response = client.get("/test_mp4_video.mp4")
+            .header(Header::new("Range", "bytes=0-99"))
+            .header(Header::new("If-Range", "\"v0\""))
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), bytes);
+    }
+
+    #[rocket::async_test]
+    async fn not_modified() {
+        let client = client().await;
+        let response = client.get("/test_webm_video.webm").header(Header::new("If-None-Match", ETAG)).dispatch().await;
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(header(&response, "ETag"), Some(ETAG));
+        assert!(response.into_bytes().await.unwrap_or_default().is_empty());
+
+        let response = client.get("/test_webm_video.webm").header(Header::new("If-None-Match", "\"v0\"")).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn head() {
+        let client = client().await;
+        let length = std::fs::metadata(example_path("test_mp4_video.mp4")).unwrap().len();
+        let response = client.head("/test_mp4_video.mp4").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(header(&response, "Accept-Ranges"), Some("bytes"));
+        assert_eq!(header(&response, "ETag"), Some(ETAG));
+        assert!(response.into_bytes().await.unwrap_or_default().is_empty());
+
+        let response = client.head("/test_mp4_video.mp4").header(Header::new("Range", "bytes=0-99")).dispatch().await;
+        assert_eq!(response.status(), Status::PartialContent);
+        assert_eq!(header(&response, "Content-Range"), Some(format!("bytes 0-99/{}", length).as_str()));
+        assert!(response.into_bytes().await.unwrap_or_default().is_empty());
+    }
+}
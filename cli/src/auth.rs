@@ -0,0 +1,295 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::SystemTime;
+use anyhow::{Result, anyhow};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Services;
+use crate::credentials;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct AuthorRecord{
+    password_hash: String,
+    // we only keep hashes of the tokens we hand out
+    token_hashes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct TokenStoreFile{
+    authors: BTreeMap<String, AuthorRecord>,
+}
+
+///
+/// Who is allowed to publish to this server, and as which author:
+///  every author has a password, and every token we hand out is good for exactly one author
+///
+/// lives in a JSON file (ROCKET_TOKEN_STORE, default ./.cardchapter_tokens.json) that `cardchapter add-author` writes while the server is running:
+///  so every change takes the file's lock and is made to what's on disk, and we re-read the file whenever it's changed underneath us
+///
+pub struct TokenStore{
+    path: PathBuf,
+    contents: RwLock<TokenStoreFile>,
+    // the file's (modified time, size) when we last read or wrote it
+    stamp: Mutex<Option<(SystemTime, u64)>>,
+}
+
+impl TokenStore{
+    pub fn load(path: &str) -> Result<TokenStore>{
+        let path = PathBuf::from(path);
+        let stamp = file_stamp(&path);
+        let contents = read_store(&path)?;
+        Ok(TokenStore{
+            path,
+            contents: RwLock::new(contents),
+            stamp: Mutex::new(stamp),
+        })
+    }
+
+    ///
+    /// Pick up whatever somebody else (another `cardchapter add-author`, say) saved since we last looked
+    ///
+    fn refresh(&self) -> Result<()>{
+        let stamp = file_stamp(&self.path);
+        let mut known_stamp = self.stamp.lock().map_err(|_| anyhow!("Token store is poisoned"))?;
+        if *known_stamp == stamp{
+            return Ok(());
+        }
+        let contents = read_store(&self.path)?;
+        *self.contents.write().map_err(|_| anyhow!("Token store is poisoned"))? = contents;
+        *known_stamp = stamp;
+        Ok(())
+    }
+
+    ///
+    /// Change the store: holding its lock file, to what's on disk right now (so nobody else's change is lost), and save it
+    ///
+    fn update<T>(&self, change: impl FnOnce(&mut TokenStoreFile) -> Result<T>) -> Result<T>{
+        let mut known_stamp = self.stamp.lock().map_err(|_| anyhow!("Token store is poisoned"))?;
+        let _file_lock = lock_store(&self.path)?;
+        let mut contents = read_store(&self.path)?;
+        let changed = change(&mut contents)?;
+        // (password hashes: nobody else on the machine needs them)
+        credentials::write_private(&self.path, rocket::serde::json::to_pretty_string(&contents)?.as_bytes())?;
+        *self.contents.write().map_err(|_| anyhow!("Token store is poisoned"))? = contents;
+        *known_stamp = file_stamp(&self.path);
+        Ok(changed)
+    }
+
+    ///
+    /// Create an author (or change their password)
+    ///
+    pub fn set_password(&self, author_slug: &str, password: &str) -> Result<()>{
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default().hash_password(password.as_bytes(), &salt)
+            .map_err(|err| anyhow!("Couldn't hash password: {}", err))?
+            .to_string();
+
+        self.update(|contents| {
+            let author = contents.authors.entry(author_slug.to_string()).or_default();
+            author.password_hash = password_hash;
+            // a new password logs out everybody who was using the old one
+            author.token_hashes.clear();
+            Ok(())
+        })
+    }
+
+    ///
+    /// Trade an author's password for a brand new token
+    ///
+    /// argon2 is slow on purpose: it runs on the blocking pool, without the store locked,
+    ///  and an author that doesn't exist takes just as long to turn away as a wrong password
+    ///
+    pub async fn login(&self, author_slug: &str, password: &str) -> Result<String>{
+        self.refresh()?;
+        let known_hash = {
+            let contents = self.contents.read().map_err(|_| anyhow!("Token store is poisoned"))?;
+            contents.authors.get(author_slug).map(|author| author.password_hash.clone())
+        };
+        let password_hash = known_hash.clone().unwrap_or_else(|| dummy_password_hash().to_string());
+        let password = password.to_string();
+        let verified = rocket::tokio::task::spawn_blocking(move || verify_password(&password, &password_hash)).await??;
+        if !verified || known_hash.is_none(){
+            return Err(anyhow!("Unknown author or wrong password"));
+        }
+
+        let mut token_bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut token_bytes);
+        let token = hex(&token_bytes);
+        self.update(|contents| {
+            match contents.authors.get_mut(author_slug){
+                // (unless their password changed while we were checking it)
+                Some(author) if Some(&author.password_hash) == known_hash.as_ref() => {
+                    author.token_hashes.push(hash_token(&token));
+                    Ok(())
+                },
+                _ => Err(anyhow!("Unknown author or wrong password")),
+            }
+        })?;
+        Ok(token)
+    }
+
+    ///
+    /// Which author does this token belong to, if anybody?
+    ///
+    pub fn author_for_token(&self, token: &str) -> Option<String>{
+        if let Err(err) = self.refresh(){
+            println!("Couldn't re-read {}: {}", self.path.to_str().unwrap_or(""), err);
+        }
+        let token_hash = hash_token(token);
+        let contents = self.contents.read().ok()?;
+        contents.authors.iter()
+            .find(|(_, author)| author.token_hashes.contains(&token_hash))
+            .map(|(author_slug, _)| author_slug.clone())
+    }
+}
+
+fn read_store(path: &Path) -> Result<TokenStoreFile>{
+    match path.exists(){
+        true => Ok(rocket::serde::json::from_str(&std::fs::read_to_string(path)?)?),
+        false => Ok(TokenStoreFile::default()),
+    }
+}
+
+fn file_stamp(path: &Path) -> Option<(SystemTime, u64)>{
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+///
+/// Take the store's lock file (waiting for whoever has it): everybody who writes the store holds it while they do
+///
+fn lock_store(path: &Path) -> Result<std::fs::File>{
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()){
+        std::fs::create_dir_all(parent)?;
+    }
+    let lock = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(path.with_extension("lock"))?;
+    lock.lock()?;
+    Ok(lock)
+}
+
+fn verify_password(password: &str, password_hash: &str) -> Result<bool>{
+    let password_hash = PasswordHash::new(password_hash).map_err(|err| anyhow!("Corrupt password hash: {}", err))?;
+    Ok(Argon2::default().verify_password(password.as_bytes(), &password_hash).is_ok())
+}
+
+///
+/// A hash that no password matches, to check the passwords of authors who don't exist against
+///
+fn dummy_password_hash() -> &'static str{
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| {
+        let mut password = [0u8; 32];
+        OsRng.fill_bytes(&mut password);
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default().hash_password(&password, &salt).map(|hash| hash.to_string()).unwrap_or_default()
+    })
+}
+
+fn hash_token(token: &str) -> String{
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String{
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+///
+/// Someone who is allowed to publish decks to this server: they sent a token we handed out at /login
+///  (and they can only publish as the author that token belongs to)
+///
+pub struct Publisher{
+    pub author_slug: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Publisher{
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error>{
+        let services = match request.rocket().state::<Services>(){
+            Some(services) => services,
+            None => return Outcome::Error((Status::InternalServerError, "No services")),
+        };
+        let bearer = request.headers().get_one("Authorization").and_then(|header| header.strip_prefix("Bearer "));
+        match bearer.and_then(|token| services.tokens.author_for_token(token)){
+            Some(author_slug) => Outcome::Success(Publisher{ author_slug }),
+            None => Outcome::Error((Status::Unauthorized, "Missing or incorrect token")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> TokenStore {
+        let directory = std::env::temp_dir().join(format!("cardchapter-auth-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        TokenStore::load(directory.join("tokens.json").to_str().unwrap()).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn logs_in_with_the_right_password_only() {
+        let tokens = store("login");
+        tokens.set_password("alice", "hunter2").unwrap();
+
+        assert!(tokens.login("alice", "hunter3").await.is_err());
+        assert!(tokens.login("bob", "hunter2").await.is_err());
+        let token = tokens.login("alice", "hunter2").await.unwrap();
+        assert_eq!(tokens.author_for_token(&token).as_deref(), Some("alice"));
+    }
+
+    #[rocket::async_test]
+    async fn unknown_authors_are_turned_away_as_slowly_as_wrong_passwords() {
+        let tokens = store("timing");
+        tokens.set_password("alice", "hunter2").unwrap();
+        // (the dummy hash is made the first time it's needed)
+        let _ = tokens.login("bob", "hunter2").await;
+
+        let started = std::time::Instant::now();
+        let _ = tokens.login("alice", "wrong").await;
+        let wrong_password = started.elapsed();
+        let started = std::time::Instant::now();
+        let _ = tokens.login("bob", "wrong").await;
+        let unknown_author = started.elapsed();
+        assert!(unknown_author * 4 > wrong_password, "{:?} vs {:?}", unknown_author, wrong_password);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nobody_else_can_read_the_token_store() {
+        use std::os::unix::fs::PermissionsExt;
+        let tokens = store("permissions");
+        tokens.set_password("alice", "hunter2").unwrap();
+        let mode = std::fs::metadata(&tokens.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[rocket::async_test]
+    async fn two_stores_on_one_file_keep_each_others_changes() {
+        let server = store("shared");
+        let path = server.path.to_str().unwrap().to_string();
+        server.set_password("alice", "hunter2").unwrap();
+        let alice_token = server.login("alice", "hunter2").await.unwrap();
+
+        // `cardchapter add-author`, while the server is running
+        let add_author = TokenStore::load(&path).unwrap();
+        add_author.set_password("bob", "swordfish").unwrap();
+        assert_eq!(server.author_for_token(&alice_token).as_deref(), Some("alice"));
+        let bob_token = server.login("bob", "swordfish").await.unwrap();
+
+        // and the other way around: nobody's tokens got lost
+        add_author.set_password("carol", "letmein").unwrap();
+        let reloaded = TokenStore::load(&path).unwrap();
+        assert_eq!(reloaded.author_for_token(&alice_token).as_deref(), Some("alice"));
+        assert_eq!(reloaded.author_for_token(&bob_token).as_deref(), Some("bob"));
+        assert!(reloaded.login("carol", "letmein").await.is_ok());
+    }
+}
@@ -0,0 +1,179 @@
+use std::io::{Cursor, Write};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+
+// a big deck's /range responses are hundreds of kilobytes of very repetitive JSON: it's worth squashing them
+
+// anything smaller than this goes out as-is: compressing it wouldn't save enough to be worth the trouble
+const MINIMUM_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    ///
+    /// The best encoding that an `Accept-Encoding` header (`gzip, deflate, br;q=0.9` and so on) will take
+    ///
+    pub fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+        let (mut brotli, mut gzip, mut anything) = (None, None, None);
+        for coding in accept_encoding.unwrap_or("").split(','){
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or("").trim().to_lowercase();
+            let quality: f32 = parts
+                .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse().ok())
+                .unwrap_or(1.0);
+            match name.as_str(){
+                "br" => brotli = Some(quality),
+                "gzip" | "x-gzip" => gzip = Some(quality),
+                "*" => anything = Some(quality),
+                _ => {},
+            }
+        }
+        let brotli = brotli.or(anything).unwrap_or(0.0);
+        let gzip = gzip.or(anything).unwrap_or(0.0);
+        // a tie goes to brotli: it's smaller
+        if brotli > 0.0 && brotli >= gzip {
+            Encoding::Brotli
+        }
+        else if gzip > 0.0 {
+            Encoding::Gzip
+        }
+        else {
+            Encoding::Identity
+        }
+    }
+
+    fn header(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Identity => None,
+        }
+    }
+
+    fn compress(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut compressed = Vec::new();
+                // quality 5 is most of the savings at a fraction of the time it'd take at 11
+                let params = brotli::enc::BrotliEncoderParams {
+                    quality: 5,
+                    ..Default::default()
+                };
+                brotli::BrotliCompress(&mut &body[..], &mut compressed, &params)?;
+                Ok(compressed)
+            },
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            },
+            Encoding::Identity => Ok(body.to_vec()),
+        }
+    }
+}
+
+///
+/// Compresses JSON and HTML responses (decks, ranges, pages) for clients that can take it
+///
+/// everything else is left alone: images and videos are already about as small as they're going to get,
+///  and the bundled JS and CSS were compressed when the binary was built (see Precompressed)
+///
+pub struct Compression;
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let compressible = response.content_type().is_some_and(|content_type| content_type.is_json() || content_type.is_html());
+        if response.status() != Status::Ok || !compressible || response.headers().contains("Content-Encoding") {
+            return;
+        }
+        // whether or not this one gets compressed, a cache shouldn't hand it to a client that asked differently
+        response.adjoin_raw_header("Vary", "Accept-Encoding");
+
+        let encoding = Encoding::negotiate(request.headers().get_one("Accept-Encoding"));
+        if encoding == Encoding::Identity {
+            return;
+        }
+        // (a body without a size is a stream: we'd have to wait for the end of it)
+        match response.body_mut().size().await {
+            Some(size) if size >= MINIMUM_SIZE => {},
+            _ => return,
+        }
+        let body = match response.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(err) => {
+                println!("Error reading response to compress it: {}", err);
+                response.set_status(Status::InternalServerError);
+                return;
+            }
+        };
+
+        let compressed = rocket::tokio::task::spawn_blocking(move || match encoding.compress(&body) {
+            Ok(compressed) => Ok(compressed),
+            Err(err) => {
+                println!("Error compressing response: {}", err);
+                Err(body)
+            }
+        }).await;
+        match compressed {
+            Ok(Ok(compressed)) => {
+                response.set_sized_body(compressed.len(), Cursor::new(compressed));
+                response.set_raw_header("Content-Encoding", encoding.header().unwrap_or("identity"));
+                // the compressed bytes aren't the bytes the ETag was for: but they mean the same thing
+                if let Some(etag) = response.headers().get_one("ETag").filter(|etag| !etag.starts_with("W/")).map(|etag| format!("W/{}", etag)) {
+                    response.set_raw_header("ETag", etag);
+                }
+            },
+            Ok(Err(body)) => response.set_sized_body(body.len(), Cursor::new(body)),
+            Err(err) => {
+                println!("Error compressing response: {}", err);
+                response.set_status(Status::InternalServerError);
+            },
+        }
+    }
+}
+
+///
+/// A file that was compressed ahead of time (the bundled JS and CSS): we just pick the version the client can read
+///
+pub struct Precompressed {
+    pub content_type: ContentType,
+    pub identity: &'static [u8],
+    pub gzip: &'static [u8],
+    pub brotli: &'static [u8],
+}
+
+impl<'r> Responder<'r, 'static> for Precompressed {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let encoding = Encoding::negotiate(request.headers().get_one("Accept-Encoding"));
+        let body = match encoding {
+            Encoding::Brotli => self.brotli,
+            Encoding::Gzip => self.gzip,
+            Encoding::Identity => self.identity,
+        };
+        let mut response = Response::build()
+            .header(self.content_type)
+            .raw_header("Vary", "Accept-Encoding")
+            .sized_body(body.len(), Cursor::new(body))
+            .finalize();
+        if let Some(header) = encoding.header() {
+            response.set_raw_header("Content-Encoding", header);
+        }
+        Ok(response)
+    }
+}
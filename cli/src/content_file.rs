@@ -0,0 +1,653 @@
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+use serde::de::{SeqAccess, Visitor};
+use schemars::JsonSchema;
+use schemars::schema::{RootSchema, Schema, SchemaObject};
+
+// a deck is written in one of these (if there's more than one, the first one wins)
+pub const CONTENT_FILE_NAMES: [&str; 2] = ["content.yml", "content.md"];
+
+///
+/// content.yml, exactly as it's written: MinistryDirectory turns this into a DeckMetadata and a list of Cards
+///
+/// keys we don't know about are ignored here (`cardchapter check` warns about them), but a key we _do_ know about
+///  with the wrong type of value is an error
+///
+/// this is also where the JSON Schema comes from (`cardchapter schema`, /schema.json), so the doc comments on the fields
+///  are what shows up in writers' editors
+///
+#[derive(Debug, Deserialize, Default, JsonSchema)]
+#[schemars(title = "CardChapter deck (content.yml)", description = "A deck of cards: its metadata, and the cards themselves under `pages`")]
+pub struct ContentFile{
+    /// The deck's name (its slug is the directory it has to live in)
+    #[serde(default, deserialize_with = "scalar_string")]
+    #[schemars(with = "Option<Scalar>")]
+    pub name: Option<String>,
+    /// Used if there's no `name`
+    #[serde(default, deserialize_with = "scalar_string")]
+    #[schemars(with = "Option<Scalar>")]
+    pub title: Option<String>,
+    /// Who wrote the deck (its slug is the directory the deck's directory has to live in)
+    #[serde(default, deserialize_with = "scalar_string")]
+    #[schemars(with = "Option<Scalar>")]
+    pub author: Option<String>,
+    pub author_link: Option<String>,
+    /// `assets/favicon.png`
+    pub favicon: Option<String>,
+    pub description: Option<String>,
+    /// The deck's preview image: `assets/bee.jpg`
+    pub image: Option<String>,
+    /// `en`, `fr`, ...
+    pub locale: Option<String>,
+    /// Raw HTML for the <head> of the deck's page
+    pub extra_header: Option<String>,
+    /// Hidden decks aren't served at all
+    pub hidden: Option<bool>,
+    /// Unlisted decks are served, but left out of the sitemap
+    pub unlisted: Option<bool>,
+    /// A soundtrack for the deck: `assets/song.mp3`
+    pub mp3: Option<String>,
+    /// Advance through the cards in time with the mp3 (see each card's `next`)
+    pub audio_guide: Option<bool>,
+    /// CSS class(es) for the deck's container
+    #[serde(default, deserialize_with = "string_or_list")]
+    #[schemars(with = "StringOrList")]
+    pub container_class: Vec<String>,
+    /// CSS class(es) for the deck
+    #[serde(default, deserialize_with = "string_or_list")]
+    #[schemars(with = "StringOrList")]
+    pub class: Vec<String>,
+    /// The cards, in order
+    pub pages: Option<Vec<CardContent>>,
+
+    // every file that `include:` pulled in, in the order we read them
+    #[serde(skip)]
+    pub included_files: Vec<PathBuf>,
+}
+
+///
+/// One card in content.yml (`pages` and `stack` hold more of these)
+///
+#[derive(Debug, Deserialize, Default, JsonSchema)]
+pub struct CardContent{
+    /// Replace this entry with the list of cards in another YAML file (relative to the deck directory): `chapters/02-bees.yml`
+    pub include: Option<String>,
+    /// The card's id, for links: defaults to its position in the deck (or `<stack id>-<position>` in a stack)
+    #[serde(default, deserialize_with = "scalar_string")]
+    #[schemars(with = "Option<Scalar>")]
+    pub id: Option<String>,
+    /// Worked out from the other keys if it's missing: `content` makes a markdown card, `image` an image card, etc
+    #[serde(rename = "type")]
+    pub card_type: Option<CardType>,
+    #[serde(default, deserialize_with = "scalar_string")]
+    #[schemars(with = "Option<Scalar>")]
+    pub title: Option<String>,
+    /// CSS class(es) for the card
+    #[serde(default, deserialize_with = "string_or_list")]
+    #[schemars(with = "StringOrList")]
+    pub class: Vec<String>,
+    /// CSS class(es) for the card's container
+    #[serde(default, deserialize_with = "string_or_list")]
+    #[schemars(with = "StringOrList")]
+    pub container_class: Vec<String>,
+    /// CSS class(es) for the whole page, while this card is showing
+    #[serde(default, deserialize_with = "string_or_list")]
+    #[schemars(with = "StringOrList")]
+    pub document_class: Vec<String>,
+
+    /// Markdown (or HTML, for `type: html`)
+    pub content: Option<String>,
+    pub animated_text: Option<String>,
+    pub footnote: Option<String>,
+
+    /// `assets/bee.jpg`, optionally with `?width=100` and friends
+    pub image: Option<String>,
+    /// Don't squeeze the image to fit the width of the screen
+    pub wide: Option<bool>,
+    /// Don't squeeze the image to fit the height of the screen
+    pub tall: Option<bool>,
+
+    /// `assets/bee.mp4`
+    pub video: Option<String>,
+    pub video_has_sound: Option<bool>,
+    pub video_controls: Option<bool>,
+
+    /// Loop the video, animated pngs or animation
+    #[serde(rename = "loop")]
+    pub is_loop: Option<bool>,
+
+    /// A directory full of .png frames: `assets/florb`
+    pub pngs: Option<String>,
+    pub pngs_fps: Option<i64>,
+
+    /// Milliseconds
+    pub fade_in: Option<i64>,
+    /// Milliseconds
+    pub fade_out: Option<i64>,
+    /// Milliseconds
+    pub shake: Option<i64>,
+    /// Milliseconds
+    pub vertical_shake: Option<i64>,
+    /// Milliseconds
+    pub jitter: Option<i64>,
+    /// Milliseconds
+    pub vertical_jitter: Option<i64>,
+    /// Pixels
+    pub pan_left: Option<i64>,
+    /// Pixels
+    pub pan_right: Option<i64>,
+    /// Pixels
+    pub pan_up: Option<i64>,
+    /// Pixels
+    pub pan_down: Option<i64>,
+    // these three can be written as either `2` or `1.5`
+    /// Scale to zoom in to
+    pub dolly_in: Option<f64>,
+    /// Scale to zoom out to
+    pub dolly_out: Option<f64>,
+    /// Scale, without any animation
+    pub scale: Option<f64>,
+    /// Degrees
+    pub spin_clockwise: Option<i64>,
+    /// Pixels, without any animation
+    pub push_up: Option<i64>,
+    /// Pixels, without any animation
+    pub push_down: Option<i64>,
+    /// Pixels, without any animation
+    pub push_left: Option<i64>,
+    /// Pixels, without any animation
+    pub push_right: Option<i64>,
+
+    /// Milliseconds
+    pub duration: Option<i64>,
+    /// How far to shake or jitter, in pixels
+    pub amount: Option<i64>,
+    /// Milliseconds
+    pub delay: Option<i64>,
+    /// An anime.js easing: `linear`, `easeInOutQuad`, `easeOutElastic(1, .5)`, ...
+    pub easing: Option<String>,
+    /// Animate the card's container, rather than the card
+    pub animate_container: Option<bool>,
+
+    /// With `audio_guide`: milliseconds until the next card
+    pub next: Option<i64>,
+
+    /// The cards in a stack, which are shown on top of each other
+    pub pages: Option<Vec<CardContent>>,
+    /// Same as `pages`
+    pub stack: Option<Vec<CardContent>>,
+
+    /// How deep in the table of contents this card should be
+    pub depth: Option<i64>,
+}
+
+///
+/// Every kind of card that the client knows how to draw
+///
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CardType{
+    Markdown,
+    Html,
+    Title,
+    Image,
+    Video,
+    Pngs,
+    Stack,
+    AnimatedText,
+    // older decks spell it this way, and the client still draws it
+    #[serde(rename = "animated-text")]
+    AnimatedTextHyphenated,
+}
+
+impl CardType{
+    pub fn as_str(&self) -> &'static str{
+        match self{
+            CardType::Markdown => "markdown",
+            CardType::Html => "html",
+            CardType::Title => "title",
+            CardType::Image => "image",
+            CardType::Video => "video",
+            CardType::Pngs => "pngs",
+            CardType::Stack => "stack",
+            CardType::AnimatedText => "animated_text",
+            CardType::AnimatedTextHyphenated => "animated-text",
+        }
+    }
+}
+
+// the shapes of the lenient fields, for the schema
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[serde(untagged)]
+enum StringOrList{
+    String(String),
+    List(Vec<String>),
+}
+
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[serde(untagged)]
+enum Scalar{
+    String(String),
+    Number(f64),
+}
+
+///
+/// The JSON Schema for content.yml
+///
+pub fn schema() -> RootSchema{
+    schemars::schema_for!(ContentFile)
+}
+
+///
+/// Every key a deck can have
+///
+pub fn deck_keys() -> Vec<String>{
+    property_names(&schema().schema)
+}
+
+///
+/// Every key a card can have
+///
+pub fn card_keys() -> Vec<String>{
+    match schema().definitions.get("CardContent"){
+        Some(Schema::Object(card)) => property_names(card),
+        _ => Vec::new(),
+    }
+}
+
+fn property_names(schema: &SchemaObject) -> Vec<String>{
+    schema.object.as_ref().map(|object| object.properties.keys().cloned().collect()).unwrap_or_default()
+}
+
+///
+/// Read and deserialize a content.yml, pulling in any `include:`d files:
+///  errors look like `deck/content.yml:12:14: pages[3].pages[1].fade_in: invalid type: string "slow", expected i64`
+///  (or `deck/chapters/02-bees.yml:3:14: [1].fade_in: ...` if the problem is in an included file)
+///
+pub fn load(content_path: &Path) -> Result<ContentFile>{
+    let content = std::fs::read_to_string(content_path)?;
+    let parsed = match content_path.extension().and_then(|extension| extension.to_str()){
+        Some("md") => parse_markdown(&content),
+        _ => parse(&content),
+    };
+    let mut content_file: ContentFile = parsed.map_err(|err| anyhow!("{}{}", content_path.to_str().unwrap_or(""), err))?;
+
+    let mut includes = Includes{
+        deck_root: content_path.parent().unwrap_or(Path::new(".")).to_path_buf(),
+        including: vec![content_path.to_path_buf()],
+        files: Vec::new(),
+    };
+    if let Some(pages) = content_file.pages.take(){
+        content_file.pages = Some(includes.expand(pages, content_path, "pages")?);
+    }
+    content_file.included_files = includes.files;
+    Ok(content_file)
+}
+
+struct Includes{
+    deck_root: PathBuf,
+    // the files we're in the middle of including, so that a file can't include itself
+    including: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+}
+
+impl Includes{
+    ///
+    /// Replace every `include:` in a list of cards (and in their stacks) with the cards from the included file
+    ///
+    fn expand(&mut self, cards: Vec<CardContent>, file: &Path, location: &str) -> Result<Vec<CardContent>>{
+        let mut expanded = Vec::new();
+        for (i, mut card) in cards.into_iter().enumerate(){
+            let card_location = format!("{}[{}]", location, i);
+            if let Some(include) = card.include.take(){
+                expanded.extend(self.include(&include, file, &card_location)?);
+                continue;
+            }
+            if let Some(pages) = card.pages.take(){
+                card.pages = Some(self.expand(pages, file, &format!("{}.pages", card_location))?);
+            }
+            if let Some(stack) = card.stack.take(){
+                card.stack = Some(self.expand(stack, file, &format!("{}.stack", card_location))?);
+            }
+            expanded.push(card);
+        }
+        Ok(expanded)
+    }
+
+    fn include(&mut self, include: &str, file: &Path, location: &str) -> Result<Vec<CardContent>>{
+        let error = |message: String| anyhow!("{}: {}: {}", file.to_str().unwrap_or(""), location, message);
+
+        // no `..`, no absolute paths: a deck can only include its own files
+        if Path::new(include).components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir)){
+            return Err(error(format!("Can't include {}: included files have to be inside the deck directory", include)));
+        }
+        let path = self.deck_root.join(include);
+        if self.including.contains(&path){
+            return Err(error(format!("Can't include {}: it's already being included (an include loop?)", include)));
+        }
+        let content = std::fs::read_to_string(&path).map_err(|err| error(format!("Can't include {}: {}", include, err)))?;
+        let cards: Vec<CardContent> = parse(&content).map_err(|err| anyhow!("{}{}", path.to_str().unwrap_or(""), err))?;
+
+        if !self.files.contains(&path){
+            self.files.push(path.clone());
+        }
+        self.including.push(path.clone());
+        let cards = self.expand(cards, &path, "");
+        self.including.pop();
+        cards
+    }
+}
+
+///
+/// Deserialize the contents of a content.yml (or an included file):
+///  the error starts with `:line:column: ` when we know where the problem is
+///
+pub fn parse<T: DeserializeOwned + Default>(content: &str) -> Result<T, String>{
+    parse_at(content, 1, "")
+}
+
+///
+/// Deserialize some YAML that starts on line `first_line` of a bigger file (the front matter or a card in a content.md):
+///  `location` goes in front of the error message (`pages[3]: `)
+///
+fn parse_at<T: DeserializeOwned + Default>(content: &str, first_line: usize, location_name: &str) -> Result<T, String>{
+    // a `content: |` block at the very end of a file without a final newline should still end in a newline
+    let content = match content.ends_with('\n'){
+        true => content.to_string(),
+        false => format!("{}\n", content),
+    };
+    match serde_yaml::from_str::<Option<T>>(&content){
+        Ok(value) => Ok(value.unwrap_or_default()),
+        Err(err) => {
+            let message = err.to_string();
+            match err.location(){
+                Some(location) => {
+                    // serde_yaml tacks the location onto the end of the message: we'd rather have it up front
+                    let suffix = format!(" at line {} column {}", location.line(), location.column());
+                    let message = message.strip_suffix(&suffix).unwrap_or(&message);
+                    // in an included file, the cards are at the top: `[1].fade_in`, rather than `.[1].fade_in`
+                    let message = message.strip_prefix('.').unwrap_or(message);
+                    Err(format!(":{}:{}: {}{}", location.line() + first_line - 1, location.column(), location_prefix(location_name), message))
+                },
+                None => Err(format!(": {}{}", location_prefix(location_name), message)),
+            }
+        }
+    }
+}
+
+fn location_prefix(location_name: &str) -> String{
+    match location_name.is_empty(){
+        true => "".to_string(),
+        false => format!("{}: ", location_name),
+    }
+}
+
+///
+/// A chunk of a content.md: `line` is the line (in the whole file) that `text` starts on
+///
+#[derive(Debug, Clone)]
+pub struct MarkdownBlock{
+    pub line: usize,
+    pub text: String,
+}
+
+///
+/// One card in a content.md: an optional ```yaml block of card options, followed by the card's markdown
+///
+#[derive(Debug, Clone)]
+pub struct MarkdownCard{
+    pub options: Option<MarkdownBlock>,
+    pub content: String,
+}
+
+///
+/// A content.md, split into its front matter and its cards
+///
+/// ````markdown
+/// ---
+/// name: Bees
+/// author: Alice
+/// ---
+///
+/// Bees are **important pollinators**.
+///
+/// ---
+///
+/// ```yaml
+/// id: bee-types
+/// fade_in: 500
+/// ```
+/// There are many types of bees.
+/// ````
+///
+/// cards are separated by `---` lines (outside of fenced code blocks): use `***` for a horizontal rule inside a card
+///
+pub fn split_markdown(content: &str) -> Result<(MarkdownBlock, Vec<MarkdownCard>), String>{
+    let lines: Vec<&str> = content.lines().collect();
+    let is_separator = |line: &str| line.trim_end() == "---";
+
+    if lines.first().map(|line| is_separator(line)) != Some(true){
+        return Err(":1:1: content.md has to start with YAML front matter (name, author, ...) between two `---` lines".to_string());
+    }
+    let front_matter_end = match lines.iter().skip(1).position(|line| is_separator(line)){
+        Some(index) => index + 1,
+        None => return Err(":1:1: the front matter never ends: it needs a `---` line after it".to_string()),
+    };
+    let front_matter = MarkdownBlock{
+        line: 2,
+        text: lines[1..front_matter_end].join("\n"),
+    };
+
+    // split the rest on separators, skipping over fenced code blocks
+    let mut chunks: Vec<(usize, Vec<&str>)> = vec![(front_matter_end + 2, Vec::new())];
+    let mut fence: Option<&str> = None;
+    for (index, line) in lines.iter().enumerate().skip(front_matter_end + 1){
+        let trimmed = line.trim_start();
+        match fence{
+            Some(marker) if trimmed.starts_with(marker) => fence = None,
+            Some(_) => {},
+            None if trimmed.starts_with("```") => fence = Some("```"),
+            None if trimmed.starts_with("~~~") => fence = Some("~~~"),
+            None if is_separator(line) => {
+                chunks.push((index + 2, Vec::new()));
+                continue;
+            },
+            None => {},
+        }
+        if let Some((_, chunk)) = chunks.last_mut(){
+            chunk.push(line);
+        }
+    }
+
+    let mut cards = Vec::new();
+    for (line, chunk) in chunks{
+        // skip the blank lines at the start of the card
+        let blank_lines = chunk.iter().take_while(|line| line.trim().is_empty()).count();
+        let chunk = &chunk[blank_lines..];
+        let line = line + blank_lines;
+
+        let mut options = None;
+        let mut content_lines = chunk;
+        let opens_options = chunk.first().map(|first| matches!(first.trim(), "```yaml" | "```yml")).unwrap_or(false);
+        if opens_options{
+            let end = match chunk.iter().skip(1).position(|line| line.trim() == "```"){
+                Some(index) => index + 1,
+                None => return Err(format!(":{}:1: this card's ```yaml block never ends", line)),
+            };
+            options = Some(MarkdownBlock{
+                line: line + 1,
+                text: chunk[1..end].join("\n"),
+            });
+            content_lines = &chunk[end + 1..];
+        }
+
+        let content = content_lines.join("\n").trim_matches('\n').to_string();
+        if options.is_none() && content.trim().is_empty(){
+            // `---` at the very end of the file, or two in a row
+            continue;
+        }
+        cards.push(MarkdownCard{
+            options,
+            // the same as a `content: |` block in content.yml
+            content: match content.trim().is_empty(){
+                true => "".to_string(),
+                false => format!("{}\n", content),
+            },
+        });
+    }
+    Ok((front_matter, cards))
+}
+
+///
+/// Deserialize a content.md: the front matter is everything in content.yml except `pages`, and each card's ```yaml block
+///  is everything in a card except `content`
+///
+pub fn parse_markdown(content: &str) -> Result<ContentFile, String>{
+    let (front_matter, cards) = split_markdown(content)?;
+    let mut content_file: ContentFile = parse_at(&front_matter.text, front_matter.line, "")?;
+    if content_file.pages.is_some(){
+        return Err(format!(":{}:1: the cards in a content.md go after the front matter, not in `pages`", front_matter.line));
+    }
+
+    let mut pages = Vec::new();
+    for (i, card) in cards.iter().enumerate(){
+        let mut card_content: CardContent = match &card.options{
+            Some(options) => parse_at(&options.text, options.line, &format!("pages[{}]", i))?,
+            None => CardContent::default(),
+        };
+        if !card.content.is_empty(){
+            card_content.content = Some(card.content.clone());
+        }
+        pages.push(card_content);
+    }
+    content_file.pages = Some(pages);
+    Ok(content_file)
+}
+
+///
+/// `class: big` and `class: [big, red]` are both fine
+///
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error> where D: Deserializer<'de>{
+    struct StringOrList;
+
+    impl<'de> Visitor<'de> for StringOrList{
+        type Value = Vec<String>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result{
+            formatter.write_str("a string or a list of strings")
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Vec<String>, E> where E: serde::de::Error{
+            Ok(vec![value.to_string()])
+        }
+
+        fn visit_unit<E>(self) -> Result<Vec<String>, E> where E: serde::de::Error{
+            Ok(Vec::new())
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Vec<String>, A::Error> where A: SeqAccess<'de>{
+            let mut list = Vec::new();
+            while let Some(item) = seq.next_element::<String>()?{
+                list.push(item);
+            }
+            Ok(list)
+        }
+    }
+
+    deserializer.deserialize_any(StringOrList)
+}
+
+///
+/// Ids, names and titles are strings, but `id: 7` or `title: 1984` shouldn't be an error
+///
+fn scalar_string<'de, D>(deserializer: D) -> Result<Option<String>, D::Error> where D: Deserializer<'de>{
+    struct ScalarString;
+
+    impl<'de> Visitor<'de> for ScalarString{
+        type Value = Option<String>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result{
+            formatter.write_str("a string")
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Option<String>, E> where E: serde::de::Error{
+            Ok(Some(value.to_string()))
+        }
+
+        fn visit_i64<E>(self, value: i64) -> Result<Option<String>, E> where E: serde::de::Error{
+            Ok(Some(value.to_string()))
+        }
+
+        fn visit_u64<E>(self, value: u64) -> Result<Option<String>, E> where E: serde::de::Error{
+            Ok(Some(value.to_string()))
+        }
+
+        fn visit_f64<E>(self, value: f64) -> Result<Option<String>, E> where E: serde::de::Error{
+            Ok(Some(value.to_string()))
+        }
+
+        fn visit_unit<E>(self) -> Result<Option<String>, E> where E: serde::de::Error{
+            Ok(None)
+        }
+    }
+
+    deserializer.deserialize_any(ScalarString)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ministry_directory::MinistryDirectory;
+
+    fn deck(name: &str, files: &[(&str, &str)]) -> MinistryDirectory {
+        let directory_root = std::env::temp_dir().join(format!("cardchapter-content-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory_root);
+        std::fs::create_dir_all(directory_root.join("assets")).unwrap();
+        for (file_name, contents) in files {
+            std::fs::write(directory_root.join(file_name), contents).unwrap();
+        }
+        MinistryDirectory::new(directory_root.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn markdown_decks_are_the_same_as_yaml_ones() {
+        let yaml = deck("yaml", &[("content.yml", include_str!("content.yml"))]);
+        let markdown = deck("markdown", &[("content.md", include_str!("content.md"))]);
+        let cards = |directory: &MinistryDirectory| rocket::serde::json::to_string(&directory.get_deck().unwrap()).unwrap();
+        assert_eq!(cards(&markdown), cards(&yaml));
+    }
+
+    #[test]
+    fn reinitializing_as_markdown_replaces_content_yml() {
+        let directory = deck("reinit", &[("content.yml", include_str!("content.yml"))]);
+        directory.init(true, true).unwrap();
+        assert!(directory.get_content_path().ends_with("content.md"));
+        assert_eq!(directory.get_deck().unwrap().len(), 3);
+    }
+
+    fn load_error(directory: &MinistryDirectory) -> String {
+        load(&directory.get_content_path()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn type_errors_say_where_they_are() {
+        let directory = deck("type-error", &[("content.yml", "name: Bees\nauthor: Alice\npages:\n  - title: Hello\n  - title: Slow\n    fade_in: slow\n")]);
+        let content_path = directory.get_content_path();
+        assert_eq!(load_error(&directory), format!("{}:6:14: pages[1].fade_in: invalid type: string \"slow\", expected i64", content_path.to_str().unwrap()));
+    }
+
+    #[test]
+    fn type_errors_in_included_files_say_which_file() {
+        let directory = deck("include-error", &[
+            ("content.yml", "name: Bees\nauthor: Alice\npages:\n  - title: Hello\n  - include: chapter.yml\n"),
+            ("chapter.yml", "- title: One\n- title: Two\n  fade_in: slow\n"),
+        ]);
+        let chapter_path = directory.get_content_path().with_file_name("chapter.yml");
+        assert_eq!(load_error(&directory), format!("{}:3:12: [1].fade_in: invalid type: string \"slow\", expected i64", chapter_path.to_str().unwrap()));
+    }
+}
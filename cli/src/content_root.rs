@@ -0,0 +1,278 @@
+use std::path::{Component, Path, PathBuf};
+use anyhow::{Result, anyhow};
+
+use crate::ministry_directory::{self, MinistryDirectory};
+
+// everything that comes in from a URL is decoded before we see it: `/s/%2e%2e/secrets` arrives as `..`
+//  so every path built out of one goes through here before it goes anywhere near the disk
+
+///
+/// What a server is for
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+    // the deck in the content root, at `/`: there's no `/s/...`
+    Single,
+    // every `<author_slug>/<deck_slug>` in the content root, at `/s/<author_slug>/<deck_slug>`,
+    //  and at `/`, the deck in the content root (if there is one) or a list of all the others
+    Multi,
+}
+
+impl Mode {
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "single" => Some(Mode::Single),
+            "multi" => Some(Mode::Multi),
+            _ => None,
+        }
+    }
+
+    ///
+    /// For a server that wasn't told: a content root that's a deck, with no other decks in it, is a single deck
+    ///
+    pub fn detect(root: &Path) -> Mode {
+        let is_deck = MinistryDirectory::new(root.to_str().unwrap_or(".").to_string()).exists();
+        let has_decks = ministry_directory::find_deck_paths(root).is_ok_and(|deck_paths| !deck_paths.is_empty());
+        match is_deck && !has_decks {
+            true => Mode::Single,
+            false => Mode::Multi,
+        }
+    }
+}
+
+///
+/// The directory that decks are served out of
+///
+/// decks (and their common/ directories) have to actually be inside it once every symlink and `..` has been followed,
+///  and nothing that's hidden (.git, .env, uploads that are still being unpacked) or in temp_assets is ever handed out
+///
+#[derive(Clone)]
+pub struct ContentRoot{
+    // the paths we hand out are built on this one, so that they look like the ones find_deck_paths finds
+    root: PathBuf,
+    canonical_root: PathBuf,
+    mode: Mode,
+    // converted images: they're served, but only through the asset they were converted from
+    temporary_asset_directory: PathBuf,
+}
+
+impl ContentRoot{
+    pub fn new(root: &Path, temporary_asset_directory: &str, mode: Mode) -> Result<ContentRoot>{
+        let canonical_root = root.canonicalize().map_err(|err| anyhow!("Can't find content root {}: {}", root.display(), err))?;
+        Ok(ContentRoot{
+            root: root.to_path_buf(),
+            canonical_root,
+            mode,
+            temporary_asset_directory: PathBuf::from(temporary_asset_directory),
+        })
+    }
+
+    pub fn path(&self) -> &Path{
+        &self.root
+    }
+
+    pub fn mode(&self) -> Mode{
+        self.mode
+    }
+
+    ///
+    /// Whether there's a deck at `/`
+    ///
+    pub fn has_home(&self) -> bool{
+        self.directory(self.home()).exists()
+    }
+
+    ///
+    /// Every `<author_slug>/<deck_slug>` deck (a single-deck server doesn't have any)
+    ///
+    pub fn decks(&self) -> Result<Vec<PathBuf>>{
+        match self.mode{
+            Mode::Single => Ok(Vec::new()),
+            Mode::Multi => ministry_directory::find_deck_paths(&self.root),
+        }
+    }
+
+    ///
+    /// A deck that's already been resolved (with deck() or home())
+    ///
+    pub fn directory(&self, directory_path: String) -> MinistryDirectory{
+        MinistryDirectory::in_content_root(directory_path, &self.root)
+    }
+
+    ///
+    /// The deck at the top of the content root (the one at `/`)
+    ///
+    pub fn home(&self) -> String{
+        self.root.to_str().unwrap_or(".").to_string()
+    }
+
+    ///
+    /// The directory for `/s/<author_slug>/<deck_slug>`: an error if either isn't a plain name, or if it leads out of the content root
+    ///
+    pub fn deck(&self, author_slug: &str, deck_slug: &str) -> Result<String>{
+        if self.mode == Mode::Single{
+            return Err(anyhow!("This server only has the deck at /"));
+        }
+        for segment in [author_slug, deck_slug]{
+            if !is_plain_name(segment){
+                return Err(anyhow!("Not a deck: {}/{}", author_slug, deck_slug));
+            }
+        }
+        let path = self.root.join(author_slug).join(deck_slug);
+        if !self.contains(&path){
+            return Err(anyhow!("Not a deck: {}/{}", author_slug, deck_slug));
+        }
+        Ok(path.to_str().unwrap_or("").to_string())
+    }
+
+    ///
+    /// Whether `path` exists and is somewhere we're willing to serve from
+    ///
+    /// published decks are symlinks to hidden, versioned directories (`.<deck_slug>.<timestamp>`):
+    ///  the symlink has to be asked for by name, but once it's followed, it's allowed to end up in one of those
+    ///
+    /// (only what's inside the content root counts: it's fine for the content root itself to be in ~/.cardchapter)
+    ///
+    pub fn contains(&self, path: &Path) -> bool{
+        let asked_for = path.strip_prefix(&self.root).unwrap_or(path);
+        let lexically_hidden = asked_for.components().any(|component| match component{
+            Component::Normal(name) => name.to_str().map(|name| name.starts_with('.')).unwrap_or(true),
+            _ => false,
+        });
+        if lexically_hidden{
+            return false;
+        }
+        let Ok(canonical) = path.canonicalize() else {
+            return false;
+        };
+        let Ok(relative) = canonical.strip_prefix(&self.canonical_root) else {
+            return false;
+        };
+        // (it might not have existed yet when we started)
+        if self.temporary_asset_directory.canonicalize().is_ok_and(|temporary| canonical.starts_with(temporary)){
+            return false;
+        }
+        relative.components().all(|component| match component{
+            Component::Normal(name) => name.to_str().map(|name| !name.starts_with('.') || is_published_version(name)).unwrap_or(false),
+            _ => false,
+        })
+    }
+
+    ///
+    /// Find the original file for `asset_path`: in the deck's assets/, or failing that, in one of the common/ directories,
+    ///  but only ever inside the content root: a deck's `../common` and `../../common` are fine, as long as they don't climb out of it
+    ///
+    pub fn find_asset(&self, directory: &MinistryDirectory, asset_path: &Path) -> Result<PathBuf>{
+        // (an asset is in assets/ or common/: never next to them)
+        if !asset_path.components().all(|component| matches!(component, Component::Normal(_))){
+            return Err(anyhow!("Not an asset: {}", asset_path.to_str().unwrap_or("")));
+        }
+        let asset_path = asset_path.to_path_buf();
+        for path in directory.get_alternate_asset_paths(&asset_path){
+            if self.contains(&path) && path.is_file(){
+                return Ok(path);
+            }
+        }
+        Err(anyhow!("File not found: {}", directory.get_asset_path(&asset_path).to_str().unwrap_or("")))
+    }
+}
+
+///
+/// A single, ordinary, visible file or directory name: no `/`, no `..`, no `.git`
+///
+fn is_plain_name(segment: &str) -> bool{
+    let mut components = Path::new(segment).components();
+    match (components.next(), components.next()){
+        (Some(Component::Normal(name)), None) => name == segment && !segment.starts_with('.') && !segment.contains('\\'),
+        _ => false,
+    }
+}
+
+///
+/// `.<deck_slug>.<timestamp>`: a version of a published deck (see publish::swap_in)
+///
+fn is_published_version(name: &str) -> bool{
+    let Some((slug, timestamp)) = name.strip_prefix('.').and_then(|name| name.rsplit_once('.')) else {
+        return false;
+    };
+    !slug.is_empty() && !timestamp.is_empty() && timestamp.chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// A content root (in a hidden directory of its own) with a home deck, alice/bees, a published alice/wasps, and some things that mustn't be served
+    ///
+    fn content_root(name: &str) -> (PathBuf, ContentRoot) {
+        let outside = std::env::temp_dir().join(format!("cardchapter-root-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&outside);
+        let root = outside.join(".cardchapter").join("decks");
+        let write = |path: &Path, contents: &str| {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write(&outside.join("secret.txt"), "secret");
+        write(&root.join("content.yml"), "name: Home\nauthor: Root\npages:\n  - title: Hello\n");
+        write(&root.join("assets").join("home.jpg"), "home");
+        write(&root.join("common").join("shared.jpg"), "shared");
+        write(&root.join(".git").join("config"), "[core]");
+        write(&root.join("temp_assets").join("variant.webp"), "variant");
+        write(&root.join("alice").join("bees").join("content.yml"), "name: Bees\nauthor: Alice\npages:\n  - title: Hello\n");
+        write(&root.join("alice").join("bees").join("assets").join("bee.jpg"), "bee");
+        write(&root.join("alice").join("bees").join("assets").join(".env"), "SECRET=1");
+        write(&root.join("alice").join(".wasps.1700000000").join("content.yml"), "name: Wasps\nauthor: Alice\npages:\n  - title: Hello\n");
+        write(&root.join("alice").join(".wasps.1700000000").join("assets").join("wasp.jpg"), "wasp");
+        std::os::unix::fs::symlink(".wasps.1700000000", root.join("alice").join("wasps")).unwrap();
+
+        let temporary_asset_directory = root.join("temp_assets");
+        let content_root = ContentRoot::new(&root, temporary_asset_directory.to_str().unwrap(), Mode::Multi).unwrap();
+        (root, content_root)
+    }
+
+    #[test]
+    fn serves_decks_in_a_hidden_content_root() {
+        let (root, content_root) = content_root("hidden");
+        assert!(content_root.has_home());
+        let bees = content_root.deck("alice", "bees").unwrap();
+        assert!(content_root.find_asset(&content_root.directory(bees), Path::new("bee.jpg")).is_ok());
+        // (common/ is shared by every deck)
+        let bees = content_root.deck("alice", "bees").unwrap();
+        assert_eq!(content_root.find_asset(&content_root.directory(bees), Path::new("shared.jpg")).unwrap(), root.join("alice/bees/../../common/shared.jpg"));
+        assert!(content_root.find_asset(&content_root.directory(content_root.home()), Path::new("home.jpg")).is_ok());
+    }
+
+    #[test]
+    fn follows_published_decks() {
+        let (_, content_root) = content_root("published");
+        let wasps = content_root.deck("alice", "wasps").unwrap();
+        assert!(content_root.find_asset(&content_root.directory(wasps), Path::new("wasp.jpg")).is_ok());
+        // but not by their hidden names
+        assert!(content_root.deck("alice", ".wasps.1700000000").is_err());
+    }
+
+    #[test]
+    fn refuses_traversal() {
+        let (root, content_root) = content_root("traversal");
+        for (author_slug, deck_slug) in [("..", ".."), ("alice", ".."), ("alice/bees", "assets"), ("", "bees"), ("alice", "bees/.."), ("..\\..", "x")] {
+            assert!(content_root.deck(author_slug, deck_slug).is_err(), "{}/{}", author_slug, deck_slug);
+        }
+        let bees = content_root.directory(content_root.deck("alice", "bees").unwrap());
+        for asset_path in ["../../../../secret.txt", "../content.yml", "/etc/passwd"] {
+            assert!(content_root.find_asset(&bees, Path::new(asset_path)).is_err(), "{}", asset_path);
+        }
+        assert!(!content_root.contains(&root.join("..").join("..").join("secret.txt")));
+    }
+
+    #[test]
+    fn refuses_hidden_files_and_temp_assets() {
+        let (root, content_root) = content_root("refused");
+        let bees = content_root.directory(content_root.deck("alice", "bees").unwrap());
+        assert!(content_root.find_asset(&bees, Path::new(".env")).is_err());
+        assert!(!content_root.contains(&root.join(".git").join("config")));
+        assert!(!content_root.contains(&root.join("temp_assets").join("variant.webp")));
+        assert!(content_root.deck("temp_assets", "variant.webp").is_err());
+        assert!(content_root.deck(".git", "config").is_err());
+    }
+}
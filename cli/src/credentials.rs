@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerCredentials{
+    pub author_slug: String,
+    pub token: String,
+}
+
+///
+/// The tokens we've collected from `cardchapter login`, one per server
+///
+/// these are per-user, not per-deck: they live in ~/.config/cardchapter/credentials.json (or CARDCHAPTER_CREDENTIALS)
+///
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Credentials{
+    servers: BTreeMap<String, ServerCredentials>,
+}
+
+impl Credentials{
+    pub fn path() -> PathBuf{
+        if let Ok(path) = std::env::var("CARDCHAPTER_CREDENTIALS"){
+            return PathBuf::from(path);
+        }
+        let config_directory = std::env::var("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .or_else(|_| std::env::var("APPDATA").map(PathBuf::from))
+            .unwrap_or_else(|_| PathBuf::from("."));
+        config_directory.join("cardchapter").join("credentials.json")
+    }
+
+    pub fn load() -> Result<Credentials>{
+        let path = Credentials::path();
+        if !path.exists(){
+            return Ok(Credentials::default());
+        }
+        Ok(rocket::serde::json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self) -> Result<()>{
+        let path = Credentials::path();
+        if let Some(parent) = path.parent(){
+            std::fs::create_dir_all(parent)?;
+        }
+        // these are passwords, more or less: nobody else needs to read them
+        write_private(&path, rocket::serde::json::to_pretty_string(self)?.as_bytes())
+    }
+
+    pub fn get(&self, server_url: &Url) -> Option<&ServerCredentials>{
+        self.servers.get(server_url.as_str())
+    }
+
+    pub fn set(&mut self, server_url: &Url, credentials: ServerCredentials){
+        self.servers.insert(server_url.as_str().to_string(), credentials);
+    }
+}
+
+///
+/// Replace `path` with `contents`, in a file that only we can read (on unix, anyway): it's never readable by anybody else, not even for a moment
+///
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<()>{
+    let temporary_path = path.with_extension("tmp");
+    // (a leftover from last time might have been created with looser permissions)
+    if temporary_path.exists(){
+        std::fs::remove_file(&temporary_path)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temporary_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary_path, path)?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoginRequest{
+    pub author_slug: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoginResponse{
+    pub author_slug: String,
+    pub token: String,
+}
+
+///
+/// Ask a server for a token
+///
+pub fn login(server_url: &Url, author_slug: &str, password: &str) -> Result<ServerCredentials>{
+    let url = server_url.join("login")?;
+    let request = LoginRequest{
+        author_slug: author_slug.to_string(),
+        password: password.to_string(),
+    };
+    match ureq::post(url.as_str()).send_json(&request){
+        Ok(response) => {
+            let response: LoginResponse = response.into_json()?;
+            Ok(ServerCredentials{
+                author_slug: response.author_slug,
+                token: response.token,
+            })
+        },
+        Err(ureq::Error::Status(401, _)) => Err(anyhow!("Unknown author or wrong password")),
+        Err(ureq::Error::Status(code, _)) => Err(anyhow!("{} refused to log us in ({})", server_url, code)),
+        Err(err) => Err(anyhow!("Couldn't reach {}: {}", server_url, err)),
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use serde::Serialize;
+use slugify::slugify;
+use yaml_rust2::{Yaml, YamlLoader};
+use yaml_rust2::yaml::Hash;
+
+use crate::asset_references::{self, AssetReference};
+use crate::content_file;
+use crate::content_root::{ContentRoot, Mode};
+use crate::ministry_directory::{self, MinistryDirectory};
+
+// anime.js easings: `easing: easeOutElastic(1, .5)` is fine, too
+const EASINGS: [&str; 4] = ["linear", "spring", "cubicBezier", "steps"];
+const EASING_DIRECTIONS: [&str; 4] = ["easeIn", "easeOut", "easeInOut", "easeOutIn"];
+const EASING_CURVES: [&str; 10] = ["Quad", "Cubic", "Quart", "Quint", "Expo", "Sine", "Circ", "Back", "Bounce", "Elastic"];
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity{
+    Error,
+    Warning,
+}
+
+///
+/// One thing that's wrong with a deck: `location` is where in content.yml it is (`pages[3].stack[1]`)
+///
+#[derive(Debug, Serialize, Clone)]
+pub struct Problem{
+    pub severity: Severity,
+    pub deck: String,
+    pub location: String,
+    pub message: String,
+}
+
+struct Checker{
+    // assets are looked up the way the server looks them up, so that we only pass the ones it'll serve
+    content_root: ContentRoot,
+    directory: MinistryDirectory,
+    deck: String,
+    problems: Vec<Problem>,
+    // card id -> where we first saw it
+    ids: HashMap<String, String>,
+    // the files we're in the middle of checking, so that an include loop doesn't loop forever here too
+    including: Vec<PathBuf>,
+    // everything ContentFile and CardContent know how to read: anything else in content.yml is ignored
+    deck_keys: Vec<String>,
+    card_keys: Vec<String>,
+}
+
+impl Checker{
+    fn report(&mut self, severity: Severity, location: &str, message: String){
+        self.problems.push(Problem{
+            severity,
+            deck: self.deck.clone(),
+            location: location.to_string(),
+            message,
+        });
+    }
+
+    fn check_deck(&mut self, doc: &Yaml, is_home: bool){
+        // the same mandatory fields as get_metadata (which we don't call, because it prints as it goes)
+        if doc["name"].as_str().or(doc["title"].as_str()).unwrap_or("").is_empty(){
+            self.report(Severity::Error, "name", "No name or title found: this is a mandatory field".to_string());
+        }
+        if doc["author"].as_str().unwrap_or("").is_empty(){
+            self.report(Severity::Error, "author", "No author found: this is a mandatory field".to_string());
+        }
+        if !is_home{
+            self.check_location(doc);
+        }
+
+        self.check_keys(doc, "", &self.deck_keys.clone());
+        for key in ["image", "favicon", "mp3"]{
+            if let Some(link) = doc[key].as_str(){
+                self.check_link(key, link);
+            }
+        }
+
+        match doc["pages"].as_vec(){
+            Some(pages) if !pages.is_empty() => {
+                self.check_cards(pages, "pages", "", &mut 0);
+            },
+            _ => self.report(Severity::Error, "pages", "No cards found: `pages` should be a list of cards".to_string()),
+        }
+    }
+
+    ///
+    /// get_metadata only checks that the directory path _contains_ the slugs: decks are served from exactly <author_slug>/<slug>
+    ///
+    fn check_location(&mut self, doc: &Yaml){
+        let name = doc["name"].as_str().or(doc["title"].as_str()).unwrap_or("");
+        let author = doc["author"].as_str().unwrap_or("");
+        let path = PathBuf::from(&self.deck);
+        let directory_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        let author_directory_name = path.parent().and_then(|parent| parent.file_name()).and_then(|name| name.to_str()).unwrap_or("");
+        if !name.is_empty() && directory_name != slugify!(name){
+            self.report(Severity::Error, "name", format!("This deck's slug is `{}`, but it's in a directory called `{}`", slugify!(name), directory_name));
+        }
+        if !author.is_empty() && author_directory_name != slugify!(author){
+            self.report(Severity::Error, "author", format!("This deck's author slug is `{}`, but it's in a directory called `{}`", slugify!(author), author_directory_name));
+        }
+    }
+
+    fn check_keys(&mut self, doc: &Yaml, location: &str, known_keys: &[String]){
+        let Some(hash) = doc.as_hash() else { return };
+        for key in hash.keys(){
+            match key.as_str(){
+                Some(key) if known_keys.iter().any(|known_key| known_key == key) => {},
+                Some(key) => self.report(Severity::Warning, location, format!("Unknown key `{}` will be ignored", key)),
+                None => self.report(Severity::Warning, location, format!("Unknown key {:?} will be ignored", key)),
+            }
+        }
+    }
+
+    fn check_card(&mut self, doc: &Yaml, location: &str, default_id: String){
+        if doc.as_hash().is_none(){
+            self.report(Severity::Warning, location, "This card isn't a map of keys and values, so it'll be an empty title card".to_string());
+        }
+        self.check_keys(doc, location, &self.card_keys.clone());
+
+        // the same ids that parse_card would come up with
+        let id = slugify!(doc["id"].as_str().unwrap_or(&default_id));
+        match self.ids.get(&id){
+            Some(first_location) => {
+                let message = format!("Duplicate card id `{}` (first used at {})", id, first_location);
+                self.report(Severity::Error, location, message);
+            },
+            None => {
+                self.ids.insert(id.clone(), location.to_string());
+            },
+        }
+
+        if let Some(easing) = doc["easing"].as_str(){
+            if !is_easing(easing){
+                self.report(Severity::Error, location, format!("Unknown easing `{}` (try linear, easeInOutQuad, easeOutElastic(1, .5), ...)", easing));
+            }
+        }
+
+        for key in ["image", "video"]{
+            if let Some(link) = doc[key].as_str(){
+                self.check_link(&format!("{}.{}", location, key), link);
+            }
+        }
+        if let Some(pngs) = doc["pngs"].as_str(){
+            self.check_pngs(&format!("{}.pngs", location), pngs);
+        }
+        for key in ["content", "animated_text", "footnote"]{
+            if let Some(text) = doc[key].as_str(){
+                for reference in asset_references::find_in_text(text){
+                    self.check_asset(&format!("{}.{}", location, key), &reference);
+                }
+            }
+        }
+
+        // stacks number their cards straight through `pages` and then `stack`
+        let mut counter = 0;
+        for key in ["pages", "stack"]{
+            if let Some(cards) = doc[key].as_vec(){
+                self.check_cards(cards, &format!("{}.{}", location, key), &format!("{}-", id), &mut counter);
+            }
+        }
+    }
+
+    ///
+    /// Check a list of cards, following `include:`s: `counter` is the position of the next card, for default ids
+    ///
+    fn check_cards(&mut self, cards: &[Yaml], location: &str, id_prefix: &str, counter: &mut usize){
+        for (i, card) in cards.iter().enumerate(){
+            let card_location = format!("{}[{}]", location, i);
+            if let Some(include) = card["include"].as_str(){
+                self.check_include(include, id_prefix, counter);
+                continue;
+            }
+            self.check_card(card, &card_location, format!("{}{}", id_prefix, counter));
+            *counter += 1;
+        }
+    }
+
+    fn check_include(&mut self, include: &str, id_prefix: &str, counter: &mut usize){
+        // a bad path, a missing file or a loop has already been reported by get_content_file: there's nothing to check
+        if Path::new(include).components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir)){
+            return;
+        }
+        let path = PathBuf::from(&self.deck).join(include);
+        if self.including.contains(&path){
+            return;
+        }
+        let Ok(content) = std::fs::read_to_string(&path) else { return };
+        let Ok(docs) = YamlLoader::load_from_str(&content) else { return };
+        if let Some(cards) = docs.first().and_then(|doc| doc.as_vec()){
+            self.including.push(path);
+            self.check_cards(cards, include, id_prefix, counter);
+            self.including.pop();
+        }
+    }
+
+    fn check_link(&mut self, location: &str, link: &str){
+        if link.starts_with("http://") || link.starts_with("https://") || link.starts_with("//") || link.starts_with("data:"){
+            return;
+        }
+        match AssetReference::parse(link){
+            Some(reference) => self.check_asset(location, &reference),
+            None => self.report(Severity::Error, location, format!("`{}` isn't in assets/, so it won't be served", link)),
+        }
+    }
+
+    fn check_asset(&mut self, location: &str, reference: &AssetReference){
+        if self.content_root.find_asset(&self.directory, &reference.asset_path).is_err(){
+            self.report(Severity::Error, location, format!("Missing asset: {}", reference.reference));
+        }
+    }
+
+    fn check_pngs(&mut self, location: &str, directory: &str){
+        let path = PathBuf::from(&self.deck).join(directory);
+        let pngs = match std::fs::read_dir(&path){
+            Ok(entries) => entries.filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().extension().and_then(|extension| extension.to_str()) == Some("png"))
+                .count(),
+            Err(_) => {
+                self.report(Severity::Error, location, format!("Missing pngs directory: {}", directory));
+                return;
+            }
+        };
+        if pngs == 0{
+            self.report(Severity::Warning, location, format!("There aren't any .png files in {}", directory));
+        }
+    }
+}
+
+fn is_easing(easing: &str) -> bool{
+    let name = easing.split('(').next().unwrap_or("").trim();
+    EASINGS.contains(&name) || EASING_DIRECTIONS.iter().any(|direction| {
+        name.strip_prefix(direction).is_some_and(|curve| EASING_CURVES.contains(&curve))
+    })
+}
+
+///
+/// Check one deck directory, reporting everything that's wrong with it (rather than stopping at the first problem)
+///
+/// the home deck (the one at the root) is allowed to live in a directory that doesn't match its slug
+///
+pub fn check_deck(content_root: &ContentRoot, deck_path: &Path, is_home: bool) -> Vec<Problem>{
+    let deck = deck_path.to_str().unwrap_or(".").to_string();
+    let directory = content_root.directory(deck.clone());
+    let content_path = directory.get_content_path();
+    let content_file_name = content_path.file_name().and_then(|name| name.to_str()).unwrap_or("").to_string();
+    let mut checker = Checker{
+        content_root: content_root.clone(),
+        directory,
+        deck,
+        problems: Vec::new(),
+        ids: HashMap::new(),
+        including: vec![content_path.clone()],
+        deck_keys: content_file::deck_keys(),
+        card_keys: content_file::card_keys(),
+    };
+
+    let content = match checker.directory._get_content(){
+        Ok(content) => content,
+        Err(err) => {
+            checker.report(Severity::Error, "", format!("Couldn't read {}: {}", content_file_name, err));
+            return checker.problems;
+        }
+    };
+    // wrong types (`fade_in: slow`, `type: slideshow`) and broken includes stop the deck from loading at all
+    if let Err(err) = checker.directory.get_content_file(){
+        checker.report(Severity::Error, "", err.to_string());
+    }
+    if content_file_name.ends_with(".md"){
+        // if it can't be split up, get_content_file has already told us why
+        if let Some(doc) = markdown_to_yaml(&content){
+            checker.check_deck(&doc, is_home);
+        }
+        return checker.problems;
+    }
+    match YamlLoader::load_from_str(&content){
+        Ok(docs) if !docs.is_empty() => checker.check_deck(&docs[0], is_home),
+        Ok(_) => checker.report(Severity::Error, "", format!("{} is empty", content_file_name)),
+        Err(err) => checker.report(Severity::Error, "", format!("{} isn't valid YAML: {}", content_file_name, err)),
+    }
+    checker.problems
+}
+
+///
+/// A content.md, as the YAML document that the same deck would be as a content.yml: so that it can be checked the same way
+///
+fn markdown_to_yaml(content: &str) -> Option<Yaml>{
+    let (front_matter, cards) = content_file::split_markdown(content).ok()?;
+    let mut doc = load_hash(&front_matter.text)?;
+    let mut pages = Vec::new();
+    for card in cards{
+        let mut card_doc = match &card.options{
+            Some(options) => load_hash(&options.text)?,
+            None => Hash::new(),
+        };
+        if !card.content.is_empty(){
+            card_doc.insert(Yaml::String("content".to_string()), Yaml::String(card.content));
+        }
+        pages.push(Yaml::Hash(card_doc));
+    }
+    doc.insert(Yaml::String("pages".to_string()), Yaml::Array(pages));
+    Some(Yaml::Hash(doc))
+}
+
+fn load_hash(text: &str) -> Option<Hash>{
+    match YamlLoader::load_from_str(text).ok()?.into_iter().next(){
+        Some(Yaml::Hash(hash)) => Some(hash),
+        None => Some(Hash::new()),
+        Some(_) => None,
+    }
+}
+
+///
+/// Check the deck at `root` (if there is one) and every <author>/<deck> underneath it
+///
+/// the deck at `root` is the home deck, however `root` is spelled (`.`, `./`, `/srv/decks`)
+///
+pub fn check_root(root: &Path, temporary_asset_directory: &str) -> Vec<Problem>{
+    let mut problems = Vec::new();
+    let mut found = false;
+    let root_string = root.to_str().unwrap_or(".").to_string();
+    let content_root = match ContentRoot::new(root, temporary_asset_directory, Mode::detect(root)){
+        Ok(content_root) => content_root,
+        Err(err) => return vec![Problem{
+            severity: Severity::Error,
+            deck: root_string,
+            location: "".to_string(),
+            message: err.to_string(),
+        }],
+    };
+    if MinistryDirectory::new(root_string.clone()).exists(){
+        found = true;
+        problems.extend(check_deck(&content_root, root, true));
+    }
+    match ministry_directory::find_deck_paths(root){
+        Ok(deck_paths) => {
+            for deck_path in deck_paths{
+                found = true;
+                problems.extend(check_deck(&content_root, &deck_path, false));
+            }
+        },
+        Err(err) => problems.push(Problem{
+            severity: Severity::Error,
+            deck: root_string.clone(),
+            location: "".to_string(),
+            message: format!("Couldn't look for decks: {}", err),
+        }),
+    }
+    if !found && problems.is_empty(){
+        problems.push(Problem{
+            severity: Severity::Error,
+            deck: root_string,
+            location: "".to_string(),
+            message: "No decks found: a deck is a directory with a content.yml (or content.md) and an assets/ directory".to_string(),
+        });
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// A deck with `content` as its content.yml, in a directory that's named nothing like its slug
+    ///
+    fn deck(name: &str, content: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cardchapter-check-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join("content.yml"), content).unwrap();
+        root
+    }
+
+    #[test]
+    fn the_root_deck_is_home_however_its_spelled() {
+        let root = deck("home", "name: Bees\nauthor: Alice\npages:\n  - title: Hello\n");
+        let spellings = [root.clone(), PathBuf::from(format!("{}/", root.to_str().unwrap())), root.join(".")];
+        for spelling in spellings {
+            let problems = check_root(&spelling, "temp_assets");
+            assert!(problems.is_empty(), "{}: {:?}", spelling.display(), problems);
+        }
+    }
+
+    #[test]
+    fn warns_about_keys_the_deck_doesnt_know() {
+        let root = deck("keys", "name: Bees\nauthor: Alice\ncolour: red\npages:\n  - title: Hello\n    type: title\n    loop: true\n    fade: 500\n");
+        let problems = check_root(&root, "temp_assets");
+        let messages: Vec<(&str, &str)> = problems.iter().map(|problem| (problem.location.as_str(), problem.message.as_str())).collect();
+        assert_eq!(messages, vec![
+            ("", "Unknown key `colour` will be ignored"),
+            ("pages[0]", "Unknown key `fade` will be ignored"),
+        ]);
+    }
+
+    #[test]
+    fn knows_every_key_in_the_schema() {
+        let deck_keys = content_file::deck_keys();
+        let card_keys = content_file::card_keys();
+        for key in ["name", "title", "author", "extra_header", "pages"] {
+            assert!(deck_keys.iter().any(|known_key| known_key == key), "{}", key);
+        }
+        // (renamed fields go by the name that's written in content.yml)
+        for key in ["type", "loop", "include", "stack", "document_class"] {
+            assert!(card_keys.iter().any(|known_key| known_key == key), "{}", key);
+        }
+        assert!(!deck_keys.iter().any(|known_key| known_key == "included_files"));
+        assert!(!card_keys.iter().any(|known_key| known_key == "card_type" || known_key == "is_loop"));
+        assert_eq!((deck_keys.len(), card_keys.len()), (16, 46));
+    }
+
+    #[test]
+    fn only_passes_assets_the_server_will_serve() {
+        let outside = std::env::temp_dir().join(format!("cardchapter-check-outside-{}", std::process::id()));
+        let root = outside.join("decks");
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::create_dir_all(outside.join("common")).unwrap();
+        std::fs::write(root.join("assets").join(".hidden.jpg"), "hidden").unwrap();
+        std::fs::write(root.join("assets").join("bee.jpg"), "bee").unwrap();
+        std::fs::write(outside.join("common").join("outside.jpg"), "outside").unwrap();
+        std::fs::write(root.join("content.yml"), "name: Bees\nauthor: Alice\npages:\n  - image: assets/bee.jpg\n  - image: assets/.hidden.jpg\n  - image: assets/outside.jpg\n").unwrap();
+
+        let problems = check_root(&root, root.join("temp_assets").to_str().unwrap());
+        let messages: Vec<(&str, &str)> = problems.iter().map(|problem| (problem.location.as_str(), problem.message.as_str())).collect();
+        assert_eq!(messages, vec![
+            ("pages[1].image", "Missing asset: assets/.hidden.jpg"),
+            ("pages[2].image", "Missing asset: assets/outside.jpg"),
+        ]);
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use rocket::serde::json::serde_json::{self, Value};
+use url::Url;
+
+use crate::asset_references;
+use crate::ministry_directory::MinistryDirectory;
+
+#[derive(Debug)]
+pub struct CardChange{
+    pub id: String,
+    pub fields: Vec<String>,
+}
+
+///
+/// Everything that `cardchapter publish` would change on the server
+///
+#[derive(Debug, Default)]
+pub struct DeckDiff{
+    pub published: bool,
+    pub metadata_fields: Vec<String>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub reordered: Vec<String>,
+    pub modified: Vec<CardChange>,
+    pub assets_added: Vec<String>,
+    pub assets_removed: Vec<String>,
+    pub assets_changed: Vec<String>,
+    // older servers don't have a manifest
+    pub assets_compared: bool,
+}
+
+impl DeckDiff{
+    pub fn is_empty(&self) -> bool{
+        self.published && self.metadata_fields.is_empty() && self.added.is_empty() && self.removed.is_empty() &&
+            self.reordered.is_empty() && self.modified.is_empty() &&
+            self.assets_added.is_empty() && self.assets_removed.is_empty() && self.assets_changed.is_empty()
+    }
+
+    pub fn print(&self){
+        if !self.published {
+            println!("This deck hasn't been published yet: everything is new.");
+        }
+        if !self.metadata_fields.is_empty(){
+            println!("~ deck: {}", self.metadata_fields.join(", "));
+        }
+        for id in &self.added{
+            println!("+ {}", id);
+        }
+        for id in &self.removed{
+            println!("- {}", id);
+        }
+        for change in &self.modified{
+            println!("~ {}: {}", change.id, change.fields.join(", "));
+        }
+        for id in &self.reordered{
+            println!("↕ {}", id);
+        }
+        for path in &self.assets_added{
+            println!("+ {}", path);
+        }
+        for path in &self.assets_removed{
+            println!("- {}", path);
+        }
+        for path in &self.assets_changed{
+            println!("~ {}", path);
+        }
+        if !self.assets_compared {
+            println!("(the server didn't tell us about its assets, so they weren't compared)");
+        }
+        if self.is_empty(){
+            println!("No changes.");
+        }
+    }
+}
+
+///
+/// Compare the deck in `directory_root` with the version that's published on `server_url`, card by card
+///
+pub fn diff(directory_root: &Path, server_url: &Url) -> Result<DeckDiff>{
+    let directory = MinistryDirectory::new(directory_root.to_str().unwrap_or(".").to_string());
+    let metadata = directory.get_metadata()?;
+    let local_cards: Vec<Value> = directory.get_deck()?.iter().map(serde_json::to_value).collect::<Result<_, _>>()?;
+    let local_metadata = serde_json::to_value(&metadata)?;
+    let local_assets = directory.get_asset_hashes()?;
+
+    let deck_url = server_url.join(&format!("s/{}/{}/", metadata.author_slug, metadata.slug))?;
+    println!("Comparing {} with {}", directory_root.to_str().unwrap_or("."), deck_url);
+
+    let remote_index = match get_json(&deck_url.join("index")?)?{
+        Some(remote_index) => remote_index,
+        None => {
+            return Ok(DeckDiff{
+                published: false,
+                added: local_cards.iter().map(card_id).collect(),
+                assets_added: local_assets.into_keys().collect(),
+                assets_compared: true,
+                ..DeckDiff::default()
+            });
+        }
+    };
+    let remote_ids: Vec<String> = remote_index["deck_ids"].as_array().ok_or_else(|| anyhow!("The server sent a strange index"))?
+        .iter().filter_map(|id| id.as_str().map(|id| id.to_string())).collect();
+    let remote_cards = get_remote_cards(&deck_url, &remote_ids)?;
+
+    let mut diff = DeckDiff{
+        published: true,
+        metadata_fields: changed_fields("", &local_metadata, &remote_index["metadata"]).into_iter()
+            .filter(|field| !field.starts_with("last_update_time"))
+            .collect(),
+        ..DeckDiff::default()
+    };
+
+    // cards, by id
+    let local_ids: Vec<String> = local_cards.iter().map(card_id).collect();
+    let local_id_set: HashSet<&String> = local_ids.iter().collect();
+    let remote_id_set: HashSet<&String> = remote_ids.iter().collect();
+    diff.added = local_ids.iter().filter(|id| !remote_id_set.contains(id)).cloned().collect();
+    diff.removed = remote_ids.iter().filter(|id| !local_id_set.contains(id)).cloned().collect();
+
+    let remote_by_id: HashMap<String, &Value> = remote_cards.iter().map(|card| (card_id(card), card)).collect();
+    for card in &local_cards{
+        if let Some(remote_card) = remote_by_id.get(&card_id(card)){
+            let fields = changed_fields("", card, remote_card);
+            if !fields.is_empty(){
+                diff.modified.push(CardChange{ id: card_id(card), fields });
+            }
+        }
+    }
+
+    // a card has moved if it isn't part of the longest run of cards that kept their relative order
+    let local_common: Vec<&String> = local_ids.iter().filter(|id| remote_id_set.contains(id)).collect();
+    let remote_common: Vec<&String> = remote_ids.iter().filter(|id| local_id_set.contains(id)).collect();
+    let unmoved = longest_common_subsequence(&local_common, &remote_common);
+    diff.reordered = local_common.into_iter().filter(|id| !unmoved.contains(id)).cloned().collect();
+
+    // assets, by hash
+    if let Some(remote_manifest) = get_json(&deck_url.join("manifest")?)?{
+        let remote_assets: BTreeMap<String, String> = serde_json::from_value(remote_manifest)?;
+        diff.assets_compared = true;
+        for (path, hash) in &local_assets{
+            match remote_assets.get(path){
+                None => diff.assets_added.push(path.clone()),
+                Some(remote_hash) if remote_hash != hash => diff.assets_changed.push(path.clone()),
+                _ => {},
+            }
+        }
+        diff.assets_removed = remote_assets.into_keys().filter(|path| !local_assets.contains_key(path)).collect();
+    }
+
+    Ok(diff)
+}
+
+fn card_id(card: &Value) -> String{
+    card["id"].as_str().unwrap_or("").to_string()
+}
+
+///
+/// GET some JSON: None if the server doesn't have it
+///
+fn get_json(url: &Url) -> Result<Option<Value>>{
+    match ureq::get(url.as_str()).call(){
+        Ok(response) => Ok(Some(response.into_json()?)),
+        Err(ureq::Error::Status(404, _)) => Ok(None),
+        Err(ureq::Error::Status(status, _)) => Err(anyhow!("{} said {}", url, status)),
+        Err(err) => Err(anyhow!("Couldn't reach {}: {}", url, err)),
+    }
+}
+
+///
+/// Page through /range until we've seen every card in the deck
+///
+fn get_remote_cards(deck_url: &Url, remote_ids: &[String]) -> Result<Vec<Value>>{
+    let mut cards: Vec<Value> = Vec::new();
+    let mut start_id = "0".to_string();
+    while cards.len() < remote_ids.len(){
+        let url = deck_url.join(&format!("range/{}/0", start_id))?;
+        let page = get_json(&url)?.ok_or_else(|| anyhow!("Couldn't get cards from {}", url))?;
+        let page = page.as_array().ok_or_else(|| anyhow!("The server sent a strange range"))?.clone();
+        // each page starts with the card we asked for, which we already have (unless this is the first page)
+        let skip = if cards.is_empty() { 0 } else { 1 };
+        let new_cards: Vec<Value> = page.into_iter().skip(skip).collect();
+        if new_cards.is_empty(){
+            break;
+        }
+        cards.extend(new_cards);
+        start_id = card_id(cards.last().unwrap_or(&Value::Null));
+    }
+    Ok(cards)
+}
+
+///
+/// Which fields differ between two JSON values? (`stack[1].fade_in`, etc)
+///
+fn changed_fields(path: &str, local: &Value, remote: &Value) -> Vec<String>{
+    match (local, remote){
+        (Value::Object(local), Value::Object(remote)) => {
+            let mut keys: Vec<&String> = local.keys().chain(remote.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            keys.into_iter().flat_map(|key| {
+                let child_path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                changed_fields(&child_path, local.get(key).unwrap_or(&Value::Null), remote.get(key).unwrap_or(&Value::Null))
+            }).collect()
+        },
+        (Value::Array(local_items), Value::Array(remote_items)) if local_items.len() == remote_items.len() && local_items.iter().any(|item| item.is_object()) => {
+            local_items.iter().zip(remote_items).enumerate().flat_map(|(i, (local_item, remote_item))| {
+                changed_fields(&format!("{}[{}]", path, i), local_item, remote_item)
+            }).collect()
+        },
+        // the server points asset links at versions of their files: that isn't a change to the deck
+        (Value::String(local), Value::String(remote)) if *local == unversioned(remote) => vec![],
+        _ if local == remote => vec![],
+        _ => vec![path.to_string()],
+    }
+}
+
+///
+/// Take the `v=` back off every asset link in some text from the server: `assets/bee.jpg?width=100&v=0123456789abcdef` -> `assets/bee.jpg?width=100`
+///
+fn unversioned(text: &str) -> String{
+    asset_references::rewrite_text(text, |reference| {
+        let (link, version) = reference.reference.rsplit_once("v=")?;
+        let link = link.strip_suffix('?').or(link.strip_suffix('&'))?;
+        match !version.is_empty() && version.chars().all(|c| c.is_ascii_hexdigit()){
+            true => Some(link.to_string()),
+            false => None,
+        }
+    })
+}
+
+fn longest_common_subsequence<'a>(a: &[&'a String], b: &[&'a String]) -> HashSet<&'a String>{
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev(){
+        for j in (0..b.len()).rev(){
+            lengths[i][j] = match a[i] == b[j]{
+                true => lengths[i + 1][j + 1] + 1,
+                false => lengths[i + 1][j].max(lengths[i][j + 1]),
+            };
+        }
+    }
+    let mut common = HashSet::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len(){
+        if a[i] == b[j]{
+            common.insert(a[i]);
+            i += 1;
+            j += 1;
+        }
+        else if lengths[i + 1][j] >= lengths[i][j + 1]{
+            i += 1;
+        }
+        else{
+            j += 1;
+        }
+    }
+    common
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    ///
+    /// A server that answers exactly one request with `status`
+    ///
+    fn answering(status: &'static str) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/index", listener.local_addr().unwrap())).unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 4096];
+            let _ = stream.read(&mut request);
+            let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+        });
+        url
+    }
+
+    #[test]
+    fn versions_on_asset_links_arent_changes() {
+        let local = serde_json::json!({
+            "image_url": "assets/bee.jpg",
+            "content": "![A bee](assets/bee.jpg?width=100) and ![another](/assets/wow.png)",
+        });
+        let remote = serde_json::json!({
+            "image_url": "assets/bee.jpg?v=0123456789abcdef",
+            "content": "![A bee](assets/bee.jpg?width=100&v=0123456789abcdef) and ![another](/assets/wow.png?v=fedcba9876543210)",
+        });
+        assert!(changed_fields("", &local, &remote).is_empty());
+
+        let remote = serde_json::json!({
+            "image_url": "assets/wow.png?v=0123456789abcdef",
+            "content": "![A bee](assets/bee.jpg?width=200&v=0123456789abcdef) and ![another](/assets/wow.png?v=fedcba9876543210)",
+        });
+        assert_eq!(changed_fields("", &local, &remote), vec!["content", "image_url"]);
+    }
+
+    #[test]
+    fn only_a_404_means_unpublished() {
+        assert!(get_json(&answering("404 Not Found")).unwrap().is_none());
+        let err = get_json(&answering("500 Internal Server Error")).unwrap_err();
+        assert!(err.to_string().contains("500"), "{}", err);
+    }
+}
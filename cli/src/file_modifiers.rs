@@ -1,7 +1,8 @@
 use std::collections::HashMap;
-use std::cell::LazyCell;
+use std::fmt;
+use std::sync::LazyLock;
 
-#[derive(FromForm)]
+#[derive(FromForm, Default, Clone, Debug)]
 pub struct FileDirectives{
     pub unmodified: Option<bool>, // return the image as-is
     pub grayscale: Option<bool>, // remove color from the image
@@ -36,11 +37,78 @@ fn generate_color_map() -> HashMap<&'static str, (u8, u8, u8)>{
     map
 }
 
-const COLOR_MAP: LazyCell<HashMap<&'static str, (u8, u8, u8)>> = LazyCell::new(|| generate_color_map());
+static COLOR_MAP: LazyLock<HashMap<&'static str, (u8, u8, u8)>> = LazyLock::new(generate_color_map);
 
 
 impl FileDirectives{
-    pub fn to_string(&self) -> String{
+    ///
+    /// Parse directives out of a query string like `width=100&grayscale=true`
+    ///  (the same thing rocket does for us when a request comes in, but for asset links we find in a deck)
+    ///
+    pub fn from_query(query: &str) -> FileDirectives{
+        let mut directives = FileDirectives::default();
+        for pair in query.trim_start_matches('?').split('&'){
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("true");
+            let flag = value != "false" && value != "0";
+            match key{
+                "unmodified" => directives.unmodified = Some(flag),
+                "grayscale" => directives.grayscale = Some(flag),
+                "tall" => directives.tall = Some(flag),
+                "wide" => directives.wide = Some(flag),
+                "width" => directives.width = value.parse().ok(),
+                "height" => directives.height = value.parse().ok(),
+                "blur" => directives.blur = value.parse().ok(),
+                "flip_horizontal" => directives.flip_horizontal = Some(flag),
+                "flip_vertical" => directives.flip_vertical = Some(flag),
+                "flip_turnwise" => directives.flip_turnwise = Some(flag),
+                "color" => directives.color = Some(value.to_string()),
+                _ => {},
+            }
+        }
+        directives
+    }
+
+    ///
+    /// Like to_string, but two sets of directives that produce the same image always get the same string:
+    ///  things that don't do anything (width=0, blur=0, grayscale when there's a color, wide when there's a width) are dropped,
+    ///  and colors are always spelled as hex
+    ///
+    pub fn canonical(&self) -> String{
+        let width = self.width.filter(|width| *width > 0);
+        let height = self.height.filter(|height| *height > 0);
+        let color = self.color().map(|(r, g, b)| format!("{:02x}{:02x}{:02x}", r, g, b));
+        FileDirectives{
+            unmodified: None,
+            grayscale: Some(self.grayscale.unwrap_or(false) && color.is_none()),
+            tall: Some(self.tall.unwrap_or(false) && height.is_none()),
+            wide: Some(self.wide.unwrap_or(false) && width.is_none()),
+            width,
+            height,
+            blur: self.blur.filter(|blur| *blur > 0.1),
+            flip_horizontal: self.flip_horizontal,
+            flip_vertical: self.flip_vertical,
+            flip_turnwise: self.flip_turnwise,
+            color,
+        }.to_string()
+    }
+
+    pub fn color(&self) -> Option<(u8, u8, u8)>{
+        let color = self.color.as_ref()?;
+        if let Some(color) = COLOR_MAP.get(color.replace("_", " ").to_lowercase().as_str()){
+            return Some(*color);
+        }
+
+        let r = u8::from_str_radix(&color[0..2], 16).unwrap_or(0);
+        let g = u8::from_str_radix(&color[2..4], 16).unwrap_or(0);
+        let b = u8::from_str_radix(&color[4..6], 16).unwrap_or(0);
+        Some((r, g, b))
+    }
+}
+
+impl fmt::Display for FileDirectives{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
         let mut directives = vec![];
         // unmodified is not included because... if the file isn't modified, we don't need to save anything
         if self.grayscale.unwrap_or(false){
@@ -73,21 +141,6 @@ impl FileDirectives{
         if let Some(color) = &self.color{
             directives.push(format!("color{}", color));
         }
-        directives.join("_")
+        write!(f, "{}", directives.join("_"))
     }
-
-    pub fn color(&self) -> Option<(u8, u8, u8)>{
-        if self.color.is_none(){
-            return None;
-        }
-        let color = self.color.as_ref().unwrap();
-        if let Some(color) = COLOR_MAP.get(color.replace("_", " ").to_lowercase().as_str()){
-            return Some(*color);
-        }
-
-        let r = u8::from_str_radix(&color[0..2], 16).unwrap_or(0);
-        let g = u8::from_str_radix(&color[2..4], 16).unwrap_or(0);
-        let b = u8::from_str_radix(&color[4..6], 16).unwrap_or(0);
-        Some((r, g, b))
-    }
-}
\ No newline at end of file
+}
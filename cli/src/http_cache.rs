@@ -0,0 +1,47 @@
+use std::io::Cursor;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use serde::Serialize;
+
+use crate::ministry_directory::sha256_hex;
+
+// decks change whenever their authors feel like it, so nothing that comes out of one can be cached for long:
+//  but a browser that already has the latest version can find that out without downloading it again
+
+///
+/// JSON with an ETag (a hash of the JSON itself): if the client already has exactly this, it gets a 304 and an empty body
+///
+pub struct CachedJson<T>(pub T);
+
+impl<'r, T: Serialize> Responder<'r, 'static> for CachedJson<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = rocket::serde::json::to_string(&self.0).map_err(|err| {
+            println!("Error serializing response: {}", err);
+            Status::InternalServerError
+        })?;
+        let etag = format!("\"{}\"", &sha256_hex(body.as_bytes())[..32]);
+
+        let mut response = match etag_matches(request.headers().get_one("If-None-Match"), &etag){
+            true => Response::build().status(Status::NotModified).finalize(),
+            false => Response::build().header(ContentType::JSON).sized_body(body.len(), Cursor::new(body)).finalize(),
+        };
+        response.set_raw_header("ETag", etag);
+        response.set_raw_header("Cache-Control", "no-cache");
+        Ok(response)
+    }
+}
+
+///
+/// Whether an `If-None-Match` header (`"abc"`, `W/"abc", "def"` or `*`) lists `etag`
+///
+pub fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    let Some(if_none_match) = if_none_match else {
+        return false;
+    };
+    // If-None-Match uses the weak comparison: W/"abc" is as good as "abc"
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.split(',')
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
@@ -0,0 +1,758 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use anyhow::{Result, anyhow};
+use image::{ImageReader, imageops, DynamicImage, ImageBuffer};
+use moka::future::Cache;
+use rocket::tokio::sync::Semaphore;
+use serde::{Deserialize, Serialize};
+use webp::Encoder;
+
+use crate::Config;
+use crate::file_modifiers::FileDirectives;
+use crate::ministry_directory::{sha256_file, sha256_hex};
+
+///
+/// Turns images into resized/recolored .webp variants, off the async executor:
+///
+/// - at most `workers` conversions run at once (decoding and encoding big images is expensive, and the rest of the server has work to do)
+/// - if several requests want the same variant at the same time, it's only converted once and they all wait for it
+/// - variants are written to a temporary file and renamed into place, so nobody ever reads half of one
+///
+/// variants are named after a hash of the source image's bytes and of exactly what was done to them:
+///  so the same image used by ten decks is converted once, and two different `bee.jpg`s never collide.
+///  index.json (next to the variants) remembers the hashes of source files, so that we don't have to re-read them on every request
+///
+/// index.json is saved a few seconds after it changes (once, however many images were converted in the meantime), and call flush()
+///  before exiting: a server and `cardchapter clean` can share the directory, so saves take index.lock and keep what the other one saved
+///
+pub struct ImageConverter{
+    directory: PathBuf,
+    permits: Semaphore,
+    // variant path -> itself, for as long as it's being converted
+    in_flight: Cache<PathBuf, PathBuf>,
+    // how many images we've converted since we started
+    converted: AtomicU64,
+    index: Arc<IndexFile>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct VariantIndex{
+    // source file (absolute path) -> what it looked like the last time we hashed it
+    sources: BTreeMap<String, SourceEntry>,
+    // variant file name -> what it was made out of
+    variants: BTreeMap<String, VariantEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SourceEntry{
+    size: u64,
+    modified: u128,
+    hash: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct VariantEntry{
+    source_hash: String,
+    directives: String,
+    // every file we've seen with these bytes in it
+    sources: BTreeSet<String>,
+    // seconds since the epoch: the variants that haven't been served in the longest time are the first to go
+    #[serde(default)]
+    last_served: u64,
+}
+
+struct IndexFile{
+    path: PathBuf,
+    index: Mutex<VariantIndex>,
+    // saves happen one at a time, so that an older snapshot can't overwrite a newer one
+    save_lock: Mutex<()>,
+    // whether there's anything (like a last_served time) that hasn't been saved yet
+    dirty: AtomicBool,
+    // whether there's already a save on the way (see changed())
+    save_scheduled: AtomicBool,
+    // source file -> held by whoever's hashing it, so that a burst of requests for a new video reads it once
+    hashing: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+// how long a change to the index waits to be saved, so that a burst of conversions is one save
+const SAVE_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
+// temporary file names need to be unique within this process (and the pid takes care of other processes)
+static TEMPORARY_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl ImageConverter{
+    pub fn new(directory: &str, workers: usize) -> ImageConverter{
+        let directory = PathBuf::from(directory);
+        let index_path = directory.join("index.json");
+        let index = read_index(&index_path);
+        ImageConverter{
+            directory,
+            permits: Semaphore::new(workers.max(1)),
+            in_flight: Cache::new(10_000),
+            converted: AtomicU64::new(0),
+            index: Arc::new(IndexFile{
+                path: index_path,
+                index: Mutex::new(index),
+                save_lock: Mutex::new(()),
+                dirty: AtomicBool::new(false),
+                save_scheduled: AtomicBool::new(false),
+                hashing: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    ///
+    /// Save index.json now, if there's anything to save: the scheduled save won't happen if we're about to exit
+    ///
+    pub async fn flush(&self) -> Result<()>{
+        if !self.index.dirty.load(Ordering::SeqCst){
+            return Ok(());
+        }
+        let index = self.index.clone();
+        rocket::tokio::task::spawn_blocking(move || index.save()).await?
+    }
+
+    ///
+    /// The .webp version of `source` with `file_directives` applied: converting it, if nobody's asked for it before
+    ///
+    pub async fn convert(&self, source: &Path, config: &Config, file_directives: &FileDirectives) -> Result<PathBuf>{
+        let (source, index) = (source.to_path_buf(), self.index.clone());
+        let (source, source_hash) = rocket::tokio::task::spawn_blocking(move || -> Result<(PathBuf, String)> {
+            let source = source.canonicalize()?;
+            let source_hash = source_hash(&index, &source)?;
+            Ok((source, source_hash))
+        }).await??;
+        let lossless = source.extension().and_then(|extension| extension.to_str()) == Some("png");
+        let directives = file_directives.canonical();
+        // the server's settings change what comes out, too
+        let variant_key = format!("{}\n{}\n{}x{} q{} lossless={}", source_hash, directives, config.max_width, config.max_height, config.webp_quality, lossless);
+        let variant_name = format!("{}.webp", sha256_hex(variant_key.as_bytes()));
+        let variant = self.directory.join(&variant_name);
+
+        if variant.exists(){
+            println!("Using existing {}", variant.to_str().unwrap_or(""));
+        }
+        else{
+            let converted = self.in_flight.try_get_with(variant.clone(), async {
+                // whoever converted it before us might have finished while we were getting here
+                if variant.exists(){
+                    return Ok(variant.clone());
+                }
+                let _permit = self.permits.acquire().await?;
+                let converted = self.converted.fetch_add(1, Ordering::SeqCst) + 1;
+                println!("Converting {} to {} (#{})", source.to_str().unwrap_or(""), variant.to_str().unwrap_or(""), converted);
+                let (source, variant, config, file_directives) = (source.clone(), variant.clone(), config.clone(), file_directives.clone());
+                rocket::tokio::task::spawn_blocking(move || -> Result<PathBuf> {
+                    convert_image(&source, &variant, &config, &file_directives)?;
+                    Ok(variant)
+                }).await?
+            }).await;
+            // if it ever goes missing, we'll want to convert it again
+            self.in_flight.invalidate(&variant).await;
+            converted.map_err(|err| anyhow!("{}", err))?;
+        }
+
+        let source_path = source.to_str().unwrap_or("").to_string();
+        let changed = self.index.update(|index| {
+            let entry = index.variants.entry(variant_name).or_insert_with(|| VariantEntry{
+                source_hash,
+                directives,
+                sources: BTreeSet::new(),
+                last_served: 0,
+            });
+            entry.last_served = now();
+            entry.sources.insert(source_path)
+        })?;
+        // (a new last_served time on its own can wait for the next eviction round to be saved)
+        self.index.dirty.store(true, Ordering::SeqCst);
+        if changed{
+            self.index.changed();
+        }
+        Ok(variant)
+    }
+
+    ///
+    /// A short string that changes whenever what we'd serve for `source` might: its bytes, or the settings images are converted with
+    ///  (asset links carry it, so that browsers can keep a file forever: a new version of the file gets a new link)
+    ///
+    pub async fn version(&self, source: &Path, config: &Config) -> Result<String>{
+        let (versioner, source) = (self.versioner(config), source.to_path_buf());
+        rocket::tokio::task::spawn_blocking(move || versioner.version(&source)).await?
+    }
+
+    ///
+    /// version(), for code that's already on the blocking pool
+    ///
+    pub fn versioner(&self, config: &Config) -> Versioner{
+        Versioner{
+            index: self.index.clone(),
+            settings: format!("{}x{} q{}", config.max_width, config.max_height, config.webp_quality),
+        }
+    }
+}
+
+///
+/// Works out ImageConverter::version() without an executor: it reads (and sometimes hashes) files, so keep it on the blocking pool
+///
+#[derive(Clone)]
+pub struct Versioner{
+    index: Arc<IndexFile>,
+    // the settings images are converted with
+    settings: String,
+}
+
+impl Versioner{
+    pub fn version(&self, source: &Path) -> Result<String>{
+        let source_hash = source_hash(&self.index, &source.canonicalize()?)?;
+        let version_key = format!("{}\n{}", source_hash, self.settings);
+        Ok(sha256_hex(version_key.as_bytes())[..16].to_string())
+    }
+
+    ///
+    /// The sha256 of a file's contents (from the index, if it hasn't changed since we last looked)
+    ///
+    pub fn source_hash(&self, source: &Path) -> Result<String>{
+        source_hash(&self.index, &source.canonicalize()?)
+    }
+}
+
+///
+/// The sha256 of a source file: from the index, if the file hasn't changed since we last hashed it
+///
+/// (if somebody else is already hashing it, we wait for them rather than reading it again)
+///
+fn source_hash(index: &Arc<IndexFile>, source: &Path) -> Result<String>{
+    let source_path = source.to_str().unwrap_or("").to_string();
+    let known_hash = || -> Result<(Option<String>, u64, u128)>{
+        let metadata = std::fs::metadata(source)?;
+        let size = metadata.len();
+        let modified = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?.as_nanos();
+        let known = index.update(|index| index.sources.get(&source_path).cloned())?;
+        let hash = known.filter(|known| known.size == size && known.modified == modified).map(|known| known.hash);
+        Ok((hash, size, modified))
+    };
+    if let (Some(hash), _, _) = known_hash()?{
+        return Ok(hash);
+    }
+
+    let hashing = {
+        let mut hashing = index.hashing.lock().map_err(|_| anyhow!("Variant index lock poisoned"))?;
+        hashing.entry(source_path.clone()).or_default().clone()
+    };
+    let hashed = (|| -> Result<String>{
+        let _hashing = hashing.lock().map_err(|_| anyhow!("Variant index lock poisoned"))?;
+        // (whoever we were waiting for has probably just done it)
+        let (known, size, modified) = known_hash()?;
+        if let Some(hash) = known{
+            return Ok(hash);
+        }
+        let hash = sha256_file(source)?;
+        index.update(|index| {
+            index.sources.insert(source_path.clone(), SourceEntry{
+                size,
+                modified,
+                hash: hash.clone(),
+            });
+        })?;
+        index.changed();
+        Ok(hash)
+    })();
+    if let Ok(mut hashing) = index.hashing.lock(){
+        // (the last one out turns off the lights)
+        if hashing.get(&source_path).is_some_and(|held| Arc::strong_count(held) <= 2){
+            hashing.remove(&source_path);
+        }
+    }
+    hashed
+}
+
+impl ImageConverter{
+    ///
+    /// Every `interval`, delete the variants that were served least recently until they all fit in `budget` bytes
+    ///
+    pub fn spawn_eviction(&self, budget: u64, interval: std::time::Duration){
+        let (directory, index) = (self.directory.clone(), self.index.clone());
+        rocket::tokio::spawn(async move {
+            loop{
+                rocket::tokio::time::sleep(interval).await;
+                let (directory, index) = (directory.clone(), index.clone());
+                let evicted = rocket::tokio::task::spawn_blocking(move || evict(&directory, &index, budget)).await;
+                match evicted{
+                    Ok(Ok(())) => {},
+                    Ok(Err(err)) => println!("Error evicting variants: {}", err),
+                    Err(err) => println!("Error evicting variants: {}", err),
+                }
+            }
+        });
+    }
+}
+
+fn evict(directory: &Path, index: &IndexFile, budget: u64) -> Result<()>{
+    let mut variants = Vec::new();
+    let mut total_size = 0;
+    if directory.exists(){
+        for entry in std::fs::read_dir(directory)?{
+            let entry = entry?;
+            let file_name = entry.file_name().to_str().unwrap_or("").to_string();
+            if !file_name.ends_with(".webp") || file_name.starts_with('.') || !is_ours(&file_name){
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            // variants that aren't in the index (left over from older versions, say) count as last served when they were made
+            let last_served = index.update(|index| index.variants.get(&file_name).map(|variant| variant.last_served))?
+                .unwrap_or_else(|| metadata.modified().ok()
+                    .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|modified| modified.as_secs())
+                    .unwrap_or(0));
+            total_size += metadata.len();
+            variants.push((last_served, metadata.len(), file_name));
+        }
+    }
+
+    if total_size > budget{
+        variants.sort();
+        for (_, size, file_name) in variants{
+            if total_size <= budget{
+                break;
+            }
+            println!("Evicting {}", file_name);
+            std::fs::remove_file(directory.join(&file_name))?;
+            index.update(|index| index.variants.remove(&file_name))?;
+            index.dirty.store(true, Ordering::SeqCst);
+            total_size -= size;
+        }
+    }
+
+    if index.dirty.load(Ordering::SeqCst){
+        index.save()?;
+    }
+    Ok(())
+}
+
+///
+/// Delete every variant that nothing will ask for again: the ones whose source files are all gone (or different now),
+///  and anything in the directory that we made but don't know about any more (older versions' variants, temporary files)
+///
+/// the directory is whatever ROCKET_TEMPORARY_ASSET_DIRECTORY says, so files that aren't named like ours are never touched
+///
+/// returns how many files were deleted, and how many bytes that freed up
+///
+pub fn clean(directory: &str) -> Result<(usize, u64)>{
+    // a server using the same directory waits for us to finish before it saves (and then keeps what we did)
+    let _file_lock = lock_index(Path::new(directory))?;
+    let converter = ImageConverter::new(directory, 1);
+    let index = &converter.index;
+    let mut removed = 0;
+    let mut freed = 0;
+
+    let current_hash = |source: &str| -> Option<String>{
+        let metadata = std::fs::metadata(source).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?.as_nanos();
+        let known = index.update(|index| index.sources.get(source).cloned()).ok()?;
+        match known{
+            Some(known) if known.size == metadata.len() && known.modified == modified => Some(known.hash),
+            _ => sha256_file(Path::new(source)).ok(),
+        }
+    };
+    let variants: Vec<(String, VariantEntry)> = index.update(|index| index.variants.clone().into_iter().collect())?;
+    for (file_name, mut variant) in variants{
+        variant.sources.retain(|source| current_hash(source).as_deref() == Some(variant.source_hash.as_str()));
+        let variant_path = converter.directory.join(&file_name);
+        if variant.sources.is_empty() || !variant_path.exists(){
+            if let Ok(metadata) = std::fs::metadata(&variant_path){
+                println!("🗑  {} ({})", file_name, variant.directives);
+                std::fs::remove_file(&variant_path)?;
+                removed += 1;
+                freed += metadata.len();
+            }
+            index.update(|index| index.variants.remove(&file_name))?;
+        }
+        else{
+            index.update(|index| index.variants.insert(file_name, variant))?;
+        }
+    }
+    index.update(|index| index.sources.retain(|source, _| Path::new(source).exists()))?;
+
+    if converter.directory.exists(){
+        for entry in std::fs::read_dir(&converter.directory)?{
+            let entry = entry?;
+            let file_name = entry.file_name().to_str().unwrap_or("").to_string();
+            let known = file_name == "index.json" || file_name == "index.lock" || index.update(|index| index.variants.contains_key(&file_name))?;
+            if known || !is_ours(&file_name) || !entry.metadata()?.is_file() || is_recent(&entry.metadata()?){
+                continue;
+            }
+            println!("🗑  {}", file_name);
+            freed += entry.metadata()?.len();
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+
+    index.replace()?;
+    Ok((removed, freed))
+}
+
+///
+/// Whether a file was written in the last minute: a variant that a server converted but hasn't saved in index.json yet, say
+///
+fn is_recent(metadata: &std::fs::Metadata) -> bool{
+    metadata.modified().ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < std::time::Duration::from_secs(60))
+}
+
+///
+/// Whether a file is named like something we'd have written: `<sha256>.webp`, an older version's `author_deck_width100_bee.webp`,
+///  or write_atomically()'s `.<name>.<pid>.<n>.tmp`
+///
+fn is_ours(file_name: &str) -> bool{
+    let Some(stem) = file_name.strip_suffix(".webp") else {
+        return file_name.starts_with('.') && file_name.ends_with(".tmp");
+    };
+    let hashed = stem.len() == 64 && stem.chars().all(|c| c.is_ascii_hexdigit());
+    hashed || stem.contains('_')
+}
+
+fn read_index(index_path: &Path) -> VariantIndex{
+    match std::fs::read_to_string(index_path){
+        Ok(index) => rocket::serde::json::from_str(&index).unwrap_or_else(|err| {
+            println!("Starting a new {}: {}", index_path.to_str().unwrap_or(""), err);
+            VariantIndex::default()
+        }),
+        Err(_) => VariantIndex::default(),
+    }
+}
+
+///
+/// Take index.lock (waiting for whoever has it): every process that writes index.json holds it while it does
+///
+fn lock_index(directory: &Path) -> Result<std::fs::File>{
+    std::fs::create_dir_all(directory)?;
+    let lock = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(directory.join("index.lock"))?;
+    lock.lock()?;
+    Ok(lock)
+}
+
+impl VariantIndex{
+    ///
+    /// Fold in an index that somebody else saved: variants that either of us knows about are kept, as long as they're still there
+    ///
+    fn merge(&mut self, saved: VariantIndex, directory: &Path){
+        for (source, entry) in saved.sources{
+            self.sources.entry(source).or_insert(entry);
+        }
+        for (file_name, saved_variant) in saved.variants{
+            match self.variants.get_mut(&file_name){
+                Some(variant) => {
+                    variant.last_served = variant.last_served.max(saved_variant.last_served);
+                    variant.sources.extend(saved_variant.sources);
+                },
+                None => {
+                    self.variants.insert(file_name, saved_variant);
+                },
+            }
+        }
+        // (evicted or cleaned)
+        self.variants.retain(|file_name, _| directory.join(file_name).exists());
+    }
+}
+
+fn now() -> u64{
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+impl IndexFile{
+    fn update<T>(&self, update: impl FnOnce(&mut VariantIndex) -> T) -> Result<T>{
+        let mut index = self.index.lock().map_err(|_| anyhow!("Variant index lock poisoned"))?;
+        Ok(update(&mut index))
+    }
+
+    ///
+    /// Something that should be saved soon has changed: save it in a few seconds, unless that's already been arranged
+    ///
+    fn changed(self: &Arc<Self>){
+        self.dirty.store(true, Ordering::SeqCst);
+        if self.save_scheduled.swap(true, Ordering::SeqCst){
+            return;
+        }
+        let index = self.clone();
+        rocket::tokio::spawn(async move {
+            rocket::tokio::time::sleep(SAVE_DELAY).await;
+            index.save_scheduled.store(false, Ordering::SeqCst);
+            let saving = index.clone();
+            match rocket::tokio::task::spawn_blocking(move || saving.save()).await{
+                Ok(Ok(())) => {},
+                Ok(Err(err)) => println!("Error saving {}: {}", index.path.to_str().unwrap_or(""), err),
+                Err(err) => println!("Error saving {}: {}", index.path.to_str().unwrap_or(""), err),
+            }
+        });
+    }
+
+    fn save(&self) -> Result<()>{
+        let _save_lock = self.save_lock.lock().map_err(|_| anyhow!("Variant index lock poisoned"))?;
+        let directory = self.path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        let _file_lock = lock_index(&directory)?;
+        let saved = read_index(&self.path);
+        self.update(|index| index.merge(saved, &directory))?;
+        self.replace()
+    }
+
+    ///
+    /// Overwrite index.json with exactly what we have, for somebody who's been holding index.lock all along
+    ///
+    fn replace(&self) -> Result<()>{
+        self.dirty.store(false, Ordering::SeqCst);
+        let json = self.update(|index| rocket::serde::json::to_pretty_string(index))??;
+        write_atomically(&self.path, json.as_bytes())
+    }
+}
+
+fn convert_image(source: &Path, variant: &Path, config: &Config, file_directives: &FileDirectives) -> Result<()>{
+    let lossless = source.extension().and_then(|extension| extension.to_str()) == Some("png");
+    let mut img = ImageReader::open(source)?.decode()?;
+
+    let mut max_width = config.max_width;
+    let w = img.width();
+    if file_directives.wide.unwrap_or(false) {
+        max_width = w;
+    }
+    if file_directives.width.unwrap_or(0) > 0 {
+        max_width = file_directives.width.unwrap_or(0);
+    }
+    let h = img.height();
+    let mut max_height = config.max_height;
+    if file_directives.tall.unwrap_or(false) {
+        max_height = h;
+    }
+    if file_directives.height.unwrap_or(0) > 0 {
+        max_height = file_directives.height.unwrap_or(0);
+    }
+
+    if w > max_width && h > max_height {
+        if w > h {
+            img = image::DynamicImage::ImageRgba8(imageops::resize(
+                &img,
+                max_width,
+                (max_width as f64 * h as f64 / w as f64) as u32,
+                imageops::FilterType::Lanczos3,
+            ));
+        }
+        else{
+            img = image::DynamicImage::ImageRgba8(imageops::resize(
+                &img,
+                (max_height as f64 * w as f64 / h as f64) as u32,
+                max_height,
+                imageops::FilterType::Lanczos3,
+            ));
+        }
+    }
+    else if w > max_width {
+        img = image::DynamicImage::ImageRgba8(imageops::resize(
+            &img,
+            max_width,
+            (max_width as f64 * h as f64 / w as f64) as u32,
+            imageops::FilterType::Lanczos3,
+        ));
+    }
+    else if h > max_height {
+        img = image::DynamicImage::ImageRgba8(imageops::resize(
+            &img,
+            (max_height as f64 * w as f64 / h as f64) as u32,
+            max_height,
+            imageops::FilterType::Lanczos3,
+        ));
+    }
+
+    if file_directives.grayscale.unwrap_or(false) {
+        img = DynamicImage::ImageLumaA8(imageops::grayscale_alpha(&img));
+        img = DynamicImage::ImageRgba8(img.to_rgba8());
+    }
+
+    if file_directives.color.is_some() {
+        img = DynamicImage::ImageLumaA8(imageops::grayscale_alpha(&img));
+        img = DynamicImage::ImageRgba8(img.to_rgba8());
+
+        let (r, g, b) = file_directives.color().unwrap();
+
+        let existing_image = img.into_rgba8();
+        let e_width = existing_image.width();
+        let e_height = existing_image.height();
+
+        let buffer = ImageBuffer::from_fn(e_width, e_height, |x, y| {
+            let pixel = existing_image.get_pixel(x, y);
+            let mut pixel = pixel.0;
+            pixel[0] = (r as u32 * (255-pixel[0] as u32) / 255) as u8;
+            pixel[1] = (g as u32 * (255-pixel[1] as u32) / 255) as u8;
+            pixel[2] = (b as u32 * (255-pixel[2] as u32) / 255) as u8;
+            image::Rgba(pixel)
+        });
+
+        img = DynamicImage::ImageRgba8(buffer);
+    }
+
+    if file_directives.blur.unwrap_or(0.0) > 0.1 {
+        let blur_amount = file_directives.blur.unwrap_or(5.0);
+        img = DynamicImage::ImageRgba8(imageops::blur(&img, blur_amount));
+    }
+
+    if file_directives.flip_horizontal.unwrap_or(false) {
+        img = DynamicImage::ImageRgba8(imageops::flip_horizontal(&img));
+    }
+
+    if file_directives.flip_vertical.unwrap_or(false) {
+        img = DynamicImage::ImageRgba8(imageops::flip_vertical(&img));
+    }
+
+    if file_directives.flip_turnwise.unwrap_or(false) {
+        img = DynamicImage::ImageRgba8(imageops::rotate180(&img));
+    }
+
+    let enc = Encoder::from_image(&img).map_err(|err| anyhow!("Can't encode {} as webp: {}", source.to_str().unwrap_or(""), err))?;
+    if lossless{
+        write_atomically(variant, &enc.encode_lossless())
+    }
+    else{
+        write_atomically(variant, &enc.encode(config.webp_quality))
+    }
+}
+
+///
+/// Write to a temporary file next to `path`, then rename it into place
+///
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()>{
+    if let Some(parent) = path.parent(){
+        std::fs::create_dir_all(parent)?;
+    }
+    let file_name = path.file_name().and_then(|file_name| file_name.to_str()).unwrap_or("variant");
+    let temporary_path = path.with_file_name(format!(".{}.{}.{}.tmp", file_name, std::process::id(), TEMPORARY_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    if let Err(err) = std::fs::write(&temporary_path, bytes).and_then(|_| std::fs::rename(&temporary_path, path)){
+        let _ = std::fs::remove_file(&temporary_path);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("cardchapter-images-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn example(file_name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("example/example/assets").join(file_name)
+    }
+
+    fn saved_variants(directory: &Path) -> usize {
+        read_index(&directory.join("index.json")).variants.len()
+    }
+
+    #[rocket::async_test]
+    async fn saves_the_index_once_for_a_burst_of_conversions() {
+        let directory = scratch("burst");
+        let config = Config::from_env(None, None);
+        // (converted already, somewhere else: so that how long converting takes doesn't matter)
+        let elsewhere = scratch("burst-elsewhere");
+        let warm = ImageConverter::new(elsewhere.to_str().unwrap(), 2);
+        std::fs::create_dir_all(&directory).unwrap();
+        for file_name in ["bee.jpg", "owen.png", "sample.png"] {
+            let variant = warm.convert(&example(file_name), &config, &FileDirectives::default()).await.unwrap();
+            std::fs::copy(&variant, directory.join(variant.file_name().unwrap())).unwrap();
+        }
+        let converter = ImageConverter::new(directory.to_str().unwrap(), 2);
+        for file_name in ["bee.jpg", "owen.png", "sample.png"] {
+            converter.convert(&example(file_name), &config, &FileDirectives::default()).await.unwrap();
+        }
+        // (it's on its way, but it hasn't been written yet)
+        assert!(!directory.join("index.json").exists());
+        converter.flush().await.unwrap();
+        assert_eq!(saved_variants(&directory), 3);
+    }
+
+    #[rocket::async_test]
+    async fn keeps_what_another_process_saved() {
+        let directory = scratch("shared");
+        let config = Config::from_env(None, None);
+        let server = ImageConverter::new(directory.to_str().unwrap(), 1);
+        let other = ImageConverter::new(directory.to_str().unwrap(), 1);
+        server.convert(&example("bee.jpg"), &config, &FileDirectives::default()).await.unwrap();
+        other.convert(&example("owen.png"), &config, &FileDirectives::default()).await.unwrap();
+        server.flush().await.unwrap();
+        other.flush().await.unwrap();
+        assert_eq!(saved_variants(&directory), 2);
+
+        // a variant that's been deleted (by clean, say) doesn't come back
+        let (file_name, _) = read_index(&directory.join("index.json")).variants.into_iter().next().unwrap();
+        std::fs::remove_file(directory.join(&file_name)).unwrap();
+        server.index.dirty.store(true, Ordering::SeqCst);
+        server.flush().await.unwrap();
+        assert_eq!(saved_variants(&directory), 1);
+    }
+
+    #[rocket::async_test]
+    async fn clean_leaves_variants_that_arent_saved_yet() {
+        let directory = scratch("clean");
+        let config = Config::from_env(None, None);
+        let converter = ImageConverter::new(directory.to_str().unwrap(), 1);
+        let variant = converter.convert(&example("bee.jpg"), &config, &FileDirectives::default()).await.unwrap();
+        let stray = format!("{}.webp", "0".repeat(64));
+        // (anybody could point ROCKET_TEMPORARY_ASSET_DIRECTORY at a directory with other things in it)
+        let strays = [stray.as_str(), "example_example_width100_bee.webp", ".bee.webp.1234.0.tmp"];
+        let not_ours = ["content.yml", "credentials.json", "bee.webp"];
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        for file_name in strays.iter().chain(not_ours.iter()) {
+            std::fs::write(directory.join(file_name), b"").unwrap();
+            std::fs::File::options().write(true).open(directory.join(file_name)).unwrap().set_modified(old).unwrap();
+        }
+
+        let (removed, _) = clean(directory.to_str().unwrap()).unwrap();
+        assert_eq!(removed, 3);
+        assert!(variant.exists());
+        for file_name in strays {
+            assert!(!directory.join(file_name).exists(), "{}", file_name);
+        }
+        for file_name in not_ours {
+            assert!(directory.join(file_name).exists(), "{}", file_name);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn a_burst_of_requests_for_a_new_video_shares_one_hash() {
+        let directory = scratch("hash");
+        let converter = ImageConverter::new(directory.to_str().unwrap(), 1);
+        let video = example("test_mp4_video.mp4").canonicalize().unwrap();
+        let hashes: Vec<_> = (0..8).map(|_| {
+            let (index, video) = (converter.index.clone(), video.clone());
+            rocket::tokio::task::spawn_blocking(move || source_hash(&index, &video))
+        }).collect();
+        for hash in hashes {
+            assert_eq!(hash.await.unwrap().unwrap(), sha256_file(&video).unwrap());
+        }
+        assert!(converter.index.hashing.lock().unwrap().is_empty());
+        assert!(converter.index.update(|index| index.sources.contains_key(video.to_str().unwrap())).unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn two_requests_for_the_same_variant_convert_it_once() {
+        let directory = scratch("single-flight");
+        let config = Config::from_env(None, None);
+        let converter = ImageConverter::new(directory.to_str().unwrap(), 2);
+        let (bee, directives) = (example("bee.jpg"), FileDirectives::from_query("width=100"));
+        let (first, second) = rocket::tokio::join!(
+            converter.convert(&bee, &config, &directives),
+            converter.convert(&bee, &config, &directives),
+        );
+        assert_eq!(first.unwrap(), second.unwrap());
+        assert_eq!(converter.converted.load(Ordering::SeqCst), 1);
+        let temporary_files: Vec<_> = std::fs::read_dir(&directory).unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_str().unwrap().ends_with(".tmp"))
+            .collect();
+        assert!(temporary_files.is_empty(), "{:?}", temporary_files);
+    }
+}
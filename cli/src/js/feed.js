@@ -463,7 +463,7 @@
       if (userSlug == null || contentSlug == null) {
         const response = await fetch(`${this.serverUrl}/index`, {});
         this.index = await response.json();
-        return `/s/default/default`;
+        return ``;
       }
       return `/s/${userSlug}/${contentSlug}`;
     }
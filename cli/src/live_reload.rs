@@ -0,0 +1,179 @@
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use anyhow::Result;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rocket::tokio::sync::{broadcast, mpsc};
+
+use crate::content_file;
+use crate::ministry_directory::{self, MinistryDirectory};
+
+// in dev mode, we watch every deck and tell the browser to reload whenever one changes:
+//  the messages are the path that the changed deck is served from ("/" or "/s/author/deck/")
+
+pub struct LiveReload{
+    sender: broadcast::Sender<String>,
+    // dropping the watcher would stop the watching
+    _watcher: RecommendedWatcher,
+}
+
+impl LiveReload{
+    pub fn subscribe(&self) -> broadcast::Receiver<String>{
+        self.sender.subscribe()
+    }
+}
+
+///
+/// Start watching the decks under `root`: the deck in `root` itself, or (if there isn't one) every deck in an author directory
+///
+/// changes to a deck's content file, anything it includes, or anything in its assets/ send a message to everybody who's subscribed
+///  (the cache has its own watcher, so by the time the browser asks again it'll get the new version)
+///
+pub fn watch(root: &Path) -> Result<LiveReload>{
+    let (changed_sender, mut changed_receiver) = mpsc::unbounded_channel::<PathBuf>();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        match event{
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                for path in event.paths{
+                    let _ = changed_sender.send(path);
+                }
+            },
+            Ok(_) => {},
+            Err(err) => println!("Error watching files: {}", err),
+        }
+    })?;
+
+    let deck_paths = match MinistryDirectory::new(root.to_str().unwrap_or(".").to_string()).exists(){
+        true => vec![root.to_path_buf()],
+        false => ministry_directory::find_deck_paths(root)?,
+    };
+    for deck_path in &deck_paths{
+        println!("Watching {}", deck_path.to_str().unwrap_or(""));
+        watcher.watch(deck_path, RecursiveMode::Recursive)?;
+    }
+
+    // the watcher reports absolute paths
+    let root = root.canonicalize()?;
+    let (sender, _) = broadcast::channel(16);
+    let reload_sender = sender.clone();
+    rocket::tokio::spawn(async move {
+        while let Some(path) = changed_receiver.recv().await{
+            // saving a file tends to be several events in quick succession: wait for the rest of them
+            rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+            let mut changed_paths = vec![path];
+            while let Ok(path) = changed_receiver.try_recv(){
+                changed_paths.push(path);
+            }
+
+            let changed_decks: BTreeSet<PathBuf> = changed_paths.iter()
+                .filter_map(|path| changed_deck(&root, path))
+                .collect();
+            for deck in changed_decks{
+                println!("🔄 {}", deck.to_str().unwrap_or("."));
+                // nobody listening is fine
+                let _ = reload_sender.send(deck_url(&deck));
+            }
+        }
+    });
+
+    Ok(LiveReload{
+        sender,
+        _watcher: watcher,
+    })
+}
+
+///
+/// The deck (as "." or "author/deck", relative to `root`) that a change to `path` matters to, if it matters to any deck
+///
+fn changed_deck(root: &Path, path: &Path) -> Option<PathBuf>{
+    let path = normalize(path);
+    // .git, in-progress uploads, old versions of published decks...
+    let hidden = path.strip_prefix(root).ok()?.components().any(|component| match component{
+        Component::Normal(name) => name.to_str().is_some_and(|name| name.starts_with('.')),
+        _ => false,
+    });
+    if hidden{
+        return None;
+    }
+
+    let deck_path = path.ancestors().skip(1)
+        .take_while(|ancestor| ancestor.starts_with(root))
+        .find(|ancestor| MinistryDirectory::new(ancestor.to_str().unwrap_or(".").to_string()).exists())?;
+    let relative_path = path.strip_prefix(deck_path).unwrap_or(&path);
+    let deck = match deck_path.strip_prefix(root){
+        Ok(deck) if !deck.as_os_str().is_empty() => deck.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    if relative_path.starts_with("assets") || content_file::CONTENT_FILE_NAMES.iter().any(|file_name| relative_path == Path::new(file_name)){
+        return Some(deck);
+    }
+    let directory = MinistryDirectory::new(deck_path.to_str().unwrap_or(".").to_string());
+    match directory.get_source_files(){
+        Ok(source_files) => match source_files.iter().any(|source_file| normalize(source_file) == path){
+            true => Some(deck),
+            false => None,
+        },
+        // the deck is broken: maybe this is the file that fixes it
+        Err(_) => match relative_path.extension().and_then(|extension| extension.to_str()){
+            Some("yml") | Some("yaml") | Some("md") => Some(deck),
+            _ => None,
+        },
+    }
+}
+
+///
+/// "./author/deck/content.yml" -> "author/deck/content.yml"
+///
+fn normalize(path: &Path) -> PathBuf{
+    path.components().filter(|component| *component != Component::CurDir).collect()
+}
+
+fn deck_url(deck: &Path) -> String{
+    match deck == Path::new("."){
+        true => "/".to_string(),
+        false => format!("/s/{}/", deck.to_str().unwrap_or("")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// A deck in `deck_path` that includes chapter.yml (the watcher hands us canonical paths, so this is one too)
+    ///
+    fn root_with_deck(name: &str, deck_path: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cardchapter-reload-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let deck = root.join(deck_path);
+        std::fs::create_dir_all(deck.join("assets").join("florb")).unwrap();
+        std::fs::write(deck.join("content.yml"), "name: Bees\nauthor: Alice\npages:\n  - include: chapter.yml\n").unwrap();
+        std::fs::write(deck.join("chapter.yml"), "- title: Hello\n").unwrap();
+        std::fs::write(deck.join("notes.txt"), "not part of the deck").unwrap();
+        root.canonicalize().unwrap()
+    }
+
+    fn changed_url(root: &Path, path: &str) -> Option<String> {
+        changed_deck(root, &root.join(path)).map(|deck| deck_url(&deck))
+    }
+
+    #[test]
+    fn reloads_the_deck_at_the_top() {
+        let root = root_with_deck("single", ".");
+        assert_eq!(changed_url(&root, "content.yml").as_deref(), Some("/"));
+        assert_eq!(changed_url(&root, "chapter.yml").as_deref(), Some("/"));
+        assert_eq!(changed_url(&root, "assets/florb/Florb_00002.png").as_deref(), Some("/"));
+        assert_eq!(changed_url(&root, "notes.txt"), None);
+    }
+
+    #[test]
+    fn reloads_decks_in_author_directories() {
+        let root = root_with_deck("multi", "alice/bees");
+        assert_eq!(changed_url(&root, "alice/bees/content.yml").as_deref(), Some("/s/alice/bees/"));
+        assert_eq!(changed_url(&root, "alice/bees/chapter.yml").as_deref(), Some("/s/alice/bees/"));
+        assert_eq!(changed_url(&root, "alice/bees/assets/florb/Florb_00002.png").as_deref(), Some("/s/alice/bees/"));
+        assert_eq!(changed_url(&root, "alice/bees/notes.txt"), None);
+        assert_eq!(changed_url(&root, "alice/.bees.1700000000/content.yml"), None);
+    }
+}
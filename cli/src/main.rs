@@ -2,34 +2,65 @@
 extern crate rocket;
 
 use std::env;
-use std::path::Path;
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
 use url::Url;
 use anyhow::Result;
 
 use ministry_directory::MinistryDirectory;
 use rocket::{Build, Rocket};
-use rocket::response::content;
+use rocket::response::{content, Redirect};
+use rocket::http::uri::Origin;
 use rocket::fs::FileServer;
 use rocket::http::Status;
 use rocket::State;
 use rocket::serde::json::Json;
+use rocket::Data;
+use rocket::Shutdown;
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
 use std::net::IpAddr;
 use indoc::indoc; // this is a macro that allows us to write multi-line strings in a more readable way
 use serde::Serialize;
 use slugify::slugify;
 use qrcode::QrCode;
 use qrcode::render::svg;
+use askama::Template;
 
+use http_cache::CachedJson;
 use ministry_directory::{DeckMetadata, DeckSummary};
 use ministry_directory_cache::MinistryDirectoryCache;
 
 mod ministry_directory;
+mod content_file;
 mod ministry_directory_cache;
 mod file_modifiers;
+mod asset_references;
+mod static_site;
+mod publish;
+mod auth;
+mod credentials;
+mod deck_diff;
+mod deck_check;
+mod markdown_import;
+mod live_reload;
+mod image_conversion;
+mod optimize;
+mod asset_response;
+mod http_cache;
+mod compression;
+mod templates;
+mod security;
+mod content_root;
 
 const APP_JS: &str = include_str!("js/feed.js");
 const APP_CSS: &str = include_str!("js/style.css");
+// compressed by build.rs
+const APP_JS_GZIP: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/feed.js.gz"));
+const APP_JS_BROTLI: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/feed.js.br"));
+const APP_CSS_GZIP: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/style.css.gz"));
+const APP_CSS_BROTLI: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/style.css.br"));
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 ///
@@ -40,7 +71,7 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 fn init(flags: Flags){
     let directory_root = ".";
     let directory = ministry_directory::MinistryDirectory::new(directory_root.to_string());
-    directory.init(flags.force).expect("Failed to initialize directory.");
+    directory.init(flags.force, flags.markdown).expect("Failed to initialize directory.");
 }
 
 fn new(flags: Flags){
@@ -59,8 +90,247 @@ fn new(flags: Flags){
     let directory_root = std::path::PathBuf::from(author_slug.clone()).join(deck_slug.clone());
     std::fs::create_dir_all(directory_root.clone()).expect("Failed to create directory.");
 
-    let directory = ministry_directory::MinistryDirectory::new(directory_root.to_str().unwrap_or_else(|| ".").to_string());
-    directory.init_with_name(flags.force, title, author).expect("Failed to initialize directory.");
+    let directory = ministry_directory::MinistryDirectory::new(directory_root.to_str().unwrap_or(".").to_string());
+    directory.init_with_name(flags.force, flags.markdown, title, author).expect("Failed to initialize directory.");
+}
+
+///
+/// Turn a Markdown file into a deck in the current directory
+///
+/// if the directory already contains a deck, this will fail unless --force is passed
+///
+fn import_markdown(flags: Flags){
+    let Some(markdown_path) = flags.arguments.first() else {
+        println!("Which file? (import-markdown <file.md>)");
+        std::process::exit(1);
+    };
+    let directory = ministry_directory::MinistryDirectory::new(".".to_string());
+    // (a content.yml without an assets/ directory yet is still somebody's deck)
+    if directory.get_content_path().exists(){
+        println!("This directory already contains a deck!");
+        if !flags.force{
+            println!("Use --force to replace it.");
+            std::process::exit(1);
+        }
+        println!("Forcing re-import...");
+        // the new content.yml would win anyway, but a stale content.md next to it is just confusing
+        if Path::new("content.md").exists(){
+            std::fs::remove_file("content.md").expect("Failed to remove content.md.");
+        }
+    }
+
+    let author = ask("What's your name?");
+    let card_count = markdown_import::import(Path::new(markdown_path), Path::new("."), &author).expect("Failed to import markdown.");
+    println!("✅ content.yml ({} cards)", card_count);
+}
+
+///
+/// Export every deck under the current directory as a static site
+///
+/// the output goes to ./build unless --output is passed
+///
+async fn build(flags: Flags, config: Config){
+    let output = flags.output.unwrap_or("build".to_string());
+    let services = Services::new(&config);
+    static_site::build(&services, &config, Path::new(&output)).await.expect("Failed to build static site.");
+}
+
+fn ask(question: &str) -> String{
+    println!("{}", question);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).expect("Failed to read line.");
+    answer.trim().to_string()
+}
+
+///
+/// ask(), without showing what's typed
+///
+fn ask_password(question: &str) -> String{
+    rpassword::prompt_password(format!("{}\n", question)).expect("Failed to read password.")
+}
+
+///
+/// The server the deck in the current directory publishes to:
+///  the first argument, or whatever's in .ministry, or CARDCHAPTER_SERVER
+///
+fn publish_server(flags: &Flags) -> Url{
+    let directory = ministry_directory::MinistryDirectory::new(".".to_string());
+    match flags.arguments.first(){
+        Some(server_url) => Url::parse(server_url).expect("Invalid server URL."),
+        None => directory.get_publish_server()
+            .or_else(|| std::env::var("CARDCHAPTER_SERVER").ok().and_then(|server_url| Url::parse(&server_url).ok()))
+            .expect("Where to? Usage: cardchapter publish <server-url>"),
+    }
+}
+
+///
+/// The token for a server: CARDCHAPTER_TOKEN if it's set (handy for CI), otherwise whatever `login` saved
+///
+fn publish_token(server_url: &Url) -> String{
+    if let Ok(token) = std::env::var("CARDCHAPTER_TOKEN"){
+        return token;
+    }
+    let credentials = credentials::Credentials::load().expect("Failed to read credentials.");
+    match credentials.get(server_url){
+        Some(server_credentials) => server_credentials.token.clone(),
+        None => {
+            println!("You aren't logged in to {}: try `cardchapter login {}`", server_url, server_url);
+            std::process::exit(1);
+        }
+    }
+}
+
+///
+/// Upload the deck in the current directory to a remote CardChapter server
+///
+fn publish(flags: Flags){
+    let server_url = publish_server(&flags);
+    let token = publish_token(&server_url);
+    match publish::publish(Path::new("."), &server_url, &token){
+        Ok(metadata) => {
+            let directory = ministry_directory::MinistryDirectory::new(".".to_string());
+            directory.set_publish_server(&server_url).expect("Failed to write .ministry.");
+            println!("✅ {}s/{}/{}", server_url, metadata.author_slug, metadata.slug)
+        },
+        Err(err) => {
+            println!("Failed to publish: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+///
+/// Show what `publish` would change: compare the deck in the current directory with the published version
+///
+fn diff(flags: Flags){
+    let server_url = publish_server(&flags);
+    match deck_diff::diff(Path::new("."), &server_url){
+        Ok(deck_diff) => deck_diff.print(),
+        Err(err) => {
+            println!("Failed to diff: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+///
+/// The JSON Schema for content.yml, on stdout or in a file
+///
+fn print_schema(flags: Flags){
+    let schema = rocket::serde::json::to_pretty_string(&content_file::schema()).expect("Failed to serialize schema.");
+    match flags.output{
+        Some(output) => {
+            std::fs::write(&output, schema + "\n").expect("Failed to write schema.");
+            println!("✅ {}", output);
+        },
+        None => println!("{}", schema),
+    }
+}
+
+///
+/// Convert every image the decks under the current directory link to, so that nobody has to wait for it later
+///
+async fn optimize(config: Config){
+    let services = Services::new(&config);
+    match optimize::optimize(&services, &config).await{
+        Ok((original, optimized)) => println!("✅ {:.1} MB of images, {:.1} MB converted", original as f64 / (1024.0 * 1024.0), optimized as f64 / (1024.0 * 1024.0)),
+        Err(err) => {
+            println!("Failed to optimize: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+///
+/// Delete the converted images that nothing will ever ask for again (their source files are gone or have changed)
+///
+fn clean(config: Config){
+    match image_conversion::clean(&config.temporary_asset_directory){
+        Ok((removed, freed)) => println!("✅ Removed {} file(s), {:.1} MB", removed, freed as f64 / (1024.0 * 1024.0)),
+        Err(err) => {
+            println!("Failed to clean {}: {}", config.temporary_asset_directory, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+///
+/// Report everything that's wrong with the decks under a directory (default: the current one):
+///  exits with 1 if there are any errors, so that it can be used as a hook
+///
+fn check(flags: Flags){
+    let root = flags.arguments.first().cloned().unwrap_or(".".to_string());
+    let config = Config::from_env(Some(&root), None);
+    let problems = deck_check::check_root(Path::new(&root), &config.temporary_asset_directory);
+    let errors = problems.iter().filter(|problem| problem.severity == deck_check::Severity::Error).count();
+    let warnings = problems.len() - errors;
+
+    if flags.format.as_deref() == Some("json"){
+        let report = rocket::serde::json::json!({
+            "errors": errors,
+            "warnings": warnings,
+            "problems": problems,
+        });
+        println!("{}", rocket::serde::json::to_pretty_string(&report).expect("Failed to serialize problems."));
+    }
+    else{
+        for problem in &problems{
+            let severity = match problem.severity{
+                deck_check::Severity::Error => "error",
+                deck_check::Severity::Warning => "warning",
+            };
+            let location = match problem.location.is_empty(){
+                true => problem.deck.clone(),
+                false => format!("{} {}", problem.deck, problem.location),
+            };
+            println!("{}: {}: {}", severity, location, problem.message);
+        }
+        println!("{} error(s), {} warning(s)", errors, warnings);
+    }
+    if errors > 0 {
+        std::process::exit(1);
+    }
+}
+
+///
+/// Trade a username & password for a token, which we keep in the user's credentials file
+///
+/// if we're in a deck directory, the deck will publish to this server from now on
+///
+fn login(flags: Flags){
+    let server_url = flags.arguments.first().expect("Usage: cardchapter login <server-url>");
+    let server_url = Url::parse(server_url).expect("Invalid server URL.");
+    let author_slug = slugify!(&ask("Author:"));
+    let password = ask_password("Password:");
+
+    let server_credentials = match credentials::login(&server_url, &author_slug, &password){
+        Ok(server_credentials) => server_credentials,
+        Err(err) => {
+            println!("Failed to log in: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut credentials = credentials::Credentials::load().expect("Failed to read credentials.");
+    credentials.set(&server_url, server_credentials);
+    credentials.save().expect("Failed to save credentials.");
+    println!("✅ {}", credentials::Credentials::path().to_str().unwrap_or(""));
+
+    let directory = ministry_directory::MinistryDirectory::new(".".to_string());
+    if directory.exists(){
+        directory.set_publish_server(&server_url).expect("Failed to write .ministry.");
+        println!("✅ .ministry");
+    }
+}
+
+///
+/// Let an author publish to the server in the current directory (or change their password)
+///
+fn add_author(config: Config){
+    let author_slug = slugify!(&ask("Author:"));
+    let password = ask_password("Password:");
+    let tokens = auth::TokenStore::load(&config.token_store).expect("Failed to read token store.");
+    tokens.set_password(&author_slug, &password).expect("Failed to save token store.");
+    println!("✅ {} can log in to this server", author_slug);
 }
 
 fn status(_flags: Flags){
@@ -71,16 +341,55 @@ fn status(_flags: Flags){
 }
 
 #[get("/js/<_version>/feed.js")]
-async fn js_app(_version: String) -> content::RawJavaScript<&'static str> {
+async fn js_app(_version: String) -> compression::Precompressed {
     // we include the version, even though it's not used, so that when the browser caches the file
     //  a new version will be requested when the version changes
-    content::RawJavaScript(APP_JS)
+    compression::Precompressed{
+        content_type: rocket::http::ContentType::JavaScript,
+        identity: APP_JS.as_bytes(),
+        gzip: APP_JS_GZIP,
+        brotli: APP_JS_BROTLI,
+    }
 }
 #[get("/js/<_version>/style.css")]
-async fn js_css(_version: String) -> content::RawCss<&'static str> {
+async fn js_css(_version: String) -> compression::Precompressed {
     // we include the version, even though it's not used, so that when the browser caches the file
     //  a new version will be requested when the version changes
-    content::RawCss(APP_CSS)
+    compression::Precompressed{
+        content_type: rocket::http::ContentType::CSS,
+        identity: APP_CSS.as_bytes(),
+        gzip: APP_CSS_GZIP,
+        brotli: APP_CSS_BROTLI,
+    }
+}
+
+///
+/// The JSON Schema for content.yml, for editors: `# yaml-language-server: $schema=https://<server>/schema.json`
+///
+#[get("/schema.json")]
+fn schema() -> Json<schemars::schema::RootSchema> {
+    Json(content_file::schema())
+}
+
+///
+/// Server-sent events for live reload (dev mode only): the path of every deck that changes, as it changes
+///
+#[get("/live-reload")]
+fn live_reload_events(live_reload: &State<live_reload::LiveReload>, mut shutdown: Shutdown) -> EventStream![] {
+    let mut receiver = live_reload.subscribe();
+    EventStream! {
+        loop {
+            let deck_url = select! {
+                message = receiver.recv() => match message {
+                    Ok(deck_url) => deck_url,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::data(deck_url);
+        }
+    }
 }
 
 #[get("/robots.txt")]
@@ -110,24 +419,83 @@ fn robots() -> &'static str {
 #[derive(Clone)]
 pub struct Flags{
     force: bool,
+    markdown: bool,
+    warm: bool,
+    output: Option<String>,
+    format: Option<String>,
+    root: Option<String>,
+    mode: Option<String>,
+    arguments: Vec<String>,
 }
 
 impl Flags{
-    fn empty() -> Flags{
-        Flags{
-            force: false,
-        }
-    }
-
     fn from_args(args: Vec<String>) -> Flags{
         let mut force = false;
-        for arg in args{
+        let mut markdown = false;
+        let mut warm = false;
+        let mut output = None;
+        let mut format = None;
+        let mut root = None;
+        let mut mode = None;
+        // arguments are everything after the command that isn't a flag: `cardchapter publish <server-url>`
+        let mut arguments = Vec::new();
+        let mut skip_next = false;
+        for (i, arg) in args.iter().enumerate(){
             if arg == "force" || arg == "--force" || arg == "-f" || std::env::var("ROCKET_FORCE").unwrap_or("false".to_string()) == "true"{
                 force = true;
             }
+            else if arg == "--markdown" || arg == "--md"{
+                markdown = true;
+            }
+            else if arg == "--warm"{
+                warm = true;
+            }
+            else if arg == "--output" || arg == "-o"{
+                output = args.get(i + 1).cloned();
+                skip_next = true;
+                continue;
+            }
+            else if let Some(value) = arg.strip_prefix("--output="){
+                output = Some(value.to_string());
+            }
+            else if arg == "--format"{
+                format = args.get(i + 1).cloned();
+                skip_next = true;
+                continue;
+            }
+            else if let Some(value) = arg.strip_prefix("--format="){
+                format = Some(value.to_string());
+            }
+            else if arg == "--root"{
+                root = args.get(i + 1).cloned();
+                skip_next = true;
+                continue;
+            }
+            else if let Some(value) = arg.strip_prefix("--root="){
+                root = Some(value.to_string());
+            }
+            else if arg == "--mode"{
+                mode = args.get(i + 1).cloned();
+                skip_next = true;
+                continue;
+            }
+            else if let Some(value) = arg.strip_prefix("--mode="){
+                mode = Some(value.to_string());
+            }
+            else if i > 1 && !skip_next && !arg.starts_with('-'){
+                arguments.push(arg.clone());
+            }
+            skip_next = false;
         }
         Flags{
             force,
+            markdown,
+            warm,
+            output,
+            format,
+            root,
+            mode,
+            arguments,
         }
     }
 }
@@ -138,45 +506,101 @@ pub struct Config{
     server_url: Url,
     site_name: String,
     default_locale: String,
+    // where the decks are: everything else (temp_assets, the token store) defaults to being in here too
+    root: PathBuf,
+    // "single" or "multi": if it's not set, Services works it out from what's in the root
+    mode: Option<content_root::Mode>,
     temporary_asset_directory: String,
     max_height: u32,
     max_width: u32,
     webp_quality: f32,
+    token_store: String,
+    max_upload_size: u64,
+    // how big an upload can get once it's unpacked
+    max_unpacked_size: u64,
+    image_workers: usize,
+    asset_cache_size: u64,
+    extra_header: security::ExtraHeaderPolicy,
 }
 
 impl Config{
-    fn from_env() -> Config{
+    ///
+    /// `root` and `mode` are --root and --mode, if they were passed: they win over ROCKET_ROOT and ROCKET_MODE
+    ///
+    fn from_env(root: Option<&str>, mode: Option<&str>) -> Config{
         let dev = std::env::var("ROCKET_ENV").unwrap_or("production".to_string()) == "development";
         let server_url = std::env::var("ROCKET_SERVER_URL").unwrap_or("http://localhost:8000".to_string());
         let site_name = std::env::var("ROCKET_SITE_NAME").unwrap_or("CardChapter".to_string());
         let default_locale = std::env::var("ROCKET_DEFAULT_LOCALE").unwrap_or("en_US".to_string());
-        let temporary_asset_directory = std::env::var("ROCKET_TEMPORARY_ASSET_DIRECTORY").unwrap_or("./temp_assets".to_string());
+        let root = PathBuf::from(root.map(str::to_string).unwrap_or(std::env::var("ROCKET_ROOT").unwrap_or(".".to_string())));
+        let mode = mode.map(str::to_string).or(std::env::var("ROCKET_MODE").ok()).and_then(|mode| {
+            let parsed = content_root::Mode::from_name(&mode);
+            if parsed.is_none(){
+                println!("Unknown ROCKET_MODE {}: it should be \"single\" or \"multi\"", mode);
+            }
+            parsed
+        });
+        let temporary_asset_directory = std::env::var("ROCKET_TEMPORARY_ASSET_DIRECTORY")
+            .unwrap_or(root.join("temp_assets").to_str().unwrap_or("./temp_assets").to_string());
+        let token_store = std::env::var("ROCKET_TOKEN_STORE")
+            .unwrap_or(root.join(".cardchapter_tokens.json").to_str().unwrap_or("./.cardchapter_tokens.json").to_string());
+        let max_upload_size = std::env::var("ROCKET_MAX_UPLOAD_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(256 * 1024 * 1024);
+        let max_unpacked_size = std::env::var("ROCKET_MAX_UNPACKED_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(1024 * 1024 * 1024);
+        // how many images we'll convert at once: by default, one per core
+        let image_workers = std::env::var("ROCKET_IMAGE_WORKERS").ok().and_then(|workers| workers.parse().ok())
+            .unwrap_or_else(|| std::thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1));
+        // how much disk converted images can take up (in bytes) before we start deleting the ones nobody's looked at lately
+        let asset_cache_size = std::env::var("ROCKET_ASSET_CACHE_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(1024 * 1024 * 1024);
+        // what we do with a deck's extra_header: "trusted", "sanitized" or "rejected"
+        let extra_header = std::env::var("ROCKET_EXTRA_HEADER").unwrap_or("trusted".to_string());
+        let extra_header = security::ExtraHeaderPolicy::from_name(&extra_header).unwrap_or_else(|| {
+            println!("Unknown ROCKET_EXTRA_HEADER {}: using \"sanitized\"", extra_header);
+            security::ExtraHeaderPolicy::Sanitized
+        });
         Config{
             dev,
             server_url: Url::parse(&server_url).unwrap(),
             site_name,
             default_locale,
+            root,
+            mode,
             temporary_asset_directory,
             max_height: 800,
             max_width: 660,
             webp_quality: 30f32,
+            token_store,
+            max_upload_size,
+            max_unpacked_size,
+            image_workers,
+            asset_cache_size,
+            extra_header,
         }
     }
 }
 
 pub struct Services{
     pub cache: MinistryDirectoryCache,
+    pub tokens: auth::TokenStore,
+    pub images: image_conversion::ImageConverter,
+    pub root: content_root::ContentRoot,
 }
 
 impl Services{
-    pub fn new() -> Services{
+    pub fn new(config: &Config) -> Services{
         Services{
-            cache: MinistryDirectoryCache::new(),
+            cache: MinistryDirectoryCache::new(&config.root),
+            tokens: auth::TokenStore::load(&config.token_store).expect("Failed to read token store."),
+            images: image_conversion::ImageConverter::new(&config.temporary_asset_directory, config.image_workers),
+            root: content_root::ContentRoot::new(&config.root, &config.temporary_asset_directory, config.mode.unwrap_or_else(|| content_root::Mode::detect(&config.root)))
+                .expect("Failed to find content root."),
         }
     }
 }
 
-fn index_template(deck_metadata: DeckMetadata, config: &State<Config>, is_home: bool) -> Result<String> {
+///
+/// The page for a deck: `nonce` is the Content-Security-Policy nonce for its scripts (if it's being served with one)
+///
+fn index_template(deck_metadata: DeckMetadata, config: &Config, is_home: bool, nonce: Option<&str>) -> Result<String> {
     let title = deck_metadata.title;
     let author = deck_metadata.author;
     let description = match deck_metadata.description {
@@ -202,117 +626,132 @@ fn index_template(deck_metadata: DeckMetadata, config: &State<Config>, is_home:
         Some(locale) => locale,
         None => config.default_locale.clone(),
     };
-    let extra_header = deck_metadata.extra_header.clone().unwrap_or("".to_string());
+    let extra_header = config.extra_header.apply(deck_metadata.extra_header.as_deref().unwrap_or(""), nonce);
 
     let js_location = match config.dev {
         true => "/js/feed.js",
         false => &format!("/js/{}/feed.js", VERSION),
     };
 
-    let css_location = match config.dev {
-        true => "/js/style.css",
-        false => &format!("/js/{}/style.css", VERSION),
-    };
+    let css_location = css_location(config);
 
     if deck_metadata.hidden {
-        return Ok(error_template("This deck is hidden."));
-    }
-
-    return Ok(format!(indoc!(r#"
-    <!DOCTYPE html>
-    <html>
-        <head>
-            <!-- CardChapter Ministry Version: {} -->
-            <link rel="stylesheet" href="{}">
-            <link rel="icon" type="image/png" href="{}" sizes="any"/>
-            <meta charset="UTF-8">
-            <title>{}</title>
-            <meta name="viewport" content="width=device-width">
-            <meta property="og:title" content="{}" />
-            <meta property="og:description" content="{}" />
-            <meta property="article:author" content="{}" />
-            <meta property="og:url" content="{}" />
-            <meta property="og:site_name" content="{}" />
-            <meta property="og:locale" content="{}" />
-            <meta property="og:image" content="{}" />
-            {}
-        </head>
-        <body>
-            <div id="app">
+        return Ok(error_template("This deck is hidden.", config, nonce));
+    }
 
-                <div class="primary-card">
-                    <div class="content">
-                        <header id="primary-header">
-                        </header>
-                        <div class="everything-feed">
-                            <div class="frames">
-                                <div class="loader-wrapper">
-                                    <div class="loader">
-                                    </div>
-                                </div>
-                            </div>
-                        </div>
-                    </div>
-                </div>
-            </div>
-            <script src="{}"></script>
-        </body>
-    </html>
-    "#), VERSION, css_location, favicon, title, title, description, author, url, site_name, locale, image, extra_header, js_location));
-}
-
-fn error_template(message: &str) -> String {
-    return format!(indoc!(r#"
-    <!DOCTYPE html>
-    <html>
-        <head>
-            <meta charset="UTF-8">
-            <meta name="viewport" content="width=device-width">
-            <title>Error</title>
-        </head>
-        <body>
-            <h1>Error</h1>
-            <p>{}</p>
-        </body>
-    </html>
-    "#), message);
+    let page = templates::DeckPage{
+        version: VERSION,
+        css_location: &css_location,
+        js_location,
+        favicon: &favicon,
+        title: &title,
+        description: &description,
+        author: &author,
+        url: &url,
+        site_name: &site_name,
+        locale: &locale,
+        image: &image,
+        extra_header: &extra_header,
+        nonce,
+        live_reload: config.dev,
+    };
+    Ok(page.render()?)
+}
+
+fn css_location(config: &Config) -> String {
+    match config.dev {
+        true => "/js/style.css".to_string(),
+        false => format!("/js/{}/style.css", VERSION),
+    }
+}
+
+///
+/// `/` on a multi-deck server that doesn't have a deck of its own: every deck in the sitemap, by author
+///
+fn catalog_template(sitemap: HashMap<String, Vec<DeckSummary>>, config: &Config, nonce: Option<&str>) -> String {
+    let mut authors: Vec<templates::CatalogAuthor> = sitemap.into_iter().map(|(author_slug, mut decks)| {
+        decks.sort_by(|a, b| a.title.cmp(&b.title));
+        templates::CatalogAuthor{
+            author: decks.first().map(|deck| deck.author.clone()).unwrap_or(author_slug.clone()),
+            author_slug,
+            decks,
+        }
+    }).collect();
+    authors.sort_by(|a, b| a.author.cmp(&b.author));
+
+    let css_location = css_location(config);
+    let page = templates::CatalogPage{
+        css_location: &css_location,
+        site_name: &config.site_name,
+        authors: &authors,
+        nonce,
+        live_reload: config.dev,
+    };
+    page.render().unwrap_or_else(|err| format!("Error: {}", err))
+}
+
+fn error_template(message: &str, config: &Config, nonce: Option<&str>) -> String {
+    let page = templates::ErrorPage{
+        message,
+        nonce,
+        live_reload: config.dev,
+    };
+    page.render().unwrap_or_else(|err| format!("Error: {}", err))
 }
 
 #[get("/")]
-async fn home(config: &State<Config>, services: &State<Services>) -> content::RawHtml<String> {
-    let directory_root = ".";
-    let metadata = services.cache.get_metadata(directory_root).await;
+async fn home(config: &State<Config>, services: &State<Services>, nonce: security::CspNonce) -> content::RawHtml<String> {
+    if services.root.mode() == content_root::Mode::Multi && !services.root.has_home(){
+        return match get_sitemap(services).await{
+            Ok(sitemap) => content::RawHtml(catalog_template(sitemap, config, Some(&nonce.0))),
+            Err(e) => content::RawHtml(error_template(&e.to_string(), config, Some(&nonce.0))),
+        };
+    }
+    let metadata = services.cache.get_metadata(&services.root.home()).await;
 
     match metadata{
         Ok(deck_metadata) => {
-            let rendered = index_template(deck_metadata, config, true);
+            let rendered = index_template(deck_metadata, config, true, Some(&nonce.0));
 
             match rendered{
                 Ok(html) => content::RawHtml(html),
-                Err(e) => content::RawHtml(error_template(&e.to_string())),
+                Err(e) => content::RawHtml(error_template(&e.to_string(), config, Some(&nonce.0))),
             }
         },
-        Err(e) => content::RawHtml(error_template(&e.to_string())),
+        Err(e) => content::RawHtml(error_template(&e.to_string(), config, Some(&nonce.0))),
     }
 }
 
 #[get("/s/<author_slug>/<deck_slug>")]
-async fn deck_home(config: &State<Config>, services: &State<Services>, author_slug: &str, deck_slug: &str) -> content::RawHtml<String> {
-    let path = std::path::PathBuf::from(author_slug).join(deck_slug);
-    let metadata = services.cache.get_metadata(path.to_str().unwrap_or_else(|| ".")).await;
+async fn deck_home(config: &State<Config>, services: &State<Services>, nonce: security::CspNonce, author_slug: &str, deck_slug: &str) -> Result<content::RawHtml<String>, Status> {
+    let Ok(directory_path) = services.root.deck(author_slug, deck_slug) else {
+        return Err(Status::NotFound);
+    };
+    let metadata = services.cache.get_metadata(&directory_path).await;
     match metadata{
         Ok(deck_metadata) => {
-            let rendered = index_template(deck_metadata, config, false);
+            let rendered = index_template(deck_metadata, config, false, Some(&nonce.0));
 
             match rendered{
-                Ok(html) => content::RawHtml(html),
-                Err(e) => content::RawHtml(error_template(&e.to_string())),
+                Ok(html) => Ok(content::RawHtml(html)),
+                Err(e) => Ok(content::RawHtml(error_template(&e.to_string(), config, Some(&nonce.0)))),
             }
         },
-        Err(e) => content::RawHtml(error_template(&e.to_string())),
+        Err(e) => Ok(content::RawHtml(error_template(&e.to_string(), config, Some(&nonce.0)))),
     }
 }
 
+///
+/// A single-deck server doesn't have any `/s/...`: links to one go to the same thing on the deck at `/`
+///
+#[get("/s/<_author_slug>/<_deck_slug>/<_path..>")]
+fn single_deck_redirect(uri: &Origin<'_>, _author_slug: &str, _deck_slug: &str, _path: PathBuf) -> Redirect {
+    // `/s/<author_slug>/<deck_slug>/assets/bee.jpg?width=100` -> `/assets/bee.jpg?width=100` (still encoded, just as it came in)
+    let path = uri.path().as_str().splitn(5, '/').nth(4).unwrap_or("");
+    let query = uri.query().map(|query| format!("?{}", query.as_str())).unwrap_or_default();
+    Redirect::to(format!("/{}{}", path, query))
+}
+
 #[derive(Serialize)]
 pub struct Index{
     id: String,
@@ -322,36 +761,107 @@ pub struct Index{
     version: String,
 }
 
-async fn get_index(services: &State<Services>, directory_path: &str) -> Result<Index> {
+async fn get_index(services: &Services, config: &Config, directory_path: &str) -> Result<Index> {
     let metadata = services.cache.get_metadata(directory_path).await?;
     let deck = services.cache.get_deck(directory_path).await?;
     if metadata.hidden {
         return Err(anyhow::anyhow!("This deck is hidden."));
     }
-    Ok(Index{
+    let links = versioned_links(services, config, directory_path).await;
+    let metadata = asset_references::rewrite_metadata(metadata, &|link: &str| {
+        asset_references::rewrite_text(link, |reference| links.get(&reference.reference).cloned())
+    });
+    Ok(build_index(metadata, deck))
+}
+
+///
+/// Point asset links at a particular version of their file: `assets/bee.jpg?width=100` -> `assets/bee.jpg?width=100&v=0123456789abcdef`
+///
+/// a link like that will always get exactly the same bytes, so browsers can keep them for as long as they like:
+///  when the file changes, so does the link
+///
+/// every link in the deck is worked out at once (on the blocking pool) and cached with the deck, until it or one of its files changes
+///
+async fn versioned_links(services: &Services, config: &Config, directory_path: &str) -> ministry_directory_cache::AssetVersions {
+    let (root, versioner) = (services.root.clone(), services.images.versioner(config));
+    let links = services.cache.get_versions(directory_path, move |directory| {
+        let mut references = asset_references::find_in_deck(&directory.get_metadata()?, &directory.get_deck()?);
+        references.sort();
+        references.dedup();
+        let mut links = HashMap::new();
+        let mut sources = Vec::new();
+        for reference in references{
+            // a missing file keeps its link: it'll 404 either way
+            let Ok(source) = root.find_asset(directory, &reference.asset_path) else {
+                continue;
+            };
+            match versioner.version(&source){
+                Ok(version) => {
+                    let separator = match reference.query.is_empty(){
+                        true => "?",
+                        false => "&",
+                    };
+                    links.insert(reference.reference.clone(), format!("{}{}v={}", reference.reference, separator, version));
+                },
+                Err(err) => println!("Error getting version of {}: {}", reference.reference, err),
+            }
+            sources.push(source);
+        }
+        Ok((links, sources))
+    }).await;
+    links.unwrap_or_else(|err| {
+        println!("Error getting asset versions: {}", err);
+        Default::default()
+    })
+}
+
+async fn versioned_cards(services: &Services, config: &Config, directory_path: &str, cards: Vec<ministry_directory::Card>) -> Vec<ministry_directory::Card> {
+    let links = versioned_links(services, config, directory_path).await;
+    let rewrite = |link: &str| -> String {
+        asset_references::rewrite_text(link, |reference| links.get(&reference.reference).cloned())
+    };
+    cards.into_iter().map(|card| asset_references::rewrite_card(card, &rewrite)).collect()
+}
+
+fn build_index(metadata: DeckMetadata, deck: Vec<ministry_directory::Card>) -> Index {
+    Index{
         id: format!("{}/{}", metadata.author_slug, metadata.slug),
         metadata,
         deck_ids: deck.clone().into_iter().map(|card| card.id).collect(),
         toc: deck.into_iter().map(|card| card.to_toc_entry()).collect(),
         version: VERSION.to_string(),
-    })
+    }
 }
 
 #[get("/s/<author_slug>/<deck_slug>/index")]
-async fn deck_index(services: &State<Services>, author_slug: &str, deck_slug: &str) -> Result<Json<Index>, Status> {
-    let path = std::path::PathBuf::from(author_slug).join(deck_slug);
-    match get_index(services, path.to_str().unwrap_or_else(|| ".")).await{
-        Ok(index) => Ok(Json(index)),
+async fn deck_index(services: &State<Services>, config: &State<Config>, author_slug: &str, deck_slug: &str) -> Result<CachedJson<Index>, Status> {
+    let directory_path = find_deck(services, author_slug, deck_slug)?;
+    match get_index(services, config, &directory_path).await{
+        Ok(index) => Ok(CachedJson(index)),
         Err(err) => {
             println!("Error getting index: {}", err);
             Err(Status::InternalServerError)
         },
     }
 }
+///
+/// The directory for `/s/<author_slug>/<deck_slug>`, if there's a deck in it: a 404 if there isn't
+///  (`cardchapter diff` takes a 404 to mean that the deck hasn't been published yet)
+///
+fn find_deck(services: &Services, author_slug: &str, deck_slug: &str) -> Result<String, Status> {
+    match services.root.deck(author_slug, deck_slug){
+        Ok(directory_path) if services.root.directory(directory_path.clone()).exists() => Ok(directory_path),
+        _ => Err(Status::NotFound),
+    }
+}
+
 #[get("/index")]
-async fn default_index(services: &State<Services>) -> Result<Json<Index>, Status> {
-    match get_index(services, ".").await{
-        Ok(index) => Ok(Json(index)),
+async fn default_index(services: &State<Services>, config: &State<Config>) -> Result<CachedJson<Index>, Status> {
+    if !services.root.has_home(){
+        return Err(Status::NotFound);
+    }
+    match get_index(services, config, &services.root.home()).await{
+        Ok(index) => Ok(CachedJson(index)),
         Err(err) => {
             println!("Error getting index: {}", err);
             Err(Status::InternalServerError)
@@ -359,46 +869,58 @@ async fn default_index(services: &State<Services>) -> Result<Json<Index>, Status
     }
 }
 
-#[get("/s/<author_slug>/<deck_slug>/range/<start_id>/<end_id>")]
-async fn deck_range(services: &State<Services>, author_slug: &str, deck_slug: &str, start_id: &str, end_id: &str) -> Result<Json<Vec<ministry_directory::Card>>, Status> {
-    let path = std::path::PathBuf::from(author_slug).join(deck_slug);
-    let directory_path;
-    if author_slug == "default" && deck_slug == "default"{
-        directory_path = ".".to_string();
+///
+/// The cards from start_id to end_id (inclusive) - if end_id is unset, up to 100 cards after start_id
+///
+/// returns None if the range is backwards
+///
+fn card_range(deck: &[ministry_directory::Card], start_id: &str, end_id: &str) -> Option<Vec<ministry_directory::Card>> {
+    // find the start and end indices
+    let unset = |id: &str| id == "0" || id == "undefined" || id.is_empty() || id == "null";
+    let start = match unset(start_id){
+        true => 0,
+        false => deck.iter().position(|card| card.id == start_id).unwrap_or(0),
+    };
+    let mut end = match unset(end_id){
+        true => std::cmp::min(start + 100, deck.len()),
+        false => deck.iter().position(|card| card.id == end_id).unwrap_or(deck.len()),
+    };
+    if end < start {
+        return None;
     }
-    else{
-        directory_path = path.to_str().unwrap_or_else(|| ".").to_string();
+    if end < deck.len(){
+        // we want to include the end card
+        end += 1;
     }
-    let metadata = services.cache.get_metadata(&directory_path).await;
-    let deck = services.cache.get_deck(&directory_path).await;
+    Some(deck[start..end].to_vec())
+}
+
+#[get("/s/<author_slug>/<deck_slug>/range/<start_id>/<end_id>")]
+async fn deck_range(services: &State<Services>, config: &State<Config>, author_slug: &str, deck_slug: &str, start_id: &str, end_id: &str) -> Result<CachedJson<Vec<ministry_directory::Card>>, Status> {
+    let directory_path = find_deck(services, author_slug, deck_slug)?;
+    get_range(services, config, &directory_path, start_id, end_id).await
+}
+
+#[get("/range/<start_id>/<end_id>")]
+async fn default_range(services: &State<Services>, config: &State<Config>, start_id: &str, end_id: &str) -> Result<CachedJson<Vec<ministry_directory::Card>>, Status> {
+    if !services.root.has_home(){
+        return Err(Status::NotFound);
+    }
+    get_range(services, config, &services.root.home(), start_id, end_id).await
+}
+
+async fn get_range(services: &Services, config: &Config, directory_path: &str, start_id: &str, end_id: &str) -> Result<CachedJson<Vec<ministry_directory::Card>>, Status> {
+    let metadata = services.cache.get_metadata(directory_path).await;
+    let deck = services.cache.get_deck(directory_path).await;
     match (metadata, deck) {
         (Ok(metadata), Ok(deck)) => {
             if metadata.hidden {
                 return Err(Status::NotFound);
             }
-            // find the start and end indices
-            let start: usize;
-            if start_id == "0" || start_id == "undefined" || start_id == "" || start_id == "null" {
-                start = 0;
-            }
-            else{
-                start = deck.iter().position(|card| card.id == start_id).unwrap_or(0);
-            }
-            let mut end: usize;
-            if end_id == "0" || end_id == "undefined" || end_id == "" || end_id == "null" {
-                end = std::cmp::min(start + 100, deck.len());
-            }
-            else{
-                end = deck.iter().position(|card| card.id == end_id).unwrap_or(deck.len());
-            }
-            if end < start {
-                return Err(Status::BadRequest);
+            match card_range(&deck, start_id, end_id){
+                Some(cards) => Ok(CachedJson(versioned_cards(services, config, directory_path, cards).await)),
+                None => Err(Status::BadRequest),
             }
-            if end < deck.len(){
-                // we want to include the end card
-                end += 1;
-            }
-            Ok(Json(deck[start..end].to_vec()))
         },
         (Err(err), _) => {
             println!("Error getting deck metadata: {}", err);
@@ -412,10 +934,22 @@ async fn deck_range(services: &State<Services>, author_slug: &str, deck_slug: &s
 }
 
 #[get("/s/<author_slug>/<deck_slug>/content/<content_id>")]
-async fn deck_id(services: &State<Services>, author_slug: &str, deck_slug: &str, content_id: &str) -> Result<Json<ministry_directory::Card>, Status> {
-    let path = std::path::PathBuf::from(author_slug).join(deck_slug);
-    let metadata = services.cache.get_metadata(path.to_str().unwrap_or_else(|| ".")).await;
-    let deck = services.cache.get_deck(path.to_str().unwrap_or_else(|| ".")).await;
+async fn deck_id(services: &State<Services>, config: &State<Config>, author_slug: &str, deck_slug: &str, content_id: &str) -> Result<CachedJson<ministry_directory::Card>, Status> {
+    let directory_path = find_deck(services, author_slug, deck_slug)?;
+    get_content(services, config, &directory_path, content_id).await
+}
+
+#[get("/content/<content_id>")]
+async fn default_id(services: &State<Services>, config: &State<Config>, content_id: &str) -> Result<CachedJson<ministry_directory::Card>, Status> {
+    if !services.root.has_home(){
+        return Err(Status::NotFound);
+    }
+    get_content(services, config, &services.root.home(), content_id).await
+}
+
+async fn get_content(services: &Services, config: &Config, directory_path: &str, content_id: &str) -> Result<CachedJson<ministry_directory::Card>, Status> {
+    let metadata = services.cache.get_metadata(directory_path).await;
+    let deck = services.cache.get_deck(directory_path).await;
     match (metadata, deck) {
         (Ok(metadata), Ok(deck)) => {
             if metadata.hidden {
@@ -423,7 +957,8 @@ async fn deck_id(services: &State<Services>, author_slug: &str, deck_slug: &str,
             }
             //find the matching card
             let index = deck.iter().position(|card| card.id == content_id).unwrap_or(0);
-            Ok(Json(deck[index].clone()))
+            let mut cards = versioned_cards(services, config, directory_path, vec![deck[index].clone()]).await;
+            Ok(CachedJson(cards.remove(0)))
         },
         (Err(err), _) => {
             println!("Error getting deck metadata: {}", err);
@@ -437,13 +972,54 @@ async fn deck_id(services: &State<Services>, author_slug: &str, deck_slug: &str,
 }
 
 
-#[get("/s/<author_slug>/<deck_slug>/assets/<asset_path..>?<file_directives..>")]
-async fn deck_assets(author_slug: &str, deck_slug: &str, asset_path: std::path::PathBuf, file_directives: file_modifiers::FileDirectives, config: &State<Config>) -> Result<rocket::fs::NamedFile, Status> {
-    let path = std::path::PathBuf::from(author_slug).join(deck_slug);
-    let directory = ministry_directory::MinistryDirectory::new(path.to_str().unwrap_or_else(|| ".").to_string());
+#[get("/s/<author_slug>/<deck_slug>/manifest")]
+async fn deck_manifest(services: &State<Services>, config: &State<Config>, author_slug: &str, deck_slug: &str) -> Result<Json<BTreeMap<String, String>>, Status> {
+    // a hash of every asset, so that `cardchapter diff` can tell what's changed without downloading everything
+    let directory_path = find_deck(services, author_slug, deck_slug)?;
+    match services.cache.get_metadata(&directory_path).await{
+        Ok(metadata) if !metadata.hidden => {},
+        _ => return Err(Status::NotFound),
+    }
+    let versioner = services.images.versioner(config);
+    match services.cache.get_manifest(&directory_path, move |source| versioner.source_hash(source)).await{
+        Ok(manifest) => Ok(Json(manifest.as_ref().clone())),
+        Err(err) => {
+            println!("Error getting manifest: {}", err);
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+///
+/// An asset, with everything a browser needs to cache it: `v` is the version that versioned_links put in the link, if there is one
+///
+async fn get_asset(directory: &MinistryDirectory, asset_path: std::path::PathBuf, v: Option<&str>, file_directives: &file_modifiers::FileDirectives, config: &Config, services: &Services) -> Result<asset_response::AssetResponse> {
+    let source = services.root.find_asset(directory, &asset_path)?;
+    let version = services.images.version(&source, config).await?;
+    let file_path = directory.convert_asset(source.clone(), &asset_path, config, services, file_directives).await?;
+    let file = rocket::fs::NamedFile::open(file_path).await?;
+    let etag = match file.path() == source.as_path(){
+        true => version.clone(),
+        // converted images are named after a hash of their source and of what was done to it
+        false => file.path().file_stem().and_then(|stem| stem.to_str()).unwrap_or("").to_string(),
+    };
+    Ok(asset_response::AssetResponse{
+        file,
+        etag: format!("\"{}\"", etag),
+        // an old version of the link gets the new file: that's fine, as long as nobody keeps it
+        immutable: v == Some(version.as_str()),
+    })
+}
+
+#[get("/s/<author_slug>/<deck_slug>/assets/<asset_path..>?<v>&<file_directives..>")]
+async fn deck_assets(author_slug: &str, deck_slug: &str, asset_path: std::path::PathBuf, v: Option<&str>, file_directives: file_modifiers::FileDirectives, config: &State<Config>, services: &State<Services>) -> Result<asset_response::AssetResponse, Status> {
+    let Ok(directory_path) = services.root.deck(author_slug, deck_slug) else {
+        return Err(Status::NotFound);
+    };
+    let directory = services.root.directory(directory_path);
 
-    match directory.get_named_file(asset_path, &config, &file_directives).await{
-        Ok(opened_file) => Ok(opened_file),
+    match get_asset(&directory, asset_path, v, &file_directives, config, services).await{
+        Ok(asset) => Ok(asset),
         Err(err) => {
             println!("Error getting asset: {}", err);
             Err(Status::NotFound)
@@ -451,12 +1027,11 @@ async fn deck_assets(author_slug: &str, deck_slug: &str, asset_path: std::path::
     }
 }
 
-#[get("/assets/<asset_path..>?<file_directives..>")]
-async fn default_assets(asset_path: std::path::PathBuf, file_directives: file_modifiers::FileDirectives, config: &State<Config>) -> Result<rocket::fs::NamedFile, Status> {
-    let directory: MinistryDirectory;
-    directory = ministry_directory::MinistryDirectory::new(".".to_string());
-    match directory.get_named_file(asset_path, &config, &file_directives).await{
-        Ok(opened_file) => Ok(opened_file),
+#[get("/assets/<asset_path..>?<v>&<file_directives..>")]
+async fn default_assets(asset_path: std::path::PathBuf, v: Option<&str>, file_directives: file_modifiers::FileDirectives, config: &State<Config>, services: &State<Services>) -> Result<asset_response::AssetResponse, Status> {
+    let directory = services.root.directory(services.root.home());
+    match get_asset(&directory, asset_path, v, &file_directives, config, services).await{
+        Ok(asset) => Ok(asset),
         Err(err) => {
             println!("Error getting asset: {}", err);
             Err(Status::NotFound)
@@ -464,59 +1039,101 @@ async fn default_assets(asset_path: std::path::PathBuf, file_directives: file_mo
     }
 }
 
-#[get("/sitemap")]
-async fn sitemap(services: &State<Services>) -> Json<HashMap<String, Vec<DeckSummary>>> {
-    // look at the current directory
-    let path = std::path::PathBuf::from(".");
+async fn get_sitemap(services: &Services) -> Result<HashMap<String, Vec<DeckSummary>>> {
     let mut hash_map = HashMap::new();
 
-    // for each subdirectory...
-    let paths = std::fs::read_dir(path).unwrap();
-    for author_path in paths{
-        let author_path = author_path.unwrap().path();
-        let str_path = author_path.to_str().unwrap_or("");
-        if str_path == "." || str_path.ends_with(".") || str_path.ends_with(".git") || str_path.ends_with("temp_assets") ||
-                str_path.ends_with("node_modules") || str_path.ends_with("assets") || str_path.ends_with("src") || str_path.ends_with("target") {
+    for deck_path in services.root.decks()?{
+        let metadata = services.cache.get_metadata(deck_path.to_str().unwrap_or("")).await?;
+        if metadata.hidden || metadata.unlisted {
+            // don't include hidden or unlisted decks in the sitemap
             continue;
         }
-        if author_path.is_dir(){
-            // if it's a directory, that's a _user_ directory: so "author_slug" is the name of the directory
-            // for each subdirectory of that directory...
-            // println!("Author path: {}", author_path.to_str().unwrap_or(""));
-            let more_paths = std::fs::read_dir(author_path.clone()).unwrap();
-            for deck_path in more_paths{
-                let deck_path = deck_path.unwrap().path();
-                let str_deck_path = deck_path.to_str().unwrap_or("");
-                if str_deck_path == "." || str_deck_path.ends_with(".") || str_deck_path.ends_with(".git") || str_deck_path.ends_with("temp_assets") ||
-                        str_deck_path.ends_with("node_modules") || str_deck_path.ends_with("assets") {
-                    continue;
-                }
-                if deck_path.is_dir(){
-                    // if it's a directory, that's a _deck_ directory: so "deck_slug" is the name of the directory
-                    // for each file in that directory...
-                    let deck = ministry_directory::MinistryDirectory::new(deck_path.to_str().unwrap_or("").to_string());
-                    if deck.exists(){
-                        let metadata = services.cache.get_metadata(deck_path.to_str().unwrap_or("")).await.unwrap();
-                        if metadata.hidden || metadata.unlisted {
-                            // don't include hidden or unlisted decks in the sitemap
-                            continue;
-                        }
-
-                        let author_slug = author_path.to_str().unwrap_or("").to_string().replace(".\\", "");
-
-                        if hash_map.contains_key(&author_slug){
-                            let decks: &mut Vec<DeckSummary> = hash_map.get_mut(&author_slug).unwrap();
-                            decks.push(metadata.to_summary());
-                        }
-                        else{
-                            hash_map.insert(author_slug, vec![metadata.to_summary()]);
-                        }
-                    }
-                }
+
+        let author_slug = deck_path.parent().and_then(|author_path| author_path.file_name()).and_then(|name| name.to_str()).unwrap_or("").to_string();
+
+        hash_map.entry(author_slug).or_insert_with(Vec::new).push(metadata.to_summary());
+    }
+    Ok(hash_map)
+}
+
+#[get("/sitemap")]
+async fn sitemap(services: &State<Services>) -> Result<Json<HashMap<String, Vec<DeckSummary>>>, Status> {
+    match get_sitemap(services).await{
+        Ok(hash_map) => Ok(Json(hash_map)),
+        Err(err) => {
+            println!("Error getting sitemap: {}", err);
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+#[post("/login", data = "<login>")]
+async fn login_route(login: Json<credentials::LoginRequest>, services: &State<Services>) -> Result<Json<credentials::LoginResponse>, Status> {
+    match services.tokens.login(&login.author_slug, &login.password).await{
+        Ok(token) => {
+            println!("Logged in: {}", login.author_slug);
+            Ok(Json(credentials::LoginResponse{
+                author_slug: login.author_slug.clone(),
+                token,
+            }))
+        },
+        Err(err) => {
+            println!("Failed login for {}: {}", login.author_slug, err);
+            Err(Status::Unauthorized)
+        },
+    }
+}
+
+fn check_publisher(publisher: &auth::Publisher, author_slug: &str) -> Result<(), (Status, String)> {
+    if publisher.author_slug != author_slug {
+        return Err((Status::Forbidden, format!("You can only publish decks as {}", publisher.author_slug)));
+    }
+    Ok(())
+}
+
+#[get("/publish/<author_slug>/<_deck_slug>")]
+fn publish_check(publisher: auth::Publisher, author_slug: &str, _deck_slug: &str) -> Result<Status, (Status, String)> {
+    // lets `cardchapter publish` find out whether it's allowed in before uploading anything
+    check_publisher(&publisher, author_slug)?;
+    Ok(Status::NoContent)
+}
+
+#[post("/publish/<author_slug>/<deck_slug>", data = "<upload>")]
+async fn publish_deck(publisher: auth::Publisher, author_slug: &str, deck_slug: &str, upload: Data<'_>, config: &State<Config>, services: &State<Services>) -> Result<Json<DeckSummary>, (Status, String)> {
+    check_publisher(&publisher, author_slug)?;
+    if author_slug != slugify!(author_slug) || deck_slug != slugify!(deck_slug) {
+        return Err((Status::BadRequest, "Author and deck must be slugs".to_string()));
+    }
+    let package = match publish::read_upload(upload, config.max_upload_size).await{
+        Ok(package) => package,
+        Err(err) => return Err((Status::PayloadTooLarge, err.to_string())),
+    };
+
+    let (author, deck) = (author_slug.to_string(), deck_slug.to_string());
+    let root = config.root.clone();
+    let max_unpacked_size = config.max_unpacked_size;
+    let allow_extra_header = config.extra_header != security::ExtraHeaderPolicy::Rejected;
+    let received = rocket::tokio::task::spawn_blocking(move || {
+        publish::receive(&root, &author, &deck, &package, allow_extra_header, max_unpacked_size)
+    }).await;
+
+    match received{
+        Ok(Ok(metadata)) => {
+            println!("Published {}/{}", author_slug, deck_slug);
+            if let Ok(directory_path) = services.root.deck(author_slug, deck_slug){
+                services.cache.invalidate(&directory_path).await;
             }
-        }
+            Ok(Json(metadata.to_summary()))
+        },
+        Ok(Err(err)) => {
+            println!("Error publishing {}/{}: {}", author_slug, deck_slug, err);
+            Err((Status::UnprocessableEntity, err.to_string()))
+        },
+        Err(err) => {
+            println!("Error publishing {}/{}: {}", author_slug, deck_slug, err);
+            Err((Status::InternalServerError, err.to_string()))
+        },
     }
-    Json(hash_map)
 }
 
 #[derive(Responder)]
@@ -540,40 +1157,23 @@ async fn qr(
 }
 
 #[get("/qr_html?<link>")]
-fn qr_html(link: String, config: &State<Config>) -> content::RawHtml<String> {
+fn qr_html(link: String, config: &State<Config>) -> Result<content::RawHtml<String>, Status> {
 
-    let css_location = match config.dev {
-        true => "/js/style.css",
-        false => &format!("/js/{}/style.css", VERSION),
-    };
-
-    content::RawHtml(format!(indoc!(r#"
-    <!DOCTYPE html>
-    <html>
-        <head>
-            <link rel="stylesheet" href="{}">
-            <meta charset="UTF-8">
-            <meta name="viewport" content="width=device-width, initial-scale=1">
-        </head>
-        <body>
-            <div id="app">
-                    <div class="primary-card">
-                        <div class="content">
-                            <header id="primary-header">
-                            </header>
-                            <div class="everything-feed">
-                                <div class="qrcode-frame">
-                                    <img class="qrcode" src="/qr?link={}" />
-                                </header>
-                            </div>
+    let css_location = css_location(config);
 
-                        </div>
-                    </div>
-                </div>
-            </div>`
-        </body>
-    </html>
-    "#), css_location, link))
+    // the link goes in a query string: it has to be encoded as one before the template escapes it for HTML
+    let qr_src = format!("/qr?link={}", url::form_urlencoded::byte_serialize(link.as_bytes()).collect::<String>());
+    let page = templates::QrPage{
+        css_location: &css_location,
+        qr_src: &qr_src,
+    };
+    match page.render(){
+        Ok(page) => Ok(content::RawHtml(page)),
+        Err(err) => {
+            println!("Error rendering QR page: {}", err);
+            Err(Status::InternalServerError)
+        },
+    }
     /*
                 <div class="primary-card">
                     <div class="content">
@@ -606,20 +1206,40 @@ async fn launch_server(flags: Flags, config: Config) -> Rocket<Build> {
 
     app = app.mount("/", routes![
         home,
-        deck_home,
-        deck_index,
         default_index,
-        deck_range,
-        deck_id,
-        deck_assets,
+        default_range,
+        default_id,
         default_assets,
         sitemap,
         boop,
         qr,
         qr_html,
         robots,
+        schema,
     ]);
 
+    let services = Services::new(&config);
+    match services.root.mode(){
+        content_root::Mode::Single => {
+            println!("Serving the deck in {} (single-deck mode)", config.root.to_str().unwrap_or("."));
+            app = app.mount("/", routes![single_deck_redirect]);
+        },
+        content_root::Mode::Multi => {
+            println!("Serving the decks in {} (multi-deck mode)", config.root.to_str().unwrap_or("."));
+            app = app.mount("/", routes![
+                deck_home,
+                deck_index,
+                deck_range,
+                deck_id,
+                deck_assets,
+                deck_manifest,
+                login_route,
+                publish_check,
+                publish_deck,
+            ]);
+        },
+    }
+
     if std::env::var("ROCKET_ENV").unwrap_or("production".to_string()) == "development"{
         // here we point to the JS and CSS build directories:
         // we only bother with this next bit if we're in dev mode: otherwise we should use include_str! to bundle the files directly into the binary
@@ -642,19 +1262,32 @@ async fn launch_server(flags: Flags, config: Config) -> Rocket<Build> {
         app = app.mount("/", routes![js_app, js_css]);
     }
 
-    let services = Services::new();
+    if flags.warm{
+        // before we start listening: so that nobody ever waits for an image to be converted
+        match optimize::optimize(&services, &config).await{
+            Ok((original, optimized)) => println!("Warmed up: {:.1} MB of images, {:.1} MB converted", original as f64 / (1024.0 * 1024.0), optimized as f64 / (1024.0 * 1024.0)),
+            Err(err) => println!("Failed to warm up: {}", err),
+        }
+    }
+    services.images.spawn_eviction(config.asset_cache_size, std::time::Duration::from_secs(60));
+
+    if config.dev{
+        match live_reload::watch(&config.root){
+            Ok(live_reload) => {
+                app = app.mount("/", routes![live_reload_events]);
+                app = app.manage(live_reload);
+            },
+            Err(err) => println!("Live reload is off: {}", err),
+        }
+    }
 
+    app = app.attach(compression::Compression);
+    app = app.attach(security::ContentSecurityPolicy);
+    app = app.attach(security::shield(&config));
     app = app.manage(flags);
     app = app.manage(config);
     app = app.manage(services);
 
-    // if _multi is false, we are running in single-deck mode
-    // the only goal here is to serve the deck in the current directory
-    // as well as the JS required to run it
-    // we're assuming that we're running in "dev" mode, which is to say, frequently updating the deck
-    // (I guess we could also have a "prod" mode where everything is aggressively cached, but
-    //     the assumption is that "multi" is the REAL prod mode)
-
     /*
     app = app.manage(services);
 
@@ -678,21 +1311,29 @@ async fn rocket() -> Rocket<Build> {
     // Parse any args that were passed in:
     let args: Vec<String> = env::args().collect();
 
-    let flags = Flags::empty();
-    let config = Config::from_env();
+    let flags = Flags::from_args(args.clone());
+    let config = Config::from_env(flags.root.as_deref(), flags.mode.as_deref());
 
 
     if args.len() == 1{
         println!("Help:");
         println!("  version:    Print the version");
-        println!("  init:       Create a new deck in the current directory");
-        println!("  new:        Create a new deck in a specified directory");
-        println!("  serve:      Start the server");
+        println!("  init:       Create a new deck in the current directory (--markdown to write it as content.md)");
+        println!("  new:        Create a new deck in a specified directory (--markdown to write it as content.md)");
+        println!("  import-markdown: Turn a Markdown file into a deck in the current directory (import-markdown <file.md>)");
+        println!("  serve:      Start the server (--root <dir> to serve the decks in <dir>, --mode single|multi, --warm to convert every image before it starts listening)");
+        println!("  login:      Log in to a server so that you can publish to it (login <server-url>)");
+        println!("  publish:    Upload the deck in the current directory to a server (publish [server-url])");
+        println!("  diff:       Compare the deck in the current directory with its published version (diff [server-url])");
+        println!("  add-author: Let an author publish to the server in the current directory");
+        println!("  schema:     Print the JSON Schema for content.yml (--output <file> to write it somewhere)");
+        println!("  check:      Look for problems in every deck (check [path] [--format json])");
+        println!("  optimize:   Convert every image the decks link to ahead of time (run it where you run serve)");
+        println!("  clean:      Delete converted images whose source files are gone (run it where you run serve)");
+        println!("  build:      Export every deck as a static site (--output <dir>, default: ./build)");
         std::process::exit(0);
     }
     if args.len() > 1{
-        let flags = Flags::from_args(args.clone());
-
         let arg = &args[1];
         if arg == "version"{
             println!("{}", VERSION);
@@ -706,21 +1347,52 @@ async fn rocket() -> Rocket<Build> {
             new(flags);
             std::process::exit(0);
         }
+        if arg == "import-markdown"{
+            import_markdown(flags);
+            std::process::exit(0);
+        }
+        if arg == "optimize"{
+            optimize(config).await;
+            std::process::exit(0);
+        }
+        if arg == "clean"{
+            clean(config);
+            std::process::exit(0);
+        }
+        if arg == "build"{
+            build(flags, config).await;
+            std::process::exit(0);
+        }
         if arg == "status"{
             println!("Status...");
             status(flags);
             std::process::exit(0);
         }
+        if arg == "schema"{
+            print_schema(flags);
+            std::process::exit(0);
+        }
+        if arg == "check"{
+            check(flags);
+            std::process::exit(0);
+        }
         if arg == "diff"{
             println!("Diffing...");
+            diff(flags);
             std::process::exit(0);
         }
         if arg == "login"{
             println!("Logging in...");
+            login(flags);
+            std::process::exit(0);
+        }
+        if arg == "add-author"{
+            add_author(config);
             std::process::exit(0);
         }
         if arg == "publish"{
             println!("Publishing...");
+            publish(flags);
             std::process::exit(0);
         }
         if arg == "serve"{
@@ -730,4 +1402,195 @@ async fn rocket() -> Rocket<Build> {
     }
 
     launch_server(flags, config).await
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::asynchronous::Client;
+
+    ///
+    /// A server for the decks in example/ (and everything else in this directory), converting images somewhere we can throw away
+    ///
+    async fn client() -> Client {
+        client_for(Path::new(env!("CARGO_MANIFEST_DIR")), "multi").await
+    }
+
+    async fn client_for(root: &Path, mode: &str) -> Client {
+        let mut config = Config::from_env(root.to_str(), Some(mode));
+        let scratch = std::env::temp_dir().join(format!("cardchapter-server-{}", std::process::id()));
+        config.temporary_asset_directory = scratch.join("temp_assets").to_str().unwrap().to_string();
+        config.token_store = scratch.join("tokens.json").to_str().unwrap().to_string();
+        Client::tracked(launch_server(Flags::from_args(Vec::new()), config).await).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn asset_links_carry_the_version_that_the_asset_is_served_with() {
+        let client = client().await;
+        let cards = client.get("/s/example/example/range/0/0").dispatch().await.into_string().await.unwrap();
+        let version = cards.split("assets/bee.jpg?width=100&v=").nth(1).map(|rest| &rest[..16]).unwrap();
+        // (and again, from the cache)
+        let again = client.get("/s/example/example/range/0/0").dispatch().await.into_string().await.unwrap();
+        assert_eq!(again, cards);
+
+        let response = client.get(format!("/s/example/example/assets/bee.jpg?v={}", version)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Cache-Control"), Some("public, max-age=31536000, immutable"));
+    }
+
+    const HOSTILE: &str = "</title><script>alert(1)</script>\"><img src=x onerror=alert(2)>";
+
+    ///
+    /// A content root with one deck in it, written by somebody who'd like to run some script on our pages
+    ///
+    fn hostile_root(name: &str, is_home: bool) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cardchapter-hostile-{}-{}", name, std::process::id()));
+        let deck = match is_home {
+            true => root.clone(),
+            false => root.join(slugify!(HOSTILE)).join(slugify!(HOSTILE)),
+        };
+        std::fs::create_dir_all(deck.join("assets")).unwrap();
+        let yaml_string = |text: &str| format!("'{}'", text.replace('\'', "''"));
+        std::fs::write(deck.join("content.yml"), format!(
+            "name: {}\nauthor: {}\ndescription: {}\npages:\n  - title: Hello\n",
+            yaml_string(HOSTILE), yaml_string(HOSTILE), yaml_string(HOSTILE),
+        )).unwrap();
+        root
+    }
+
+    fn assert_inert(page: &str) {
+        assert!(!page.contains("<script>alert"), "{}", page);
+        assert!(!page.contains("<img src=x"), "{}", page);
+    }
+
+    #[rocket::async_test]
+    async fn hostile_decks_render_inert() {
+        let client = client_for(&hostile_root("home", true), "single").await;
+        let response = client.get("/").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let page = response.into_string().await.unwrap();
+        assert!(page.contains("&lt;/title&gt;&lt;script&gt;alert(1)&lt;/script&gt;"), "{}", page);
+        assert_inert(&page);
+
+        let client = client_for(&hostile_root("catalog", false), "multi").await;
+        let page = client.get("/").dispatch().await.into_string().await.unwrap();
+        assert!(page.contains("&lt;/title&gt;"), "{}", page);
+        assert_inert(&page);
+        let page = client.get(format!("/s/{}/{}", slugify!(HOSTILE), slugify!(HOSTILE))).dispatch().await.into_string().await.unwrap();
+        assert!(page.contains("&lt;/title&gt;"), "{}", page);
+        assert_inert(&page);
+    }
+
+    #[rocket::async_test]
+    async fn hostile_qr_links_render_inert() {
+        let client = client().await;
+        let link: String = url::form_urlencoded::byte_serialize(HOSTILE.as_bytes()).collect();
+        let response = client.get(format!("/qr_html?link={}", link)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let page = response.into_string().await.unwrap();
+        assert_inert(&page);
+        assert!(page.contains(&format!("/qr?link={}", link.replace('&', "&amp;"))), "{}", page);
+    }
+
+    #[rocket::async_test]
+    async fn only_our_own_scripts_run() {
+        let client = client().await;
+        let response = client.get("/s/example/example").dispatch().await;
+        let policy = response.headers().get_one("Content-Security-Policy").unwrap().to_string();
+        let script_src = policy.split("; ").find(|directive| directive.starts_with("script-src")).unwrap();
+        let page = response.into_string().await.unwrap();
+        let nonce = script_src.strip_prefix("script-src 'nonce-").and_then(|nonce| nonce.strip_suffix('\'')).unwrap();
+        assert!(page.contains(&format!("nonce=\"{}\"", nonce)));
+
+        // (a deck could have an assets/x.html with a <script src="x.js"> in it)
+        let response = client.get("/s/example/example/assets/bee.jpg").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Content-Security-Policy"), Some("sandbox; default-src 'none'"));
+        assert_eq!(response.headers().get_one("X-Content-Type-Options"), Some("nosniff"));
+    }
+
+    #[rocket::async_test]
+    async fn refuses_encoded_traversal() {
+        let client = client().await;
+        for path in [
+            "/s/%2e%2e/%2e%2e/index",
+            "/s/example/%2e%2e/index",
+            "/s/example/example/assets/%2e%2e/content.yml",
+            "/s/example/example/assets/..%2f..%2f..%2fCargo.toml",
+            "/s/example/example/assets/%2e%2e%2f%2e%2e%2f%2e%2e%2fCargo.toml",
+            "/s/example/example/assets/%2Fetc%2Fpasswd",
+            "/assets/..%2fCargo.toml",
+            "/s/example/example/assets/..%5c..%5c..%5cCargo.toml",
+        ] {
+            let response = client.get(path).dispatch().await;
+            assert!(response.status() == Status::NotFound || response.status() == Status::UnprocessableEntity, "{}: {}", path, response.status());
+        }
+    }
+
+    #[rocket::async_test]
+    async fn missing_decks_are_404s() {
+        let client = client().await;
+        assert_eq!(client.get("/s/example/example/index").dispatch().await.status(), Status::Ok);
+        for path in ["/s/example/nope/index", "/s/example/nope/manifest", "/s/example/nope/range/0/0", "/s/example/nope/content/1"] {
+            assert_eq!(client.get(path).dispatch().await.status(), Status::NotFound, "{}", path);
+        }
+        // a directory that's there, but isn't a deck
+        assert_eq!(client.get("/s/src/js/index").dispatch().await.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn published_decks_are_served_just_like_local_ones() {
+        let example = Path::new(env!("CARGO_MANIFEST_DIR")).join("example/example");
+        let package = publish::package(&example).unwrap();
+        let root = std::env::temp_dir().join(format!("cardchapter-round-trip-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        publish::receive(&root, "example", "example", &package, true, 1024 * 1024 * 1024).unwrap();
+
+        let index = |client: Client| async move {
+            let response = client.get("/s/example/example/index").dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            let mut index = response.into_json::<rocket::serde::json::Value>().await.unwrap();
+            // (unpacking gives every file a fresh modification time)
+            index["metadata"].as_object_mut().unwrap().remove("last_update_time");
+            index
+        };
+        assert_eq!(index(client_for(&root, "multi").await).await, index(client().await).await);
+    }
+
+    #[rocket::async_test]
+    async fn manifests_list_the_assets_a_deck_links_to() {
+        let client = client().await;
+        let manifest = client.get("/s/example/example/manifest").dispatch().await.into_json::<BTreeMap<String, String>>().await.unwrap();
+        let local = MinistryDirectory::new(Path::new(env!("CARGO_MANIFEST_DIR")).join("example/example").to_str().unwrap().to_string());
+        assert_eq!(manifest, local.get_asset_hashes().unwrap());
+        assert!(manifest.contains_key("assets/bee.jpg"));
+        assert!(manifest.contains_key("assets/florb/Florb_00001.png"));
+        assert!(!manifest.contains_key("assets/impatient.gif"));
+
+        // (a deck with no assets/ at all, and one that links to assets that aren't there)
+        let root = std::env::temp_dir().join(format!("cardchapter-manifest-{}", std::process::id()));
+        let deck = root.join("alice").join("bees");
+        std::fs::create_dir_all(&deck).unwrap();
+        std::fs::write(deck.join("content.yml"), "name: Bees\nauthor: Alice\npages:\n  - image: assets/bee.jpg\n").unwrap();
+        assert!(MinistryDirectory::new(deck.to_str().unwrap().to_string()).get_asset_hashes().unwrap().is_empty());
+        std::fs::create_dir_all(deck.join("assets")).unwrap();
+        let client = client_for(&root, "multi").await;
+        let response = client.get("/s/alice/bees/manifest").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().await.unwrap(), "{}");
+    }
+
+    #[rocket::async_test]
+    async fn single_deck_servers_send_deck_links_to_the_top() {
+        let client = client_for(&Path::new(env!("CARGO_MANIFEST_DIR")).join("example/example"), "single").await;
+        let response = client.get("/s/a/b/assets/bee.jpg?width=100").dispatch().await;
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(response.headers().get_one("Location"), Some("/assets/bee.jpg?width=100"));
+        assert_eq!(client.get("/assets/bee.jpg?width=100").dispatch().await.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn multi_deck_servers_without_a_home_deck_have_no_index() {
+        let client = client().await;
+        assert_eq!(client.get("/index").dispatch().await.status(), Status::NotFound);
+    }
+}
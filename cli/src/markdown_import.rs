@@ -0,0 +1,356 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use slugify::slugify;
+
+use crate::content_file;
+
+#[derive(Serialize)]
+struct ImportedDeck{
+    name: String,
+    author: String,
+    pages: Vec<ImportedCard>,
+}
+
+#[derive(Serialize, Default)]
+struct ImportedCard{
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    card_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+struct Importer{
+    source_directory: PathBuf,
+    assets_directory: PathBuf,
+    // assets/ file name -> the file it was copied from
+    copied: Vec<(String, PathBuf)>,
+    // the files in assets/ that weren't there before we copied them (so that a failed import can take them back)
+    created: Vec<PathBuf>,
+    ids: HashSet<String>,
+}
+
+///
+/// Turn a plain Markdown document (talk notes, mostly) into a deck in `directory_root`:
+///  every heading is a card title, every paragraph is a markdown card, and every image on its own is an image card
+///
+/// local images are copied into assets/, and the deck is named after the first `# heading` (or the file)
+///
+/// if it doesn't work out, the directory is left the way it was
+///
+pub fn import(markdown_path: &Path, directory_root: &Path, author: &str) -> Result<usize>{
+    let markdown = std::fs::read_to_string(markdown_path)?;
+    let mut importer = Importer{
+        source_directory: markdown_path.parent().unwrap_or(Path::new(".")).to_path_buf(),
+        assets_directory: directory_root.join("assets"),
+        copied: Vec::new(),
+        created: Vec::new(),
+        ids: HashSet::new(),
+    };
+    let created_assets_directory = !importer.assets_directory.exists();
+    std::fs::create_dir_all(&importer.assets_directory)?;
+
+    let imported = importer.build_deck(&markdown, markdown_path, directory_root, author);
+    if imported.is_err(){
+        for created in &importer.created{
+            let _ = std::fs::remove_file(created);
+        }
+        if created_assets_directory{
+            let _ = std::fs::remove_dir_all(&importer.assets_directory);
+        }
+    }
+    imported
+}
+
+impl Importer{
+    fn build_deck(&mut self, markdown: &str, markdown_path: &Path, directory_root: &Path, author: &str) -> Result<usize>{
+        let mut name = None;
+        let mut pages: Vec<ImportedCard> = Vec::new();
+        // a heading waits for the next block, which becomes its card
+        let mut heading: Option<(String, String)> = None;
+        for block in split_blocks(markdown){
+            if let Some((level, text)) = parse_heading(&block){
+                if level == 1 && name.is_none(){
+                    name = Some(text.clone());
+                }
+                if let Some((text, _)) = heading.take(){
+                    pages.push(self.title_card(text));
+                }
+                heading = Some((text, block));
+                continue;
+            }
+
+            let mut card = match parse_image(&block){
+                Some((alt, src)) => ImportedCard{
+                    // image cards use their title as the image's title, so the alt text fits there nicely
+                    title: Some(alt).filter(|alt| !alt.is_empty()),
+                    image: Some(self.asset_link(&src)),
+                    ..ImportedCard::default()
+                },
+                None => ImportedCard{
+                    content: Some(format!("{}\n", self.rewrite_images(&block))),
+                    ..ImportedCard::default()
+                },
+            };
+            if let Some((text, heading_block)) = heading.take(){
+                card.id = Some(self.unique_id(&text));
+                // markdown cards don't show their title, so the heading stays in the markdown as well
+                if let Some(content) = card.content.take(){
+                    card.content = Some(format!("{}\n\n{}", heading_block, content));
+                }
+                card.title = Some(text);
+            }
+            pages.push(card);
+        }
+        if let Some((text, _)) = heading.take(){
+            pages.push(self.title_card(text));
+        }
+        if pages.is_empty(){
+            return Err(anyhow!("There's nothing in {} to make cards out of", markdown_path.to_str().unwrap_or("")));
+        }
+
+        let name = name.unwrap_or_else(|| {
+            markdown_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("Imported").to_string()
+        });
+        let card_count = pages.len();
+        let deck = ImportedDeck{
+            name,
+            author: author.to_string(),
+            pages,
+        };
+        // make sure that we made something we can actually serve, before it replaces anything
+        let temporary_path = directory_root.join(".content.import.yml");
+        std::fs::write(&temporary_path, serde_yaml::to_string(&deck)?)?;
+        if let Err(err) = content_file::load(&temporary_path).and_then(|_| Ok(std::fs::rename(&temporary_path, directory_root.join("content.yml"))?)){
+            let _ = std::fs::remove_file(&temporary_path);
+            return Err(err);
+        }
+        Ok(card_count)
+    }
+
+    fn title_card(&mut self, text: String) -> ImportedCard{
+        ImportedCard{
+            id: Some(self.unique_id(&text)),
+            title: Some(text),
+            card_type: Some("title".to_string()),
+            ..ImportedCard::default()
+        }
+    }
+
+    fn unique_id(&mut self, text: &str) -> String{
+        let base = match slugify!(text){
+            slug if slug.is_empty() => "card".to_string(),
+            slug => slug,
+        };
+        let mut id = base.clone();
+        let mut counter = 2;
+        while self.ids.contains(&id){
+            id = format!("{}-{}", base, counter);
+            counter += 1;
+        }
+        self.ids.insert(id.clone());
+        id
+    }
+
+    ///
+    /// Point `![alt](src)` images at assets/ (copying them there, if they're local files)
+    ///
+    fn rewrite_images(&mut self, block: &str) -> String{
+        let mut rewritten = String::new();
+        let mut remaining = block;
+        while let Some(start) = remaining.find("![") {
+            let Some((alt, src, length)) = parse_image_at(&remaining[start..]) else {
+                rewritten.push_str(&remaining[..start + 2]);
+                remaining = &remaining[start + 2..];
+                continue;
+            };
+            rewritten.push_str(&remaining[..start]);
+            rewritten.push_str(&format!("![{}]({})", alt, self.asset_link(&src)));
+            remaining = &remaining[start + length..];
+        }
+        rewritten.push_str(remaining);
+        rewritten
+    }
+
+    ///
+    /// Copy a local image into assets/, returning its new link: anything that isn't a local file is left alone
+    ///
+    /// `bee.jpg?width=100` becomes `assets/bee.jpg?width=100`
+    ///
+    fn asset_link(&mut self, src: &str) -> String{
+        if src.contains("://") || src.starts_with("data:") || src.starts_with("//"){
+            return src.to_string();
+        }
+        let path = src.split(['?', '#']).next().unwrap_or(src);
+        let query = &src[path.len()..];
+        let source_path = self.source_directory.join(path);
+        if !source_path.is_file(){
+            println!("Image not found, leaving it as it is: {}", source_path.to_str().unwrap_or(""));
+            return src.to_string();
+        }
+        if let Some((file_name, _)) = self.copied.iter().find(|(_, copied_from)| copied_from == &source_path){
+            return format!("assets/{}{}", file_name, query);
+        }
+
+        // two different images with the same name can't both be assets/bee.jpg
+        let file_name = source_path.file_name().and_then(|name| name.to_str()).unwrap_or("image").to_string();
+        let (stem, extension) = match file_name.rsplit_once('.'){
+            Some((stem, extension)) => (stem.to_string(), format!(".{}", extension)),
+            None => (file_name.clone(), "".to_string()),
+        };
+        let mut asset_name = file_name.clone();
+        let mut counter = 2;
+        while self.copied.iter().any(|(copied_name, _)| copied_name == &asset_name){
+            asset_name = format!("{}-{}{}", stem, counter, extension);
+            counter += 1;
+        }
+
+        let existed = self.assets_directory.join(&asset_name).exists();
+        match std::fs::copy(&source_path, self.assets_directory.join(&asset_name)){
+            Ok(_) => {
+                println!("✅ assets/{}", asset_name);
+                if !existed{
+                    self.created.push(self.assets_directory.join(&asset_name));
+                }
+                self.copied.push((asset_name.clone(), source_path));
+                format!("assets/{}{}", asset_name, query)
+            },
+            Err(err) => {
+                println!("Couldn't copy {}: {}", source_path.to_str().unwrap_or(""), err);
+                src.to_string()
+            }
+        }
+    }
+}
+
+///
+/// Split a Markdown document into blocks: paragraphs, headings, lists, fenced code (which can contain blank lines)...
+///
+fn split_blocks(markdown: &str) -> Vec<String>{
+    let mut blocks = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    let mut fence: Option<&str> = None;
+    for line in markdown.lines(){
+        let trimmed = line.trim_start();
+        match fence{
+            Some(marker) => {
+                current.push(line);
+                if trimmed.starts_with(marker){
+                    fence = None;
+                }
+                continue;
+            },
+            None if trimmed.starts_with("```") || trimmed.starts_with("~~~") => {
+                fence = Some(&trimmed[..3]);
+            },
+            None => {},
+        }
+        if line.trim().is_empty(){
+            if !current.is_empty(){
+                blocks.push(current.join("\n"));
+                current.clear();
+            }
+            continue;
+        }
+        // a heading is always a block of its own, even without blank lines around it
+        if parse_heading(line).is_some(){
+            if !current.is_empty(){
+                blocks.push(current.join("\n"));
+                current.clear();
+            }
+            blocks.push(line.to_string());
+            continue;
+        }
+        current.push(line);
+    }
+    if !current.is_empty(){
+        blocks.push(current.join("\n"));
+    }
+    blocks
+}
+
+///
+/// `## Some Heading` -> (2, "Some Heading")
+///
+fn parse_heading(block: &str) -> Option<(usize, String)>{
+    if block.contains('\n'){
+        return None;
+    }
+    let level = block.chars().take_while(|c| *c == '#').count();
+    if level == 0 || level > 6{
+        return None;
+    }
+    let text = &block[level..];
+    if !text.starts_with(' ') && !text.is_empty(){
+        return None;
+    }
+    let text = text.trim().trim_end_matches('#').trim();
+    Some((level, text.to_string()))
+}
+
+///
+/// A block that's nothing but an image: `![alt](src)` -> (alt, src)
+///
+fn parse_image(block: &str) -> Option<(String, String)>{
+    let block = block.trim();
+    let (alt, src, length) = parse_image_at(block)?;
+    match length == block.len(){
+        true => Some((alt, src)),
+        false => None,
+    }
+}
+
+///
+/// An image at the very start of `text`: (alt, src, how long the whole `![alt](src "title")` is)
+///
+fn parse_image_at(text: &str) -> Option<(String, String, usize)>{
+    let rest = text.strip_prefix("![")?;
+    let alt_end = rest.find("](")?;
+    let alt = &rest[..alt_end];
+    let target = &rest[alt_end + 2..];
+    let target_end = target.find(')')?;
+    // ![alt](src "title"): we only want the src
+    let src = target[..target_end].split_whitespace().next().unwrap_or("");
+    Some((alt.to_string(), src.to_string(), 2 + alt_end + 2 + target_end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_query_on_image_links() {
+        let source = std::env::temp_dir().join(format!("cardchapter-import-{}", std::process::id()));
+        let deck = source.join("deck");
+        std::fs::create_dir_all(&deck).unwrap();
+        std::fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/example/example/assets/bee.jpg"), source.join("bee.jpg")).unwrap();
+        std::fs::write(source.join("bees.md"), "# Bees\n\n![small](bee.jpg?width=100)\n\n![big](bee.jpg)\n").unwrap();
+
+        import(&source.join("bees.md"), &deck, "Alice").unwrap();
+        let content = std::fs::read_to_string(deck.join("content.yml")).unwrap();
+        // (an image on its own is an image card)
+        assert!(content.contains("image: assets/bee.jpg?width=100\n"), "{}", content);
+        assert!(content.contains("image: assets/bee.jpg\n"), "{}", content);
+        assert!(deck.join("assets/bee.jpg").is_file());
+        assert!(!deck.join("assets/bee-2.jpg").exists());
+    }
+
+    #[test]
+    fn a_failed_import_leaves_the_directory_alone() {
+        let source = std::env::temp_dir().join(format!("cardchapter-import-failed-{}", std::process::id()));
+        let deck = source.join("deck");
+        std::fs::create_dir_all(&deck).unwrap();
+        std::fs::write(source.join("empty.md"), "\n\n").unwrap();
+
+        assert!(import(&source.join("empty.md"), &deck, "Alice").is_err());
+        let leftovers: Vec<_> = std::fs::read_dir(&deck).unwrap().collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+}
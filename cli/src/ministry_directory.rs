@@ -2,11 +2,14 @@ use std::path::{Path, PathBuf};
 use anyhow::{Result, anyhow};
 use serde::Serialize;
 use yaml_rust2::YamlLoader;
-use image::{ImageReader, imageops, DynamicImage, ImageBuffer};
-use webp::Encoder;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 
 use slugify::slugify;
 
+use crate::asset_references;
+use crate::content_file::{self, CardContent, ContentFile};
+
 #[derive(Debug, Serialize, Clone)]
 pub struct DeckMetadata{
     // title & author are non-optional
@@ -56,6 +59,18 @@ pub struct DeckSummary{
     pub last_update_time: std::time::SystemTime,
 }
 
+///
+/// Everything a deck is built out of: as long as none of these change, neither does the deck
+///
+#[derive(Debug, Clone, Default)]
+pub struct Dependencies{
+    // the deck directory itself (publishing swaps it out), both content.yml and content.md (whichever exists wins),
+    //  everything they include, and the deck's image (the metadata only mentions it if it exists)
+    pub files: Vec<PathBuf>,
+    // pngs directories: adding or removing a frame changes the card
+    pub directories: Vec<PathBuf>,
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct Card{
     pub id: String,
@@ -145,43 +160,54 @@ pub struct TableOfContentsEntry{
 
 pub struct MinistryDirectory{
     directory_root: String,
+    // the directory all the decks are in: the shared common/ lives here, and the deck at the very top doesn't have to be named after itself
+    content_root: PathBuf,
 }
 
 // the default content.yml file
 const CONTENT_YML: &str = include_str!("content.yml");
+// ... and the same deck as a content.md
+const CONTENT_MD: &str = include_str!("content.md");
 
 impl MinistryDirectory{
     pub fn new(directory_root: String) -> MinistryDirectory{
+        MinistryDirectory::in_content_root(directory_root, Path::new("."))
+    }
+
+    pub fn in_content_root(directory_root: String, content_root: &Path) -> MinistryDirectory{
         MinistryDirectory{
-            directory_root: directory_root,
+            directory_root,
+            content_root: content_root.to_path_buf(),
         }
     }
 
-    pub fn init(&self, force: bool) -> Result<()>{
+    pub fn init(&self, force: bool, markdown: bool) -> Result<()>{
         if self.exists() {
             println!("This directory already contains a deck!");
             if force {
                 println!("Forcing re-initialization...");
-                return self.create();
+                return self.create(markdown);
             }
-            return Ok(());
+            Ok(())
         }
         else{
-            return self.create();
+            self.create(markdown)
         }
     }
 
-    fn create(&self) -> Result<()>{
+    fn create(&self, markdown: bool) -> Result<()>{
         println!("Creating a new deck...");
 
-        // write content_yml to the directory root
-        let content_path = PathBuf::from(&self.directory_root).join("content.yml");
-        println!("✅ {}", content_path.to_str().unwrap_or_else(|| ""));
-        std::fs::write(content_path, CONTENT_YML)?;
+        // write content_yml (or content.md) to the directory root
+        let (content_file_name, content_template) = content_template(markdown);
+        let content_path = PathBuf::from(&self.directory_root).join(content_file_name);
+        self.remove_other_content_files(content_file_name)?;
+        println!("✅ {}", content_path.to_str().unwrap_or(""));
+        std::fs::write(content_path, content_template)?;
 
         // create the assets directory
         let assets_path = PathBuf::from(&self.directory_root).join("assets");
-        println!("✅ {}", assets_path.to_str().unwrap_or_else(|| ""));
+        println!("✅ {}", assets_path.to_str().unwrap_or(""));
         if Path::new(&assets_path).exists(){
         }
         else{
@@ -191,45 +217,47 @@ impl MinistryDirectory{
         // the default bee.jpg file
         let content_bee = include_bytes!("bee.jpg");
         let bee_path = PathBuf::from(&self.directory_root).join("assets/bee.jpg");
-        println!("✅ {}", bee_path.to_str().unwrap_or_else(|| ""));
+        println!("✅ {}", bee_path.to_str().unwrap_or(""));
         std::fs::write(bee_path, content_bee)?;
 
         // the default favicon.png
         let content_favicon = include_bytes!("favicon.png");
         let favicon_path = PathBuf::from(&self.directory_root).join("assets/favicon.png");
-        println!("✅ {}", favicon_path.to_str().unwrap_or_else(|| ""));
+        println!("✅ {}", favicon_path.to_str().unwrap_or(""));
         std::fs::write(favicon_path, content_favicon)?;
 
         Ok(())
     }
 
-    pub fn init_with_name(&self, force: bool, name: String, author: String) -> Result<()>{
+    pub fn init_with_name(&self, force: bool, markdown: bool, name: String, author: String) -> Result<()>{
         if self.exists() {
             println!("This directory already contains a deck!");
             if force {
                 println!("Forcing re-initialization...");
-                return self.create_with_name(name, author);
+                return self.create_with_name(markdown, name, author);
             }
-            return Ok(());
+            Ok(())
         }
         else{
-            return self.create_with_name(name, author);
+            self.create_with_name(markdown, name, author)
         }
     }
 
-    fn create_with_name(&self, name: String, author: String) -> Result<()>{
+    fn create_with_name(&self, markdown: bool, name: String, author: String) -> Result<()>{
         println!("Creating a new deck...");
 
-        // write content_yml to the directory root
-        let content_path = PathBuf::from(&self.directory_root).join("content.yml");
-        println!("✅ {}", content_path.to_str().unwrap_or_else(|| ""));
-        let content_yml = CONTENT_YML.replace("NAMENAMENAME", &name).replace("AUTHORAUTHORAUTHOR", &author);
+        // write content_yml (or content.md) to the directory root
+        let (content_file_name, content_template) = content_template(markdown);
+        let content_path = PathBuf::from(&self.directory_root).join(content_file_name);
+        self.remove_other_content_files(content_file_name)?;
+        println!("✅ {}", content_path.to_str().unwrap_or(""));
+        let content_yml = content_template.replace("NAMENAMENAME", &name).replace("AUTHORAUTHORAUTHOR", &author);
         println!("{}", content_yml);
         std::fs::write(content_path, content_yml)?;
 
         // create the assets directory
         let assets_path = PathBuf::from(&self.directory_root).join("assets");
-        println!("✅ {}", assets_path.to_str().unwrap_or_else(|| ""));
+        println!("✅ {}", assets_path.to_str().unwrap_or(""));
         if Path::new(&assets_path).exists(){
         }
         else{
@@ -239,21 +267,35 @@ impl MinistryDirectory{
         // the default bee.jpg file
         let content_bee = include_bytes!("bee.jpg");
         let bee_path = PathBuf::from(&self.directory_root).join("assets/bee.jpg");
-        println!("✅ {}", bee_path.to_str().unwrap_or_else(|| ""));
+        println!("✅ {}", bee_path.to_str().unwrap_or(""));
         std::fs::write(bee_path, content_bee)?;
 
         // the default favicon.png
         let content_favicon = include_bytes!("favicon.png");
         let favicon_path = PathBuf::from(&self.directory_root).join("assets/favicon.png");
-        println!("✅ {}", favicon_path.to_str().unwrap_or_else(|| ""));
+        println!("✅ {}", favicon_path.to_str().unwrap_or(""));
         std::fs::write(favicon_path, content_favicon)?;
 
         Ok(())
     }
 
+    ///
+    /// Re-initializing as content.md when there's a content.yml (or the other way around): the old one would win, so it goes
+    ///
+    fn remove_other_content_files(&self, content_file_name: &str) -> Result<()>{
+        for file_name in content_file::CONTENT_FILE_NAMES.iter().filter(|file_name| **file_name != content_file_name){
+            let path = PathBuf::from(&self.directory_root).join(file_name);
+            if path.exists(){
+                println!("🗑  {}", path.to_str().unwrap_or(""));
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn exists(&self) -> bool{
         // Check if the directory exists,
-        //  if it does, check if it contains content.yml
+        //  if it does, check if it contains content.yml (or content.md)
         //  if it does, check if it contains .ministry
         //  if it does, check if it contains assets
 
@@ -262,9 +304,7 @@ impl MinistryDirectory{
             return false
         }
         let directory_root = path.to_str().unwrap();
-        let content_path_location = PathBuf::from(&directory_root).join("content.yml");
-        let content_path = Path::new(&content_path_location);
-        if !content_path.exists(){
+        if !self.get_content_path().exists(){
             return false
         }
         let assets_path_location = PathBuf::from(&directory_root).join("assets");
@@ -276,39 +316,79 @@ impl MinistryDirectory{
         true
     }
 
+    ///
+    /// The server this deck publishes to, as recorded in its .ministry file
+    ///
+    pub fn get_publish_server(&self) -> Option<url::Url>{
+        let ministry_path = PathBuf::from(&self.directory_root).join(".ministry");
+        let ministry_string = std::fs::read_to_string(ministry_path).ok()?;
+        let yaml = YamlLoader::load_from_str(&ministry_string).ok()?;
+        let server = yaml.first()?["server"].as_str()?;
+        url::Url::parse(server).ok()
+    }
+
+    pub fn set_publish_server(&self, server_url: &url::Url) -> Result<()>{
+        let ministry_path = PathBuf::from(&self.directory_root).join(".ministry");
+        let existing = std::fs::read_to_string(&ministry_path).unwrap_or_default();
+        // keep anything else that's in there
+        let mut lines: Vec<String> = existing.lines()
+            .filter(|line| !line.starts_with("server:"))
+            .map(|line| line.to_string())
+            .collect();
+        lines.push(format!("server: {}", server_url));
+        std::fs::write(ministry_path, lines.join("\n") + "\n")?;
+        Ok(())
+    }
+
+    ///
+    /// content.yml, or content.md if that's what the deck's written in
+    ///
+    pub fn get_content_path(&self) -> PathBuf{
+        let directory_root = PathBuf::from(&self.directory_root);
+        content_file::CONTENT_FILE_NAMES.iter()
+            .map(|file_name| directory_root.join(file_name))
+            .find(|content_path| content_path.exists())
+            .unwrap_or_else(|| directory_root.join(content_file::CONTENT_FILE_NAMES[0]))
+    }
+
     pub fn _get_content(&self) -> Result<String>{
-        let content_path = PathBuf::from(&self.directory_root).join("content.yml");
+        let content_path = self.get_content_path();
         let content = std::fs::read_to_string(content_path)?;
         Ok(content)
     }
 
+    ///
+    /// content.yml (or content.md), deserialized: errors point at the file, line, column and field that's wrong
+    ///
+    pub fn get_content_file(&self) -> Result<ContentFile>{
+        content_file::load(&self.get_content_path())
+    }
+
     pub fn get_metadata(&self) -> Result<DeckMetadata>{
         // what's a DeckMetadata?
-        let content_string = self._get_content()?;
-        let yaml = YamlLoader::load_from_str(&content_string)?;
-        let doc = &yaml[0];
-
-        let name = doc["name"].as_str().unwrap_or_else(|| "");
-        let title = doc["title"].as_str().unwrap_or_else(|| "");
-        let name_or_title = if name == "" { title } else { name };
-        if name_or_title == "" {
+        let doc = self.get_content_file()?;
+
+        let name = doc.name.as_deref().unwrap_or("");
+        let title = doc.title.as_deref().unwrap_or("");
+        let name_or_title = if name.is_empty() { title } else { name };
+        if name_or_title.is_empty() {
             return Err(anyhow!("No name or title found: this is a mandatory field"));
         }
-        let author = doc["author"].as_str().unwrap_or_else(|| "");
-        if author == "" {
+        let author = doc.author.as_deref().unwrap_or("");
+        if author.is_empty() {
             return Err(anyhow!("No author found: this is a mandatory field"));
         }
 
-        let image_url = match doc["image"].as_str(){
+        let image_url = match doc.image.as_deref(){
             Some(image_url) => {
                 // test for the existence of image_url as a file
                 let image_path = PathBuf::from(&self.directory_root).join(image_url);
                 if Path::new(&image_path).exists() {
-                    println!("Image exists: {}", image_path.to_str().unwrap_or_else(|| ""));
+                    println!("Image exists: {}", image_path.to_str().unwrap_or(""));
                     Some(image_url.to_string())
                 }
                 else{
-                    println!("Image does not exist: {}", image_path.to_str().unwrap_or_else(|| ""));
+                    println!("Image does not exist: {}", image_path.to_str().unwrap_or(""));
                     None
                 }
             }
@@ -318,95 +398,107 @@ impl MinistryDirectory{
         let slug = slugify!(name_or_title);
         let author_slug = slugify!(author);
 
-        if !(self.directory_root.to_string().ends_with(&slug)) && !(self.directory_root.to_string() == ".") {
+        let is_content_root = Path::new(&self.directory_root) == self.content_root;
+        if !(self.directory_root.to_string().ends_with(&slug)) && !is_content_root {
             println!("Directory root does not match slug: {} != {}", self.directory_root, slug);
             return Err(anyhow!("Directory root does not match slug - please move the directory to the correct location: {}", slug));
         }
-        if !(self.directory_root.to_string().contains(&author_slug)) && !(self.directory_root.to_string() == ".") {
+        if !(self.directory_root.to_string().contains(&author_slug)) && !is_content_root {
             println!("Directory root does not match slug: {} != {}", self.directory_root, author_slug);
             return Err(anyhow!("Directory root does not match slug - please move the directory to the correct location: {}", author_slug));
         }
 
-        let mut container_class = Vec::new();
-        let default_vec = Vec::new();
-        if doc["container_class"].as_str().is_some(){
-            container_class.push(doc["container_class"].as_str().unwrap().to_string());
-        }
-        for item in doc["container_class"].as_vec().unwrap_or_else(|| &default_vec){
-            if item.as_str().is_some(){
-                container_class.push(item.as_str().unwrap().to_string());
-            }
-        }
-
-        let mut extra_class = Vec::new();
-        if doc["class"].as_str().is_some(){
-            extra_class.push(doc["class"].as_str().unwrap().to_string());
-        }
-        for item in doc["class"].as_vec().unwrap_or_else(|| &default_vec){
-            if item.as_str().is_some(){
-                extra_class.push(item.as_str().unwrap().to_string());
-            }
-        }
-
-        let last_update_time = self.get_last_update_time()?;
+        let last_update_time = last_update_time(&self.get_source_files_of(&doc))?;
 
         let dm = DeckMetadata{
             title: name_or_title.to_string(),
             slug,
             author: author.to_string(),
             author_slug,
-            author_link: doc["author_link"].as_str().map(|s| s.to_string()),
-            favicon: doc["favicon"].as_str().map(|s| s.to_string()),
-            description: doc["description"].as_str().map(|s| s.to_string()),
-            image_url: image_url,
-            locale: doc["locale"].as_str().map(|s| s.to_string()),
-            extra_header: doc["extra_header"].as_str().map(|s| s.to_string()),
-            hidden: doc["hidden"].as_bool().unwrap_or(false),
-            unlisted: doc["unlisted"].as_bool().unwrap_or(false),
-            mp3: doc["mp3"].as_str().map(|s| s.to_string()),
-            audio_guide: doc["audio_guide"].as_bool().unwrap_or(false),
-            container_class,
-            extra_class,
+            author_link: doc.author_link.clone(),
+            favicon: doc.favicon.clone(),
+            description: doc.description.clone(),
+            image_url,
+            locale: doc.locale.clone(),
+            extra_header: doc.extra_header.clone(),
+            hidden: doc.hidden.unwrap_or(false),
+            unlisted: doc.unlisted.unwrap_or(false),
+            mp3: doc.mp3.clone(),
+            audio_guide: doc.audio_guide.unwrap_or(false),
+            container_class: doc.container_class.clone(),
+            extra_class: doc.class.clone(),
             last_update_time,
         };
         Ok(dm)
     }
 
-    pub fn get_last_update_time(&self) -> Result<std::time::SystemTime>{
-        let content_path = PathBuf::from(&self.directory_root).join("content.yml");
-        let metadata = std::fs::metadata(content_path)?;
-        let modified = metadata.modified()?;
-        Ok(modified)
+    ///
+    /// content.yml (or content.md), and every file it `include:`s
+    ///
+    pub fn get_source_files(&self) -> Result<Vec<PathBuf>>{
+        Ok(self.get_source_files_of(&self.get_content_file()?))
+    }
+
+    fn get_source_files_of(&self, doc: &ContentFile) -> Vec<PathBuf>{
+        let mut source_files = vec![self.get_content_path()];
+        source_files.extend(doc.included_files.iter().cloned());
+        source_files
+    }
+
+    ///
+    /// Every file and directory that get_metadata and get_deck read
+    ///
+    pub fn get_dependencies(&self) -> Result<Dependencies>{
+        let doc = self.get_content_file()?;
+        let directory_root = PathBuf::from(&self.directory_root);
+
+        let mut files = vec![directory_root.clone()];
+        files.extend(content_file::CONTENT_FILE_NAMES.iter().map(|file_name| directory_root.join(file_name)));
+        files.extend(doc.included_files.iter().cloned());
+        if let Some(image) = &doc.image{
+            files.push(directory_root.join(image));
+        }
+
+        let mut directories = Vec::new();
+        let mut cards: Vec<&CardContent> = doc.pages.iter().flatten().collect();
+        while let Some(card) = cards.pop(){
+            if let Some(pngs) = &card.pngs{
+                directories.push(directory_root.join(pngs));
+            }
+            cards.extend([&card.pages, &card.stack].into_iter().flatten().flatten());
+        }
+
+        Ok(Dependencies{
+            files,
+            directories,
+        })
     }
 
-    fn parse_card(&self, doc: &yaml_rust2::Yaml, default_id: String) -> Card{
-        let id = doc["id"].as_str().unwrap_or_else(|| &default_id).to_string();
+    fn parse_card(&self, doc: &CardContent, default_id: String) -> Card{
+        let id = doc.id.as_deref().unwrap_or(&default_id).to_string();
         let id = slugify!(&id);
-        let mut card_type = doc["type"].as_str().unwrap_or_else(|| "").to_string();
+        let mut card_type = doc.card_type.map(|card_type| card_type.as_str().to_string()).unwrap_or_default();
 
-        let mut content: Option<String> = doc["content"].as_str().map(|s| s.to_string());
+        let mut content: Option<String> = doc.content.clone();
 
-        if card_type == "" {
-            if doc["content"].as_str().is_some(){
+        if card_type.is_empty() {
+            if doc.content.is_some(){
                 card_type = "markdown".to_string();
             }
-            else if doc["animated_text"].as_str().is_some(){
+            else if doc.animated_text.is_some(){
                 card_type = "animated_text".to_string();
-                content = doc["animated_text"].as_str().map(|s| s.to_string());
+                content = doc.animated_text.clone();
             }
-            else if doc["image"].as_str().is_some(){
+            else if doc.image.is_some(){
                 card_type = "image".to_string();
             }
-            else if doc["video"].as_str().is_some(){
+            else if doc.video.is_some(){
                 card_type = "video".to_string();
             }
-            else if doc["pngs"].as_str().is_some(){
+            else if doc.pngs.is_some(){
                 card_type = "pngs".to_string();
             }
-            else if doc["stack"].as_vec().is_some(){
-                card_type = "stack".to_string();
-            }
-            else if doc["pages"].as_vec().is_some(){
+            else if doc.stack.is_some() || doc.pages.is_some(){
                 card_type = "stack".to_string();
             }
             else{
@@ -419,41 +511,30 @@ impl MinistryDirectory{
         if card_type == "stack"{
 
             // the card has multiple cards in it
-            let mut counter = 0;
-            doc["pages"].as_vec().map(|list| {
-                for item in list
-                {
-                    let counter_string = counter.to_string();
-                    let id = format!("{}-{}", id, counter_string);
-                    stack.push(
-                        self.parse_card(&item, id)
-                    );
-                    counter += 1;
-                }
-            });
-            doc["stack"].as_vec().map(|list| {
-                for item in list
-                {
-                    let counter_string = counter.to_string();
-                    let id = format!("{}-{}", id, counter_string);
-                    stack.push(
-                        self.parse_card(&item, id)
-                    );
-                    counter += 1;
-                }
-            });
+            for (counter, item) in [&doc.pages, &doc.stack].into_iter().flatten().flatten().enumerate(){
+                let id = format!("{}-{}", id, counter);
+                stack.push(
+                    self.parse_card(item, id)
+                );
+            }
         }
 
         let mut pngs = Vec::new();
         if card_type == "pngs" {
-            let directory = doc["pngs"].as_str().unwrap_or_else(|| "");
-            if directory != "" {
+            let directory = doc.pngs.as_deref().unwrap_or("");
+            if !directory.is_empty() {
                 let path = PathBuf::from(&self.directory_root).join(directory);
                 // every file in the directory
-                let paths = std::fs::read_dir(path).unwrap();
+                let paths: Vec<PathBuf> = match std::fs::read_dir(&path){
+                    Ok(paths) => paths.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+                    Err(err) => {
+                        // `cardchapter check` reports this properly: here we just carry on without any frames
+                        println!("Could not read pngs directory {}: {}", path.to_str().unwrap_or(""), err);
+                        Vec::new()
+                    }
+                };
                 for path in paths {
-                    let path = path.unwrap().path();
-                    let path = path.to_str().unwrap_or_else(|| "");
+                    let path = path.to_str().unwrap_or("");
                     if path.ends_with(".png") {
                         let png = path.replacen(&self.directory_root, "", 1).replace("\\", "/").to_string();
                         let png = png.trim_start_matches("/").to_string();
@@ -465,344 +546,228 @@ impl MinistryDirectory{
             }
         }
 
-        let mut extra_class = Vec::new();
-        let default_vec = Vec::new();
-        if doc["class"].as_str().is_some(){
-            extra_class.push(doc["class"].as_str().unwrap().to_string());
-        }
-        for item in doc["class"].as_vec().unwrap_or_else(|| &default_vec){
-            if item.as_str().is_some(){
-                extra_class.push(item.as_str().unwrap().to_string());
-            }
-        }
-
-        let mut container_class = Vec::new();
-        if doc["container_class"].as_str().is_some(){
-            container_class.push(doc["container_class"].as_str().unwrap().to_string());
-        }
-        for item in doc["container_class"].as_vec().unwrap_or_else(|| &default_vec){
-            if item.as_str().is_some(){
-                container_class.push(item.as_str().unwrap().to_string());
-            }
-        }
-
-        let mut document_class = Vec::new();
-        if doc["document_class"].as_str().is_some(){
-            document_class.push(doc["document_class"].as_str().unwrap().to_string());
-        }
-        for item in doc["document_class"].as_vec().unwrap_or_else(|| &default_vec){
-            if item.as_str().is_some(){
-                document_class.push(item.as_str().unwrap().to_string());
-            }
-        }
-
-        let dolly_in: Option<f64>;
-        if doc["dolly_in"].as_i64().is_some(){
-            dolly_in = Some(doc["dolly_in"].as_i64().unwrap() as f64);
-        }
-        else{
-            dolly_in = doc["dolly_in"].as_f64();
-        }
-        let dolly_out: Option<f64>;
-        if doc["dolly_out"].as_i64().is_some(){
-            dolly_out = Some(doc["dolly_out"].as_i64().unwrap() as f64);
-        }
-        else{
-            dolly_out = doc["dolly_out"].as_f64();
-        }
-        let scale: Option<f64>;
-        if doc["scale"].as_i64().is_some(){
-            scale = Some(doc["scale"].as_i64().unwrap() as f64);
-        }
-        else{
-            scale = doc["scale"].as_f64();
-        }
-
         if card_type == "animated_text"{
             content = content.map(|s| s.replace("\n", "<br />"));
         }
 
         Card{
             id,
-            title: doc["title"].as_str().map(|s| s.to_string()),
+            title: doc.title.clone(),
             card_type,
-            extra_class,
-            container_class,
-            document_class,
+            extra_class: doc.class.clone(),
+            container_class: doc.container_class.clone(),
+            document_class: doc.document_class.clone(),
 
             content,
-            footnote: doc["footnote"].as_str().map(|s| s.to_string()),
+            footnote: doc.footnote.clone(),
 
-            image_url: doc["image"].as_str().map(|s| s.to_string()),
-            wide: doc["wide"].as_bool().unwrap_or(false),
-            tall: doc["tall"].as_bool().unwrap_or(false),
+            image_url: doc.image.clone(),
+            wide: doc.wide.unwrap_or(false),
+            tall: doc.tall.unwrap_or(false),
 
-            video_url: doc["video"].as_str().map(|s| s.to_string()),
-            video_has_sound: doc["video_has_sound"].as_bool().unwrap_or(false),
-            video_controls: doc["video_controls"].as_bool().unwrap_or(false),
+            video_url: doc.video.clone(),
+            video_has_sound: doc.video_has_sound.unwrap_or(false),
+            video_controls: doc.video_controls.unwrap_or(false),
 
-            is_loop: doc["loop"].as_bool().unwrap_or(false),
+            is_loop: doc.is_loop.unwrap_or(false),
 
             pngs,
-            pngs_fps: doc["pngs_fps"].as_i64(),
-
-            fade_in: doc["fade_in"].as_i64(),
-            fade_out: doc["fade_out"].as_i64(),
-            shake: doc["shake"].as_i64(),
-            vertical_shake: doc["vertical_shake"].as_i64(),
-            jitter: doc["jitter"].as_i64(),
-            vertical_jitter: doc["vertical_jitter"].as_i64(),
-            pan_left: doc["pan_left"].as_i64(),
-            pan_right: doc["pan_right"].as_i64(),
-            pan_up: doc["pan_up"].as_i64(),
-            pan_down: doc["pan_down"].as_i64(),
-            push_left: doc["push_left"].as_i64(),
-            push_right: doc["push_right"].as_i64(),
-            push_up: doc["push_up"].as_i64(),
-            push_down: doc["push_down"].as_i64(),
-            dolly_in,
-            dolly_out,
-            scale,
-            spin_clockwise: doc["spin_clockwise"].as_i64(),
-
-            duration: doc["duration"].as_i64(),
-            amount: doc["amount"].as_i64(),
-            delay: doc["delay"].as_i64(),
-            easing: doc["easing"].as_str().map(|s| s.to_string()),
-            animate_container: doc["animate_container"].as_bool(),
-
-            next: doc["next"].as_i64(),
+            pngs_fps: doc.pngs_fps,
+
+            fade_in: doc.fade_in,
+            fade_out: doc.fade_out,
+            shake: doc.shake,
+            vertical_shake: doc.vertical_shake,
+            jitter: doc.jitter,
+            vertical_jitter: doc.vertical_jitter,
+            pan_left: doc.pan_left,
+            pan_right: doc.pan_right,
+            pan_up: doc.pan_up,
+            pan_down: doc.pan_down,
+            push_left: doc.push_left,
+            push_right: doc.push_right,
+            push_up: doc.push_up,
+            push_down: doc.push_down,
+            dolly_in: doc.dolly_in,
+            dolly_out: doc.dolly_out,
+            scale: doc.scale,
+            spin_clockwise: doc.spin_clockwise,
+
+            duration: doc.duration,
+            amount: doc.amount,
+            delay: doc.delay,
+            easing: doc.easing.clone(),
+            animate_container: doc.animate_container,
+
+            next: doc.next,
 
             stack,
 
-            toc_depth: doc["depth"].as_i64(),
+            toc_depth: doc.depth,
         }
     }
 
     pub fn get_deck(&self) -> Result<Vec<Card>>{
-        let content_string = self._get_content()?;
-        let yaml = YamlLoader::load_from_str(&content_string)?;
-        let doc = &yaml[0];
-        let list = match doc["pages"].as_vec() {
+        let doc = self.get_content_file()?;
+        let list = match &doc.pages {
             Some(list) => list,
             None => return Err(anyhow!("No content found")),
         };
 
         let mut deck = Vec::new();
-        let mut counter = 0;
-        for item in list {
-            let counter_string = counter.to_string();
+        for (counter, item) in list.iter().enumerate() {
             deck.push(
-                self.parse_card(&item, counter_string)
+                self.parse_card(item, counter.to_string())
             );
-            counter += 1;
         }
 
         Ok(deck)
     }
 
+    ///
+    /// Every file in assets/ that the deck links to, keyed by its path (`assets/florb/Florb_00001.png`)
+    ///  (not the ones it doesn't link to, or hidden ones: nobody can see those)
+    ///
+    pub fn get_asset_files(&self) -> Result<BTreeMap<String, PathBuf>>{
+        let mut files = BTreeMap::new();
+        for reference in asset_references::find_in_deck(&self.get_metadata()?, &self.get_deck()?){
+            let hidden = reference.asset_path.components().any(|component| component.as_os_str().to_str().unwrap_or(".").starts_with('.'));
+            let path = self.get_asset_path(&reference.asset_path);
+            if hidden || !path.is_file(){
+                continue;
+            }
+            let relative_path = format!("assets/{}", reference.asset_path.to_str().unwrap_or("").replace("\\", "/"));
+            files.insert(relative_path, path);
+        }
+        Ok(files)
+    }
+
+    ///
+    /// A sha256 of every file in assets/ that the deck links to, keyed by its path: what a server's manifest says, for a local deck
+    ///
+    pub fn get_asset_hashes(&self) -> Result<BTreeMap<String, String>>{
+        self.get_asset_files()?.into_iter()
+            .map(|(relative_path, path)| Ok((relative_path, sha256_file(&path)?)))
+            .collect()
+    }
+
     pub fn get_asset_path(&self, asset_path: &std::path::PathBuf) -> PathBuf{
         PathBuf::from(&self.directory_root).join("assets").join(asset_path)
     }
 
     pub fn get_alternate_asset_paths(&self, asset_path: &std::path::PathBuf) -> Vec<PathBuf> {
-        let mut paths = Vec::new();
-        paths.push(self.get_asset_path(asset_path));
-        paths.push(PathBuf::from(&self.directory_root).join("common").join(asset_path));
-        paths.push(PathBuf::from(&self.directory_root).join("../common").join(asset_path));
-        paths.push(PathBuf::from(&self.directory_root).join("../common/assets").join(asset_path));
-        paths.push(PathBuf::from(&self.directory_root).join("../../common").join(asset_path));
-        paths.push(PathBuf::from(&self.directory_root).join("../../common/assets").join(asset_path));
-        paths.push(PathBuf::from("common").join(asset_path));
-        paths.push(PathBuf::from("common").join("assets").join(asset_path));
-        paths
+        vec![
+            self.get_asset_path(asset_path),
+            PathBuf::from(&self.directory_root).join("common").join(asset_path),
+            PathBuf::from(&self.directory_root).join("../common").join(asset_path),
+            PathBuf::from(&self.directory_root).join("../common/assets").join(asset_path),
+            PathBuf::from(&self.directory_root).join("../../common").join(asset_path),
+            PathBuf::from(&self.directory_root).join("../../common/assets").join(asset_path),
+            self.content_root.join("common").join(asset_path),
+            self.content_root.join("common").join("assets").join(asset_path),
+        ]
     }
 
-    pub async fn get_named_file(&self, asset_path: std::path::PathBuf, config: &crate::Config, file_directives: &crate::file_modifiers::FileDirectives) -> Result<rocket::fs::NamedFile>{
-        let original_asset_path = asset_path.clone();
-        let mut asset_path = self.get_asset_path(&asset_path.clone());
-
-        let filename_asset_path = asset_path.clone();
-
-        let filename = filename_asset_path.file_name().unwrap_or_else(|| std::ffi::OsStr::new("")).to_str().unwrap_or_else(|| "");
-
-        let alternate_paths = self.get_alternate_asset_paths(&original_asset_path.clone());
-
-        let mut path_exists = Path::new(&asset_path).exists();
-
-        if !path_exists {
-            for path in alternate_paths{
-                if Path::new(&path).exists(){
-                    asset_path = path;
-                    path_exists = true;
-                    break;
-                }
-            }
-            if !path_exists {
-                return Err(anyhow!("File not found: {}", asset_path.clone().to_str().unwrap_or("")));
-            }
-        }
+    ///
+    /// The file on disk that should be served for `asset_path`, given its original (which ContentRoot::find_asset found: never look for one any other way):
+    ///  images are converted to .webp (according to the file directives) and the path to the converted file is returned,
+    ///  everything else is returned as-is
+    ///
+    pub async fn convert_asset(&self, source_path: PathBuf, asset_path: &Path, config: &crate::Config, services: &crate::Services, file_directives: &crate::file_modifiers::FileDirectives) -> Result<PathBuf>{
+        let filename = asset_path.file_name().and_then(|filename| filename.to_str()).unwrap_or("");
 
         let do_not_modify_file = file_directives.unmodified.unwrap_or(false);
 
         if !do_not_modify_file && (filename.ends_with(".jpg") || filename.ends_with(".png") || filename.ends_with(".gif")){
             // so, we want to replace the file with a .webp
-            // but what if two different projects both have `bee.jpg`?
-            // let's do the conversion
-            let metadata = self.get_metadata()?;
-            let webp_filename = filename.replace(".jpg", ".webp")
-                                     .replace(".png", ".webp")
-                                     .replace(".gif", ".webp");
-            let webp_filename = format!("{}_{}_{}_{}", metadata.author_slug, metadata.slug, file_directives.to_string(), webp_filename);
-            let temp_directory = config.temporary_asset_directory.clone();
-            let webp_path = format!("{}/{}", temp_directory, webp_filename);
-
-            let lossless = filename.ends_with(".png");
-
-            let mut regenerate = false;
-            if Path::new(&webp_path).exists(){
-                // check if the file has been modified since the last conversion
-                let webp_modified = std::fs::metadata(&webp_path)?.modified()?;
-                let asset_modified = std::fs::metadata(&asset_path)?.modified()?;
-
-                if asset_modified > webp_modified{
-                    // the asset has been modified since the last conversion
-                    regenerate = true;
-                    // also: delete the existing file
-                    std::fs::remove_file(&webp_path)?;
-                }
-
-            }
-
-            // if the file doesn't exist, or if we need to regenerate it,
-            //   then put the webp file in our asset cache directory
-            if !Path::new(&webp_path).exists() || regenerate{
-                println!("Converting {} to {}", asset_path.to_str().unwrap_or(""), webp_path);
-                let mut img = ImageReader::open(asset_path)?.decode()?;
-                // create the temp directory if it doesn't exist
-
-                let mut max_width = config.max_width;
-                let w = img.width();
-                if file_directives.wide.unwrap_or(false) {
-                    max_width = w;
-                }
-                if file_directives.width.unwrap_or(0) > 0 {
-                    max_width = file_directives.width.unwrap_or(0);
-                }
-                let h = img.height();
-                let mut max_height = config.max_height;
-                if file_directives.tall.unwrap_or(false) {
-                    max_height = h;
-                }
-                if file_directives.height.unwrap_or(0) > 0 {
-                    max_height = file_directives.height.unwrap_or(0);
-                }
-
-                if w > max_width && h > max_height {
-                    if w > h {
-                        img = image::DynamicImage::ImageRgba8(imageops::resize(
-                            &img,
-                            max_width,
-                            (max_width as f64 * h as f64 / w as f64) as u32,
-                            imageops::FilterType::Lanczos3,
-                        ));
-                    }
-                    else{
-                        img = image::DynamicImage::ImageRgba8(imageops::resize(
-                            &img,
-                            (max_height as f64 * w as f64 / h as f64) as u32,
-                            max_height,
-                            imageops::FilterType::Lanczos3,
-                        ));
-                    }
-                }
-                else if w > max_width {
-                    img = image::DynamicImage::ImageRgba8(imageops::resize(
-                        &img,
-                        max_width,
-                        (max_width as f64 * h as f64 / w as f64) as u32,
-                        imageops::FilterType::Lanczos3,
-                    ));
-                }
-                else if h > max_height {
-                    img = image::DynamicImage::ImageRgba8(imageops::resize(
-                        &img,
-                        (max_height as f64 * w as f64 / h as f64) as u32,
-                        max_height,
-                        imageops::FilterType::Lanczos3,
-                    ));
-                }
-
-                if file_directives.grayscale.unwrap_or(false) {
-                    img = DynamicImage::ImageLumaA8(imageops::grayscale_alpha(&img));
-                    img = DynamicImage::ImageRgba8(img.to_rgba8());
-                }
-
-                if file_directives.color.is_some() {
-                    img = DynamicImage::ImageLumaA8(imageops::grayscale_alpha(&img));
-                    img = DynamicImage::ImageRgba8(img.to_rgba8());
-
-                    let (r, g, b) = file_directives.color().unwrap();
-
-                    let existing_image = img.into_rgba8();
-                    let e_width = existing_image.width();
-                    let e_height = existing_image.height();
-
-                    let buffer = ImageBuffer::from_fn(e_width, e_height, |x, y| {
-                        let pixel = existing_image.get_pixel(x, y);
-                        let mut pixel = pixel.0;
-                        pixel[0] = (r as u32 * (255-pixel[0] as u32) / 255) as u8;
-                        pixel[1] = (g as u32 * (255-pixel[1] as u32) / 255) as u8;
-                        pixel[2] = (b as u32 * (255-pixel[2] as u32) / 255) as u8;
-                        image::Rgba(pixel)
-                    });
-
-                    img = DynamicImage::ImageRgba8(buffer);
-                }
-
-                if file_directives.blur.unwrap_or(0.0) > 0.1 {
-                    let blur_amount = file_directives.blur.unwrap_or(5.0);
-                    img = DynamicImage::ImageRgba8(imageops::blur(&img, blur_amount));
-                }
-
-                if file_directives.flip_horizontal.unwrap_or(false) {
-                    img = DynamicImage::ImageRgba8(imageops::flip_horizontal(&img));
-                }
-
-                if file_directives.flip_vertical.unwrap_or(false) {
-                    img = DynamicImage::ImageRgba8(imageops::flip_vertical(&img));
-                }
-
-                if file_directives.flip_turnwise.unwrap_or(false) {
-                    img = DynamicImage::ImageRgba8(imageops::rotate180(&img));
-                }
-
-                if !Path::new(&temp_directory).exists(){
-                    std::fs::create_dir(temp_directory)?;
-                }
-                let enc = Encoder::from_image(&img).unwrap();
-                if lossless{
-                    std::fs::write(&webp_path, &*enc.encode_lossless())?;
-                }
-                else{
-                    std::fs::write(&webp_path, &*enc.encode(config.webp_quality))?;
-                }
-            }
-            else{
-                println!("Using existing {}", webp_path);
-            }
-            let opened_file = rocket::fs::NamedFile::open(webp_path).await?;
-
-            Ok(opened_file)
+            //  (converted files are named after what's in them rather than where they came from:
+            //   so two different projects with two different `bee.jpg`s don't trip over each other)
+            services.images.convert(&source_path, config, file_directives).await
         }
         else{
             // send it anyway
             //  earlier, I had a plan to only send files from an approved list of file extension or mimetypes
             //  but, remember, this is a content server for just ME, right?
             //  anyways, file extension is not a secure way to determine file type
-            let opened_file = rocket::fs::NamedFile::open(asset_path).await?;
+            Ok(source_path)
+        }
+    }
+}
 
-            Ok(opened_file)
+///
+/// Walk a content root looking for decks: every `<author_slug>/<deck_slug>` directory that contains a deck
+///
+pub fn find_deck_paths(root: &Path) -> Result<Vec<PathBuf>>{
+    let mut deck_paths = Vec::new();
+
+    for author_path in std::fs::read_dir(root)?{
+        let author_path = author_path?.path();
+        if is_hidden(&author_path){
+            continue;
+        }
+        let str_path = author_path.to_str().unwrap_or("");
+        if str_path == "." || str_path.ends_with(".") || str_path.ends_with(".git") || str_path.ends_with("temp_assets") ||
+                str_path.ends_with("node_modules") || str_path.ends_with("assets") || str_path.ends_with("src") || str_path.ends_with("target") {
+            continue;
+        }
+        if !author_path.is_dir(){
+            continue;
+        }
+        // if it's a directory, that's a _user_ directory: so "author_slug" is the name of the directory
+        // for each subdirectory of that directory...
+        for deck_path in std::fs::read_dir(&author_path)?{
+            let deck_path = deck_path?.path();
+            if is_hidden(&deck_path){
+                // in-progress uploads and old versions of published decks
+                continue;
+            }
+            let str_deck_path = deck_path.to_str().unwrap_or("");
+            if str_deck_path == "." || str_deck_path.ends_with(".") || str_deck_path.ends_with(".git") || str_deck_path.ends_with("temp_assets") ||
+                    str_deck_path.ends_with("node_modules") || str_deck_path.ends_with("assets") {
+                continue;
+            }
+            // if it's a directory, that's a _deck_ directory: so "deck_slug" is the name of the directory
+            if deck_path.is_dir() && MinistryDirectory::new(str_deck_path.to_string()).exists(){
+                deck_paths.push(deck_path);
+            }
         }
     }
-}
\ No newline at end of file
+    deck_paths.sort();
+
+    Ok(deck_paths)
+}
+
+fn content_template(markdown: bool) -> (&'static str, &'static str){
+    match markdown{
+        true => ("content.md", CONTENT_MD),
+        false => ("content.yml", CONTENT_YML),
+    }
+}
+
+///
+/// The most recent modification time of any of `files`
+///
+pub fn last_update_time(files: &[PathBuf]) -> Result<std::time::SystemTime>{
+    let mut last_update_time = std::time::UNIX_EPOCH;
+    for file in files{
+        last_update_time = last_update_time.max(std::fs::metadata(file)?.modified()?);
+    }
+    Ok(last_update_time)
+}
+
+fn is_hidden(path: &Path) -> bool{
+    path.file_name().and_then(|name| name.to_str()).map(|name| name.starts_with('.')).unwrap_or(false)
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String{
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+///
+/// sha256_hex of a file's contents, a bit at a time (some of them are videos)
+///
+pub fn sha256_file(path: &Path) -> Result<String>{
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
@@ -1,103 +1,352 @@
-use crate::ministry_directory::{MinistryDirectory, DeckMetadata};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::ministry_directory::{MinistryDirectory, DeckMetadata, Dependencies};
 use anyhow::Result;
 use crate::ministry_directory::Card;
 use moka::future::Cache;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 
-#[derive(Clone)]
-struct CachedDeckMetadata {
-    metadata: DeckMetadata,
-    last_updated: std::time::SystemTime,
+// a cached deck is good until the filesystem tells us that one of its dependencies
+//  (content.yml, everything it includes, its pngs directories...) has changed:
+//  so a cache hit never has to touch the disk
+
+#[derive(Default)]
+struct Dependents {
+    // file -> the decks that are built out of it
+    files: HashMap<PathBuf, HashSet<String>>,
+    // directory -> the decks that list what's in it
+    directories: HashMap<PathBuf, HashSet<String>>,
+    // directories we've asked the watcher about
+    watched: HashSet<PathBuf>,
 }
 
-#[derive(Clone)]
-struct CachedDeck {
-    deck: Vec<Card>,
-    last_updated: std::time::SystemTime,
+impl Dependents {
+    ///
+    /// The decks that a change to `path` matters to (forgetting about them: they'll register again when they're reloaded)
+    ///
+    fn take_changed(&mut self, path: &Path) -> HashSet<String> {
+        let mut changed = HashSet::new();
+        changed.extend(self.files.get(path).cloned().unwrap_or_default());
+        changed.extend(self.directories.get(path).cloned().unwrap_or_default());
+        if let Some(parent) = path.parent() {
+            changed.extend(self.directories.get(parent).cloned().unwrap_or_default());
+        }
+        // if this was a watched directory being deleted or replaced, the watch went with it
+        self.watched.remove(path);
+
+        for decks in self.files.values_mut().chain(self.directories.values_mut()) {
+            decks.retain(|deck| !changed.contains(deck));
+        }
+        self.files.retain(|_, decks| !decks.is_empty());
+        self.directories.retain(|_, decks| !decks.is_empty());
+        changed
+    }
+}
+
+struct DependencyWatcher {
+    watcher: Mutex<RecommendedWatcher>,
+    dependents: Arc<Mutex<Dependents>>,
+    // goes up every time anything we're watching changes, so that a load that raced with a change can tell
+    //  (even a change to a deck that's mid-load, and so isn't in dependents right now)
+    generation: Arc<AtomicU64>,
+}
+
+impl DependencyWatcher {
+    ///
+    /// Remember what a deck depends on, and make sure we hear about it when any of that changes
+    ///
+    /// we watch the directories that files are in rather than the files themselves:
+    ///  editors like to save by writing a new file and renaming it over the old one, which a watch on the old file never sees
+    ///
+    /// returns whether we weren't already watching all of them
+    ///
+    fn watch(&self, directory_root: &str, dependencies: &Dependencies) -> Result<bool> {
+        let files: Vec<PathBuf> = dependencies.files.iter().map(|file| absolute(file)).collect::<Result<_>>()?;
+        let directories: Vec<PathBuf> = dependencies.directories.iter().map(|directory| absolute(directory)).collect::<Result<_>>()?;
+
+        let mut to_watch: Vec<PathBuf> = files.iter().chain(directories.iter()).filter_map(|path| path.parent()).map(Path::to_path_buf).collect();
+        to_watch.extend(directories.iter().cloned());
+
+        let to_watch: Vec<PathBuf> = {
+            let mut dependents = self.dependents.lock().map_err(|_| anyhow::anyhow!("Dependency lock poisoned"))?;
+            for file in files {
+                dependents.files.entry(file).or_default().insert(directory_root.to_string());
+            }
+            for directory in directories {
+                dependents.directories.entry(directory).or_default().insert(directory_root.to_string());
+            }
+            // a pngs directory that doesn't exist (yet) is fine: its parent will tell us when it shows up
+            to_watch.into_iter().filter(|directory| directory.is_dir() && dependents.watched.insert(directory.clone())).collect()
+        };
+
+        // (not while holding the dependents lock: the watcher's event handler needs it, and watch() waits on the watcher's thread)
+        let mut watcher = self.watcher.lock().map_err(|_| anyhow::anyhow!("Watcher lock poisoned"))?;
+        let newly_watched = !to_watch.is_empty();
+        for directory in to_watch {
+            if let Err(err) = watcher.watch(&directory, RecursiveMode::NonRecursive) {
+                if let Ok(mut dependents) = self.dependents.lock() {
+                    dependents.watched.remove(&directory);
+                }
+                return Err(err.into());
+            }
+        }
+        Ok(newly_watched)
+    }
 }
 
+///
+/// A deck's asset links, pointed at the current version of each file: `assets/bee.jpg` -> `assets/bee.jpg?v=0123456789abcdef`
+///
+pub type AssetVersions = Arc<HashMap<String, String>>;
+
+///
+/// A sha256 of each file in a deck's assets/ that it links to: `assets/bee.jpg` -> `0123...`
+///
+pub type AssetManifest = Arc<BTreeMap<String, String>>;
+
 #[derive(Clone)]
 pub struct MinistryDirectoryCache {
-    cached_metadata: Cache<String, CachedDeckMetadata>,
-    cached_deck: Cache<String, CachedDeck>,
+    cached_metadata: Cache<String, DeckMetadata>,
+    cached_deck: Cache<String, Vec<Card>>,
+    // (these depend on the asset files, as well as on content.yml)
+    cached_versions: Cache<String, AssetVersions>,
+    cached_manifest: Cache<String, AssetManifest>,
+    content_root: PathBuf,
+    // if we can't watch the filesystem, we can't know when anything is out of date: so we don't cache at all
+    watcher: Option<Arc<DependencyWatcher>>,
 }
 
 impl MinistryDirectoryCache {
 
-    pub fn new() -> Self {
+    pub fn new(content_root: &Path) -> Self {
+        let cached_deck: Cache<String, Vec<Card>> = Cache::new(2_000);
+        let cached_metadata: Cache<String, DeckMetadata> = Cache::new(2_000);
+        let cached_versions: Cache<String, AssetVersions> = Cache::new(2_000);
+        let cached_manifest: Cache<String, AssetManifest> = Cache::new(2_000);
+        let dependents = Arc::new(Mutex::new(Dependents::default()));
+        let generation = Arc::new(AtomicU64::new(0));
+
+        let watcher = {
+            let cached_deck = cached_deck.clone();
+            let cached_metadata = cached_metadata.clone();
+            let cached_versions = cached_versions.clone();
+            let cached_manifest = cached_manifest.clone();
+            let dependents = dependents.clone();
+            let generation = generation.clone();
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) if !matches!(event.kind, EventKind::Access(_)) => event,
+                    Ok(_) => return,
+                    Err(err) => {
+                        println!("Error watching files: {}", err);
+                        return;
+                    }
+                };
+                generation.fetch_add(1, Ordering::SeqCst);
+                let mut changed = HashSet::new();
+                if let Ok(mut dependents) = dependents.lock() {
+                    for path in &event.paths {
+                        changed.extend(dependents.take_changed(path));
+                    }
+                }
+                for directory_root in changed {
+                    println!("Invalidating {}: {}", directory_root, event.paths.iter().map(|path| path.to_str().unwrap_or("")).collect::<Vec<_>>().join(", "));
+                    futures::executor::block_on(async {
+                        cached_metadata.invalidate(&directory_root).await;
+                        cached_deck.invalidate(&directory_root).await;
+                        cached_versions.invalidate(&directory_root).await;
+                        cached_manifest.invalidate(&directory_root).await;
+                    });
+                }
+            })
+        };
+        let watcher = match watcher {
+            Ok(watcher) => Some(Arc::new(DependencyWatcher {
+                watcher: Mutex::new(watcher),
+                dependents,
+                generation,
+            })),
+            Err(err) => {
+                println!("Can't watch for changes, so decks won't be cached: {}", err);
+                None
+            }
+        };
+
         MinistryDirectoryCache {
-            cached_deck: Cache::new(2_000),
-            cached_metadata: Cache::new(2_000),
+            cached_deck,
+            cached_metadata,
+            cached_versions,
+            cached_manifest,
+            content_root: content_root.to_path_buf(),
+            watcher,
         }
     }
 
-    fn actually_get_metadata(&self, directory_root: &str) -> Result<DeckMetadata> {
-        let ministry_directory = MinistryDirectory::new(directory_root.to_string());
-        let metadata = ministry_directory.get_metadata()?;
-        Ok(metadata)
+    pub async fn get_metadata(&self, directory_root: &str) -> Result<DeckMetadata> {
+        self.get_or_load(&self.cached_metadata, directory_root, "metadata", |directory| {
+            Ok((directory.get_metadata()?, directory.get_dependencies()?))
+        }).await
     }
 
-    pub async fn get_metadata(&self, directory_root: &str) -> Result<DeckMetadata> {
+    pub async fn get_deck(&self, directory_root: &str) -> Result<Vec<Card>> {
+        self.get_or_load(&self.cached_deck, directory_root, "deck", |directory| {
+            Ok((directory.get_deck()?, directory.get_dependencies()?))
+        }).await
+    }
 
-        let cached_metadata = self.cached_metadata.get(directory_root).await;
-        let ministry_directory = MinistryDirectory::new(directory_root.to_string());
-        let last_update_time = ministry_directory.get_last_update_time()?;
-
-        if cached_metadata.is_some() {
-            println!("Cache hit for {}: metadata", directory_root);
-            // test if the cache is still valid
-            let cached_metadata = cached_metadata.unwrap();
-            // if the last update time is older than the cache, we can return it
-            if last_update_time <= cached_metadata.last_updated {
-                return Ok(cached_metadata.metadata);
-            }
-            else{
-                println!("Cache is out of date for {}: metadata", directory_root);
+    ///
+    /// The deck's asset links, with versions: `load` works them out (on the blocking pool), returning the files it looked at,
+    ///  and they're kept until content.yml or any of those files changes
+    ///
+    pub async fn get_versions(&self, directory_root: &str, load: impl Fn(&MinistryDirectory) -> Result<(HashMap<String, String>, Vec<PathBuf>)> + Send + Sync + 'static) -> Result<AssetVersions> {
+        self.get_or_load(&self.cached_versions, directory_root, "versions", move |directory| {
+            let (versions, files) = load(directory)?;
+            let mut dependencies = directory.get_dependencies()?;
+            dependencies.files.extend(files);
+            Ok((Arc::new(versions), dependencies))
+        }).await
+    }
+
+    ///
+    /// The deck's manifest: `hash` is given each of the asset files the deck links to, and they're kept until content.yml or any of those files changes
+    ///
+    pub async fn get_manifest(&self, directory_root: &str, hash: impl Fn(&Path) -> Result<String> + Send + Sync + 'static) -> Result<AssetManifest> {
+        self.get_or_load(&self.cached_manifest, directory_root, "manifest", move |directory| {
+            let files = directory.get_asset_files()?;
+            let manifest = files.iter().map(|(relative_path, path)| Ok((relative_path.clone(), hash(path)?))).collect::<Result<BTreeMap<_, _>>>()?;
+            let mut dependencies = directory.get_dependencies()?;
+            dependencies.files.extend(files.into_values());
+            Ok((Arc::new(manifest), dependencies))
+        }).await
+    }
+
+    ///
+    /// Get something out of the cache, or load it on the blocking pool (parsing YAML and reading directories would stall the executor)
+    ///
+    /// if a bunch of requests for a cold deck arrive at once, they all wait on the same load
+    ///
+    /// only a load that started after we were watching everything it read gets cached: so the first time we see a deck (or a new
+    ///  file in it), we read it again once the watches are in place, and if we can't watch it at all it isn't cached
+    ///
+    async fn get_or_load<V>(&self, cache: &Cache<String, V>, directory_root: &str, what: &str, load: impl Fn(&MinistryDirectory) -> Result<(V, Dependencies)> + Send + Sync + 'static) -> Result<V>
+    where V: Clone + Send + Sync + 'static {
+        let ministry_directory = Arc::new(MinistryDirectory::in_content_root(directory_root.to_string(), &self.content_root));
+        let load = Arc::new(load);
+        let Some(watcher) = &self.watcher else {
+            return rocket::tokio::task::spawn_blocking(move || load(&ministry_directory).map(|(value, _)| value)).await?;
+        };
+
+        let mut cacheable = true;
+        let entry = cache.entry(directory_root.to_string()).or_try_insert_with(async {
+            println!("Cache miss for {}: {}", directory_root, what);
+            let mut attempts = 0;
+            loop {
+                let generation = watcher.generation.load(Ordering::SeqCst);
+                let (ministry_directory, load) = (ministry_directory.clone(), load.clone());
+                let (value, dependencies) = rocket::tokio::task::spawn_blocking(move || load(&ministry_directory)).await??;
+                attempts += 1;
+                let newly_watched = match watcher.watch(directory_root, &dependencies) {
+                    Ok(newly_watched) => newly_watched,
+                    Err(err) => {
+                        // (out of inotify watches, say)
+                        println!("Can't watch {}, so it won't be cached: {}", directory_root, err);
+                        cacheable = false;
+                        return Ok::<V, anyhow::Error>(value);
+                    }
+                };
+                // something changed while we were loading (or before we were watching it): it might have been something we just read
+                if newly_watched || watcher.generation.load(Ordering::SeqCst) != generation {
+                    if attempts < 3 {
+                        continue;
+                    }
+                    cacheable = false;
+                }
+                return Ok(value);
             }
-            // else: fall through and update the cache
+        }).await.map_err(|err| anyhow::anyhow!("{}", err))?;
+
+        if !entry.is_fresh() {
+            println!("Cache hit for {}: {}", directory_root, what);
+        }
+        else if !cacheable {
+            cache.invalidate(directory_root).await;
         }
-        println!("Cache miss for {}: metadata", directory_root);
-        let actual_metadata = self.actually_get_metadata(directory_root)?;
-        self.cached_metadata.insert(directory_root.to_string(), CachedDeckMetadata {
-            metadata: actual_metadata.clone(),
-            last_updated: last_update_time,
-        }).await;
-
-        Ok(actual_metadata)
+        Ok(entry.into_value())
     }
 
-    fn actually_get_deck(&self, directory_root: &str) -> Result<Vec<Card>> {
-        let ministry_directory = MinistryDirectory::new(directory_root.to_string());
-        let deck = ministry_directory.get_deck()?;
-        Ok(deck)
+    ///
+    /// Forget everything we know about a deck (for example: because someone just published a new version of it)
+    ///
+    pub async fn invalidate(&self, directory_root: &str) {
+        self.cached_metadata.invalidate(directory_root).await;
+        self.cached_deck.invalidate(directory_root).await;
+        self.cached_versions.invalidate(directory_root).await;
+        self.cached_manifest.invalidate(directory_root).await;
     }
 
-    pub async fn get_deck(&self, directory_root: &str) -> Result<Vec<Card>> {
+}
 
-        let cached_deck = self.cached_deck.get(directory_root).await;
-        let ministry_directory = MinistryDirectory::new(directory_root.to_string());
-        let last_update_time = ministry_directory.get_last_update_time()?;
-
-        if cached_deck.is_some() {
-            println!("Cache hit for {}: deck", directory_root);
-            // test if the cache is still valid
-            let cached_deck = cached_deck.unwrap();
-            // if the last update time is older than the cache, we can return it
-            if last_update_time <= cached_deck.last_updated {
-                return Ok(cached_deck.deck);
-            }
-            else{
-                println!("Cache is out of date for {}: deck", directory_root);
-            }
-            // else: fall through and update the cache
+///
+/// The watcher reports absolute paths: "./author/deck/content.yml" -> "/wherever/we/are/author/deck/content.yml"
+///
+fn absolute(path: &Path) -> Result<PathBuf> {
+    let relative: PathBuf = path.components().filter(|component| *component != Component::CurDir).collect();
+    Ok(std::env::current_dir()?.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deck(name: &str) -> (PathBuf, String) {
+        let content_root = std::env::temp_dir().join(format!("cardchapter-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&content_root);
+        let directory_root = content_root.join("alice").join("florbs");
+        std::fs::create_dir_all(directory_root.join("assets").join("florb")).unwrap();
+        std::fs::write(directory_root.join("content.yml"), "name: Florbs\nauthor: Alice\npages:\n  - pngs: assets/florb\n").unwrap();
+        std::fs::write(directory_root.join("assets").join("florb").join("Florb_00001.png"), "png").unwrap();
+        (content_root, directory_root.to_str().unwrap().to_string())
+    }
+
+    async fn frames(cache: &MinistryDirectoryCache, directory_root: &str) -> usize {
+        cache.get_deck(directory_root).await.unwrap()[0].pngs.len()
+    }
+
+    #[rocket::async_test]
+    async fn a_new_frame_shows_up_without_touching_content_yml() {
+        let (content_root, directory_root) = deck("frames");
+        let cache = MinistryDirectoryCache::new(&content_root);
+        assert_eq!(frames(&cache, &directory_root).await, 1);
+
+        std::fs::write(Path::new(&directory_root).join("assets").join("florb").join("Florb_00002.png"), "png").unwrap();
+        let started = std::time::Instant::now();
+        while frames(&cache, &directory_root).await != 2 {
+            assert!(started.elapsed() < std::time::Duration::from_secs(5), "the new frame never showed up");
+            rocket::tokio::time::sleep(std::time::Duration::from_millis(50)).await;
         }
-        println!("Cache miss for {}: deck", directory_root);
-        let actual_deck = self.actually_get_deck(directory_root)?;
-        self.cached_deck.insert(directory_root.to_string(), CachedDeck {
-            deck: actual_deck.clone(),
-            last_updated: last_update_time,
-        }).await;
-
-        Ok(actual_deck)
     }
 
-}
\ No newline at end of file
+    #[rocket::async_test]
+    async fn a_burst_of_cold_misses_is_one_load() {
+        let (content_root, directory_root) = deck("burst");
+        let cache = MinistryDirectoryCache::new(&content_root);
+        // (the first load of a deck reads it again once it's being watched: get that out of the way)
+        cache.get_deck(&directory_root).await.unwrap();
+
+        let loads = Arc::new(AtomicU64::new(0));
+        let misses = (0..8).map(|_| {
+            let loads = loads.clone();
+            cache.get_versions(&directory_root, move |_| {
+                loads.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                Ok((HashMap::new(), Vec::new()))
+            })
+        });
+        for versions in futures::future::join_all(misses).await {
+            versions.unwrap();
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+}
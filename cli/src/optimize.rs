@@ -0,0 +1,88 @@
+use std::path::Path;
+use anyhow::Result;
+
+use crate::asset_references::{self, AssetReference};
+use crate::{Config, Services};
+
+///
+/// Convert every image that the decks in the content root link to, ahead of time:
+///  so that the first person to look at a card doesn't have to wait for its images to be converted
+///
+/// every `assets/...` link is found and converted exactly the way the server does it when someone asks for it,
+///  all at once: the image converter decides how many of them actually run at the same time
+///
+/// returns the total size of the originals and of what we'll actually serve instead
+///
+pub async fn optimize(services: &Services, config: &Config) -> Result<(u64, u64)> {
+    let mut deck_paths = Vec::new();
+    if services.root.has_home(){
+        deck_paths.push(services.root.path().to_path_buf());
+    }
+    deck_paths.extend(services.root.decks()?);
+
+    let mut references = Vec::new();
+    for deck_path in deck_paths{
+        let directory_path = deck_path.to_str().unwrap_or(".");
+        let (metadata, deck) = match (services.cache.get_metadata(directory_path).await, services.cache.get_deck(directory_path).await){
+            (Ok(metadata), Ok(deck)) => (metadata, deck),
+            (Err(err), _) | (_, Err(err)) => {
+                println!("Skipping deck {}: {}", directory_path, err);
+                continue;
+            }
+        };
+        for reference in asset_references::find_in_deck(&metadata, &deck){
+            references.push((deck_path.clone(), reference));
+        }
+    }
+    println!("Optimizing {} asset link(s)", references.len());
+
+    let optimized = futures::future::join_all(references.iter().map(|(deck_path, reference)| optimize_asset(services, config, deck_path, reference))).await;
+
+    let (mut original_total, mut optimized_total) = (0, 0);
+    for ((deck_path, reference), sizes) in references.iter().zip(optimized){
+        match sizes{
+            Ok(Some((original, optimized))) => {
+                original_total += original;
+                optimized_total += optimized;
+            },
+            Ok(None) => {},
+            // the server would 404 on this one: `cardchapter check` has more to say about it
+            Err(err) => println!("Skipping asset {} in {}: {}", reference.reference, deck_path.to_str().unwrap_or("."), err),
+        }
+    }
+    services.images.flush().await?;
+    Ok((original_total, optimized_total))
+}
+
+///
+/// Convert one linked asset, if it's the kind of thing that gets converted: (size of the original, size of the converted file)
+///
+async fn optimize_asset(services: &Services, config: &Config, deck_path: &Path, reference: &AssetReference) -> Result<Option<(u64, u64)>> {
+    let directory = services.root.directory(deck_path.to_str().unwrap_or(".").to_string());
+    let source_path = services.root.find_asset(&directory, &reference.asset_path)?;
+    let file_path = directory.convert_asset(source_path.clone(), &reference.asset_path, config, services, &reference.directives()).await?;
+    if file_path == source_path{
+        // videos, sounds, `?unmodified`...
+        return Ok(None);
+    }
+
+    let original = std::fs::metadata(&source_path)?.len();
+    let optimized = std::fs::metadata(&file_path)?.len();
+    println!("{}/{}: {} -> {} ({} saved)", deck_path.to_str().unwrap_or("."), reference.reference, format_size(original), format_size(optimized), format_saved(original, optimized));
+    Ok(Some((original, optimized)))
+}
+
+fn format_saved(original: u64, optimized: u64) -> String {
+    match optimized > original{
+        true => format!("-{}", format_size(optimized - original)),
+        false => format_size(original - optimized),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes{
+        bytes if bytes >= 1024 * 1024 => format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0)),
+        bytes if bytes >= 1024 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        bytes => format!("{} B", bytes),
+    }
+}
@@ -0,0 +1,290 @@
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use url::Url;
+
+use crate::ministry_directory::{DeckMetadata, MinistryDirectory};
+
+// everything in a deck directory that the server needs (plus any files that content.yml includes):
+//  the rest (.ministry, editor junk) stays home
+const PACKAGED_FILES: [&str; 2] = ["content.yml", "content.md"];
+const PACKAGED_DIRECTORIES: [&str; 1] = ["assets"];
+
+// how many files (and directories) an uploaded deck can have: millions of empty files don't take up any bytes, but they still cost something
+const MAX_ENTRIES: usize = 20_000;
+
+///
+/// Bundle up a deck directory as a .tar.gz, ready to send to a server
+///
+pub fn package(directory_root: &Path) -> Result<Vec<u8>>{
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut archive = tar::Builder::new(encoder);
+    archive.follow_symlinks(true);
+
+    for file in PACKAGED_FILES{
+        let path = directory_root.join(file);
+        if path.is_file(){
+            archive.append_path_with_name(&path, file)?;
+        }
+    }
+    // and whatever content.yml `include:`s (unless it's in a directory we're sending anyway)
+    let directory = MinistryDirectory::new(directory_root.to_str().unwrap_or(".").to_string());
+    for path in directory.get_source_files()?{
+        let name = path.strip_prefix(directory_root)?.to_path_buf();
+        let is_packaged = PACKAGED_FILES.iter().any(|file| name == Path::new(file)) ||
+            PACKAGED_DIRECTORIES.iter().any(|directory| name.starts_with(directory));
+        if !is_packaged{
+            archive.append_path_with_name(&path, &name)?;
+        }
+    }
+    for directory in PACKAGED_DIRECTORIES{
+        let path = directory_root.join(directory);
+        if path.is_dir(){
+            archive.append_dir_all(directory, &path)?;
+        }
+    }
+
+    Ok(archive.into_inner()?.finish()?)
+}
+
+///
+/// Send the deck in `directory_root` to a remote CardChapter server
+///
+pub fn publish(directory_root: &Path, server_url: &Url, token: &str) -> Result<DeckMetadata>{
+    let directory = MinistryDirectory::new(directory_root.to_str().unwrap_or(".").to_string());
+    let metadata = directory.get_metadata()?;
+    // make sure it'll survive the trip before we send it anywhere
+    directory.get_deck()?;
+
+    let url = server_url.join(&format!("publish/{}/{}", metadata.author_slug, metadata.slug))?;
+    let authorization = format!("Bearer {}", token);
+
+    // check that we're allowed in before sending the whole deck:
+    //  the server hangs up on unauthorized uploads without reading them, which just looks like a network error
+    let checked = ureq::get(url.as_str())
+        .set("Authorization", &authorization)
+        .call();
+    response_or_error(checked, server_url)?;
+
+    let package = package(directory_root)?;
+    println!("Uploading {} ({} bytes) to {}", metadata.title, package.len(), url);
+    let uploaded = ureq::post(url.as_str())
+        .set("Authorization", &authorization)
+        .set("Content-Type", "application/gzip")
+        .send_bytes(&package);
+    response_or_error(uploaded, server_url)?;
+
+    Ok(metadata)
+}
+
+fn response_or_error(result: Result<ureq::Response, ureq::Error>, server_url: &Url) -> Result<ureq::Response>{
+    match result{
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(401, _)) => Err(anyhow!("{} didn't accept your token: try `cardchapter login {}`", server_url, server_url)),
+        Err(ureq::Error::Status(code, response)) => {
+            let message = response.into_string().unwrap_or_default();
+            Err(anyhow!("The server refused the deck ({}): {}", code, message))
+        },
+        Err(err) => Err(anyhow!("Couldn't reach {}: {}", server_url, err)),
+    }
+}
+
+///
+/// Unpack an uploaded deck, check that it's a valid deck that belongs at `<author_slug>/<deck_slug>`,
+///  then swap it in for whatever was there before
+///
+/// `max_unpacked_size` is how many bytes the deck can take up once it's unpacked: a small .tar.gz can be a very big deck
+///  (and it can't have more than MAX_ENTRIES files in it, either)
+///
+pub fn receive(root: &Path, author_slug: &str, deck_slug: &str, package: &[u8], allow_extra_header: bool, max_unpacked_size: u64) -> Result<DeckMetadata>{
+    let author_path = root.join(author_slug);
+    std::fs::create_dir_all(&author_path)?;
+
+    // the staging directory has to end in <author_slug>/.../<deck_slug> so that get_metadata's slug check still works
+    let incoming = author_path.join(format!(".incoming-{}", timestamp()));
+    let staging = incoming.join(deck_slug);
+    std::fs::create_dir_all(&staging)?;
+
+    let result = unpack(package, &staging, max_unpacked_size).and_then(|_| validate(&staging, author_slug, deck_slug, allow_extra_header));
+    let result = result.and_then(|metadata| {
+        swap_in(&staging, &author_path.join(deck_slug))?;
+        Ok(metadata)
+    });
+
+    if incoming.exists(){
+        std::fs::remove_dir_all(&incoming)?;
+    }
+    result
+}
+
+fn unpack(package: &[u8], staging: &Path, max_unpacked_size: u64) -> Result<()>{
+    let mut archive = tar::Archive::new(GzDecoder::new(package));
+    // we want fresh modification times, so that everything downstream of the deck knows that it's changed
+    archive.set_preserve_mtime(false);
+    let mut unpacked_size: u64 = 0;
+    for (count, entry) in archive.entries()?.enumerate(){
+        if count >= MAX_ENTRIES{
+            return Err(anyhow!("The deck has too many files: the limit is {}", MAX_ENTRIES));
+        }
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        if !entry_type.is_file() && !entry_type.is_dir(){
+            // no symlinks, devices or other surprises
+            return Err(anyhow!("Unexpected entry in deck: {}", entry.path()?.to_str().unwrap_or("")));
+        }
+        // (checked before it's written: an entry is exactly as big as its header says)
+        unpacked_size = unpacked_size.saturating_add(entry.size());
+        if unpacked_size > max_unpacked_size{
+            return Err(anyhow!("The deck is too large once it's unpacked: the limit is {} bytes", max_unpacked_size));
+        }
+        // unpack_in refuses to write anything outside of `staging`
+        if !entry.unpack_in(staging)?{
+            return Err(anyhow!("Unexpected path in deck: {}", entry.path()?.to_str().unwrap_or("")));
+        }
+    }
+    Ok(())
+}
+
+fn validate(staging: &Path, author_slug: &str, deck_slug: &str, allow_extra_header: bool) -> Result<DeckMetadata>{
+    let directory = MinistryDirectory::new(staging.to_str().unwrap_or("").to_string());
+    if !directory.exists(){
+        return Err(anyhow!("The upload doesn't contain a deck: content.yml (or content.md) and assets/ are required"));
+    }
+    let metadata = directory.get_metadata()?;
+    if metadata.author_slug != author_slug || metadata.slug != deck_slug {
+        return Err(anyhow!("This deck belongs at {}/{}, not {}/{}", metadata.author_slug, metadata.slug, author_slug, deck_slug));
+    }
+    if !allow_extra_header && metadata.extra_header.as_deref().is_some_and(|extra_header| !extra_header.trim().is_empty()) {
+        return Err(anyhow!("This server doesn't allow extra_header: please remove it from the deck"));
+    }
+    directory.get_deck()?;
+    Ok(metadata)
+}
+
+///
+/// Replace `target` with `staging` in one step, so that nobody ever sees half of a deck
+///
+/// on unix, `target` is a symlink to a hidden, versioned directory (`.<deck_slug>.<timestamp>`),
+///  which we can atomically re-point with a rename
+///
+#[cfg(unix)]
+fn swap_in(staging: &Path, target: &Path) -> Result<()>{
+    let deck_slug = target.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    let timestamp = timestamp();
+    let version_name = format!(".{}.{}", deck_slug, timestamp);
+    let version = target.with_file_name(&version_name);
+    std::fs::rename(staging, &version)?;
+
+    let link = target.with_file_name(format!(".{}.{}.link", deck_slug, timestamp));
+    std::os::unix::fs::symlink(&version_name, &link)?;
+
+    let previous: Option<PathBuf> = match std::fs::symlink_metadata(target){
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            std::fs::read_link(target).ok().map(|previous| target.with_file_name(previous))
+        },
+        Ok(_) => {
+            // a deck that was put here by hand: move it out of the way first
+            let retired = target.with_file_name(format!(".{}.{}.retired", deck_slug, timestamp));
+            std::fs::rename(target, &retired)?;
+            Some(retired)
+        },
+        Err(_) => None,
+    };
+
+    std::fs::rename(&link, target)?;
+
+    if let Some(previous) = previous{
+        if previous.exists(){
+            std::fs::remove_dir_all(previous)?;
+        }
+    }
+    Ok(())
+}
+
+///
+/// Replace `target` with `staging`: without symlinks, the best we can do is two renames
+///
+#[cfg(not(unix))]
+fn swap_in(staging: &Path, target: &Path) -> Result<()>{
+    let deck_slug = target.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    let retired = target.with_file_name(format!(".{}.{}.retired", deck_slug, timestamp()));
+    if target.exists(){
+        std::fs::rename(target, &retired)?;
+    }
+    std::fs::rename(staging, target)?;
+    if retired.exists(){
+        std::fs::remove_dir_all(retired)?;
+    }
+    Ok(())
+}
+
+fn timestamp() -> u128{
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|duration| duration.as_nanos()).unwrap_or(0)
+}
+
+///
+/// Read an upload body into memory, refusing anything larger than `limit` bytes
+///
+pub async fn read_upload(upload: rocket::Data<'_>, limit: u64) -> Result<Vec<u8>>{
+    use rocket::data::ToByteUnit;
+    let bytes = upload.open(limit.bytes()).into_bytes().await?;
+    if !bytes.is_complete(){
+        return Err(anyhow!("The deck is too large: the limit is {} bytes", limit));
+    }
+    Ok(bytes.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn package_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::best()));
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append_data(&mut header, name, *contents).unwrap();
+        }
+        let mut encoder = archive.into_inner().unwrap();
+        encoder.flush().unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn refuses_decks_that_unpack_too_large() {
+        let root = std::env::temp_dir().join(format!("cardchapter-publish-{}", timestamp()));
+        // 64MB of zeroes is a few kilobytes of gzip
+        let zeroes = vec![0u8; 64 * 1024 * 1024];
+        let package = package_of(&[("content.yml", b"name: Bees\nauthor: Alice\n"), ("assets/zeroes", &zeroes)]);
+        assert!(package.len() < 1024 * 1024);
+
+        let err = receive(&root, "alice", "bees", &package, true, 1024 * 1024).unwrap_err();
+        assert!(err.to_string().contains("too large"), "{}", err);
+        // nothing's left behind: not the deck, and not the half-unpacked upload
+        let leftovers: Vec<_> = std::fs::read_dir(root.join("alice")).unwrap().collect();
+        assert!(leftovers.is_empty());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn refuses_decks_with_too_many_files() {
+        let root = std::env::temp_dir().join(format!("cardchapter-publish-{}", timestamp()));
+        let names: Vec<String> = (0..=MAX_ENTRIES).map(|count| format!("assets/{}.png", count)).collect();
+        let mut files: Vec<(&str, &[u8])> = vec![("content.yml", b"name: Bees\nauthor: Alice\n")];
+        files.extend(names.iter().map(|name| (name.as_str(), &b""[..])));
+        let package = package_of(&files);
+
+        let err = receive(&root, "alice", "bees", &package, true, 1024 * 1024).unwrap_err();
+        assert!(err.to_string().contains("too many files"), "{}", err);
+        let leftovers: Vec<_> = std::fs::read_dir(root.join("alice")).unwrap().collect();
+        assert!(leftovers.is_empty());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
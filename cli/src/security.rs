@@ -0,0 +1,181 @@
+use std::collections::{HashMap, HashSet};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::Response;
+use rocket::shield::{Hsts, Referrer, Shield};
+
+use crate::Config;
+
+// every page on the server shares one origin: so one deck's HTML mustn't be able to run whatever it likes in it
+
+///
+/// What we do with a deck's `extra_header` (HTML that the deck's author wants in the page's <head>)
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExtraHeaderPolicy {
+    // it goes in as-is, scripts and all: for a server where every author is you
+    Trusted,
+    // only stylesheets, fonts and <style> make it through
+    Sanitized,
+    // decks that have one can't be published, and it's left out of the page
+    Rejected,
+}
+
+impl ExtraHeaderPolicy {
+    pub fn from_name(name: &str) -> Option<ExtraHeaderPolicy> {
+        match name {
+            "trusted" => Some(ExtraHeaderPolicy::Trusted),
+            "sanitized" => Some(ExtraHeaderPolicy::Sanitized),
+            "rejected" => Some(ExtraHeaderPolicy::Rejected),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The HTML that should actually go into the page for `extra_header`
+    ///
+    /// trusted scripts get the page's nonce, so that the Content-Security-Policy lets them run
+    ///
+    pub fn apply(&self, extra_header: &str, nonce: Option<&str>) -> String {
+        match (self, nonce) {
+            (ExtraHeaderPolicy::Trusted, Some(nonce)) => add_nonce(extra_header, nonce),
+            (ExtraHeaderPolicy::Trusted, None) => extra_header.to_string(),
+            (ExtraHeaderPolicy::Sanitized, _) => sanitize(extra_header),
+            (ExtraHeaderPolicy::Rejected, _) => "".to_string(),
+        }
+    }
+}
+
+// what a sanitized extra_header can <link> to
+const LINK_RELS: [&str; 4] = ["stylesheet", "preconnect", "preload", "dns-prefetch"];
+
+///
+/// Throw away everything in `extra_header` except for stylesheets, fonts and <style>
+///
+fn sanitize(extra_header: &str) -> String {
+    let tags = HashSet::from(["link", "style"]);
+    let tag_attributes = HashMap::from([
+        ("link", HashSet::from(["rel", "href", "as", "type", "crossorigin", "media"])),
+        ("style", HashSet::from(["media"])),
+    ]);
+    ammonia::Builder::empty()
+        .tags(tags)
+        .tag_attributes(tag_attributes)
+        // (anything else that isn't allowed is unwrapped: but the insides of a script are just more script)
+        .clean_content_tags(HashSet::from(["script", "noscript", "template"]))
+        .url_schemes(HashSet::from(["https"]))
+        .link_rel(None)
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            ("link", "rel") if !value.split_whitespace().all(|rel| LINK_RELS.contains(&rel.to_lowercase().as_str())) => None,
+            ("link", "as") if value != "font" && value != "style" => None,
+            _ => Some(value.into()),
+        })
+        .clean(extra_header)
+        .to_string()
+}
+
+///
+/// `<script ...>` -> `<script nonce="..." ...>`
+///
+fn add_nonce(html: &str, nonce: &str) -> String {
+    let lowercase = html.to_ascii_lowercase();
+    let mut with_nonce = String::new();
+    let mut last = 0;
+    for (index, _) in lowercase.match_indices("<script") {
+        let end = index + "<script".len();
+        // <scripts> isn't a script
+        if !lowercase[end..].starts_with(|c: char| c.is_whitespace() || c == '>') {
+            continue;
+        }
+        with_nonce.push_str(&html[last..end]);
+        with_nonce.push_str(&format!(" nonce=\"{}\"", nonce));
+        last = end;
+    }
+    with_nonce.push_str(&html[last..]);
+    with_nonce
+}
+
+///
+/// A random value for this response's Content-Security-Policy: only <script>s that carry it are allowed to run
+///
+#[derive(Clone)]
+pub struct CspNonce(pub String);
+
+impl CspNonce {
+    fn of(request: &Request<'_>) -> CspNonce {
+        request.local_cache(|| {
+            let mut bytes = [0u8; 16];
+            OsRng.fill_bytes(&mut bytes);
+            CspNonce(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+        }).clone()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CspNonce {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(CspNonce::of(request))
+    }
+}
+
+///
+/// What a deck's asset gets, if somebody opens it on its own: an uploaded assets/x.html is a page on our origin,
+///  so it mustn't be able to run anything, or load anything, or even be a page on our origin at all
+///
+pub const ASSET_POLICY: &str = "sandbox; default-src 'none'";
+
+///
+/// Puts a Content-Security-Policy on every response (that doesn't already have one: see ASSET_POLICY),
+///  with the same nonce that the page (if there is one) was given
+///
+/// only scripts with the nonce run, not everything from 'self': anybody who can publish a deck can put a .js file on this origin
+///
+/// styles, images, fonts and media can come from anywhere on https: decks link to all sorts of things
+///
+pub struct ContentSecurityPolicy;
+
+#[rocket::async_trait]
+impl Fairing for ContentSecurityPolicy {
+    fn info(&self) -> Info {
+        Info {
+            name: "Content-Security-Policy",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if response.headers().contains("Content-Security-Policy") {
+            return;
+        }
+        let nonce = CspNonce::of(request);
+        response.set_raw_header("Content-Security-Policy", [
+            "default-src 'self'".to_string(),
+            format!("script-src 'nonce-{}'", nonce.0),
+            "style-src 'self' 'unsafe-inline' https:".to_string(),
+            "img-src 'self' data: https:".to_string(),
+            "font-src 'self' data: https:".to_string(),
+            "media-src 'self' https:".to_string(),
+            // (videos embedded in markdown cards)
+            "frame-src 'self' https:".to_string(),
+            "connect-src 'self'".to_string(),
+            "object-src 'none'".to_string(),
+            "base-uri 'self'".to_string(),
+            "form-action 'self'".to_string(),
+            "frame-ancestors 'self'".to_string(),
+        ].join("; "));
+    }
+}
+
+///
+/// The rest of the usual security headers (rocket's defaults, plus a referrer policy and, if we're on https, HSTS)
+///
+pub fn shield(config: &Config) -> Shield {
+    let shield = Shield::default().enable(Referrer::StrictOriginWhenCrossOrigin);
+    match config.server_url.scheme() == "https" {
+        true => shield.enable(Hsts::default()),
+        false => shield,
+    }
+}
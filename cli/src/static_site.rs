@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::path::{Component, Path};
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::asset_references::{self, AssetReference};
+use crate::content_file;
+use crate::ministry_directory::Card;
+use crate::{Config, Services, VERSION, APP_JS, APP_CSS};
+
+///
+/// Export every deck under `root` as a directory full of plain files that any dumb file server can serve:
+///
+/// ```text
+/// index.html, index                     <- the deck in the root directory, if there is one (otherwise, a list of the rest)
+/// range/<start>/<end>, content/<id>, assets/...
+/// s/<author>/<deck>/index.html, index   <- every other deck
+/// s/<author>/<deck>/range/<start>/<end>
+/// s/<author>/<deck>/content/<id>
+/// s/<author>/<deck>/assets/...
+/// sitemap, robots.txt, schema.json, js/<version>/feed.js, js/<version>/style.css
+/// ```
+///
+/// Every /range the client can ask for is pre-rendered, and image assets are converted to .webp up-front
+///  (with their links in the deck rewritten to point at the converted file, since a dumb file server can't read query strings)
+///
+pub async fn build(services: &Services, config: &Config, output: &Path) -> Result<()> {
+    // the static site is never in dev mode: we want the versioned, baked-in JS
+    let mut config = config.clone();
+    config.dev = false;
+
+    println!("Building static site in {}", output.to_str().unwrap_or(""));
+    std::fs::create_dir_all(output)?;
+
+    write_file(&output.join("js").join(VERSION).join("feed.js"), APP_JS)?;
+    write_file(&output.join("js").join(VERSION).join("style.css"), APP_CSS)?;
+    write_file(&output.join("robots.txt"), crate::robots())?;
+    write_json(&output.join("schema.json"), &content_file::schema())?;
+
+    let sitemap = crate::get_sitemap(services).await?;
+    write_json(&output.join("sitemap"), &sitemap)?;
+
+    let root = services.root.path();
+    if services.root.has_home(){
+        build_deck(services, &config, root, output, true).await?;
+    }
+    else{
+        write_file(&output.join("index.html"), &crate::catalog_template(sitemap, &config, None))?;
+    }
+
+    for deck_path in services.root.decks()?{
+        let relative_path = deck_path.strip_prefix(root).unwrap_or(&deck_path);
+        let deck_output = output.join("s").join(relative_path);
+        build_deck(services, &config, &deck_path, &deck_output, false).await?;
+    }
+
+    services.images.flush().await?;
+    println!("✅ {}", output.to_str().unwrap_or(""));
+    Ok(())
+}
+
+///
+/// Write one deck: the html, index, assets, /range and /content all go in `deck_output`
+///
+async fn build_deck(services: &Services, config: &Config, deck_path: &Path, deck_output: &Path, is_home: bool) -> Result<()> {
+    let directory_path = deck_path.to_str().unwrap_or(".");
+    println!("Building {}", directory_path);
+
+    let metadata = services.cache.get_metadata(directory_path).await?;
+    if metadata.hidden {
+        write_file(&deck_output.join("index.html"), &crate::error_template("This deck is hidden.", config, None))?;
+        return Ok(());
+    }
+    let deck = services.cache.get_deck(directory_path).await?;
+
+    // convert & copy every asset the deck references, keeping track of where each link should point now
+    let directory = services.root.directory(directory_path.to_string());
+    let mut links = HashMap::new();
+    for reference in asset_references::find_in_deck(&metadata, &deck){
+        // (exactly the file the server would send: nothing from outside the content root)
+        let file_path = match services.root.find_asset(&directory, &reference.asset_path){
+            Ok(source_path) => directory.convert_asset(source_path, &reference.asset_path, config, services, &reference.directives()).await,
+            Err(err) => Err(err),
+        };
+        let file_path = match file_path{
+            Ok(file_path) => file_path,
+            Err(err) => {
+                // the server would 404 on this one, too
+                println!("Skipping asset {}: {}", reference.reference, err);
+                continue;
+            }
+        };
+        let static_link = static_link(&reference, &file_path);
+        // and nothing written anywhere but the deck's own directory in the output
+        if !Path::new(&static_link).components().all(|component| matches!(component, Component::Normal(_))){
+            println!("Skipping asset {}: it doesn't belong in {}", reference.reference, deck_output.to_str().unwrap_or(""));
+            continue;
+        }
+        copy_file(&file_path, &deck_output.join(&static_link))?;
+        links.insert(reference.reference.clone(), static_link);
+    }
+    let rewrite = |link: &str| -> String {
+        asset_references::rewrite_text(link, |reference| links.get(&reference.reference).cloned())
+    };
+
+    let metadata = asset_references::rewrite_metadata(metadata, &rewrite);
+    let deck: Vec<Card> = deck.into_iter().map(|card| asset_references::rewrite_card(card, &rewrite)).collect();
+
+    write_file(&deck_output.join("index.html"), &crate::index_template(metadata.clone(), config, is_home, None)?)?;
+    write_json(&deck_output.join("index"), &crate::build_index(metadata, deck.clone()))?;
+
+    // these are all of the ranges the client will ever ask for:
+    //  the first page, every page ending at a card (starting half a page before it), and every page starting at a card
+    write_range(deck_output, &deck, "0", "0")?;
+    for (index, card) in deck.iter().enumerate(){
+        let start_of_page = &deck[index.saturating_sub(50)];
+        write_range(deck_output, &deck, &start_of_page.id, &card.id)?;
+        write_range(deck_output, &deck, &card.id, "0")?;
+        write_json(&deck_output.join("content").join(&card.id), card)?;
+    }
+
+    Ok(())
+}
+
+///
+/// Where a converted asset lives in the static site:
+///  `assets/bee.jpg?width=100` becomes `assets/bee.jpg.width100.webp`, unconverted files keep their name
+///
+fn static_link(reference: &AssetReference, file_path: &Path) -> String {
+    let asset_path = reference.asset_path.to_str().unwrap_or("").replace("\\", "/");
+    let converted = file_path.extension().map(|extension| extension == "webp").unwrap_or(false) &&
+        reference.asset_path.extension().map(|extension| extension != "webp").unwrap_or(true);
+    if !converted {
+        return format!("assets/{}", asset_path);
+    }
+    let directives = reference.directives().to_string();
+    match directives.as_str(){
+        "" => format!("assets/{}.webp", asset_path),
+        _ => format!("assets/{}.{}.webp", asset_path, directives),
+    }
+}
+
+fn write_range(deck_output: &Path, deck: &[Card], start_id: &str, end_id: &str) -> Result<()> {
+    if let Some(cards) = crate::card_range(deck, start_id, end_id){
+        write_json(&deck_output.join("range").join(start_id).join(end_id), &cards)?;
+    }
+    Ok(())
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    write_file(path, &rocket::serde::json::to_string(value)?)
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent(){
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent(){
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::copy(from, to)?;
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use askama::Template;
+
+use crate::ministry_directory::DeckSummary;
+
+// the HTML pages, from templates/: anything that goes into them is escaped for HTML (text and attributes alike)
+//  unless the template explicitly says `|safe`, so a `"` in a deck's title can't end up anywhere it shouldn't
+
+///
+/// The page that loads the app for a deck
+///
+#[derive(Template)]
+#[template(path = "deck.html")]
+pub struct DeckPage<'a>{
+    pub version: &'a str,
+    pub css_location: &'a str,
+    pub js_location: &'a str,
+    pub favicon: &'a str,
+    pub title: &'a str,
+    pub description: &'a str,
+    pub author: &'a str,
+    pub url: &'a str,
+    pub site_name: &'a str,
+    pub locale: &'a str,
+    pub image: &'a str,
+    pub extra_header: &'a str,
+    pub nonce: Option<&'a str>,
+    pub live_reload: bool,
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+pub struct ErrorPage<'a>{
+    pub message: &'a str,
+    pub nonce: Option<&'a str>,
+    pub live_reload: bool,
+}
+
+///
+/// `/`, on a multi-deck server that doesn't have a deck of its own
+///
+#[derive(Template)]
+#[template(path = "catalog.html")]
+pub struct CatalogPage<'a>{
+    pub css_location: &'a str,
+    pub site_name: &'a str,
+    pub authors: &'a [CatalogAuthor],
+    pub nonce: Option<&'a str>,
+    pub live_reload: bool,
+}
+
+pub struct CatalogAuthor{
+    pub author: String,
+    pub author_slug: String,
+    pub decks: Vec<DeckSummary>,
+}
+
+#[derive(Template)]
+#[template(path = "qr.html")]
+pub struct QrPage<'a>{
+    pub css_location: &'a str,
+    // already URL-encoded: the template takes care of the HTML
+    pub qr_src: &'a str,
+}
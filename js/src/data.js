@@ -14,7 +14,8 @@ class RealServer{
         if(userSlug == null || contentSlug == null){
             const response = await fetch(`${this.serverUrl}/index`, {});
             this.index = await response.json();
-            return `/s/default/default`;
+            // the deck at / keeps everything at the top: /index, /range/..., /content/...
+            return ``;
         }
         return `/s/${userSlug}/${contentSlug}`;
     }